@@ -2,6 +2,9 @@
 
 //! Helpers for converting between `matfile::Array` and `ndarray::Array`.
 //! 
+//! The data of a `matfile::ArrayView` can be viewed as an `ndarray::ArrayView`
+//! as well, which doesn't copy the data if it is borrowed from the file.
+//! 
 //! While `matfile` arrays abstract over the underlying data type, `ndarray`
 //! arrays are parameterized by a concrete data type. Thus the conversions
 //! provided are fallible in case the data types are not compatible.
@@ -19,7 +22,7 @@
 //! Converting a `matfile` array `mf_arr` to a dynamic dimension `ndarray` array
 //! `nd_arr`:
 //! ```rust
-//! # fn main() -> Result<(), Box<std::error::Error>> {
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
 //! #     let data = include_bytes!("../../tests/multidimensional.mat");
 //! #     let mat_file = matfile::MatFile::parse(data.as_ref()).unwrap();
 //! #     let mf_arr = &mat_file.arrays()[0];
@@ -35,7 +38,7 @@
 //! Converting a `matfile` array `mf_arr` to a static dimension `ndarray` array
 //! `nd_arr`:
 //! ```rust
-//! # fn main() -> Result<(), Box<std::error::Error>> {
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
 //! #     let data = include_bytes!("../../tests/single_complex.mat");
 //! #     let mat_file = matfile::MatFile::parse(data.as_ref()).unwrap();
 //! #     let mf_arr = &mat_file.arrays()[0];
@@ -101,6 +104,20 @@ macro_rules! dynamic_conversions {
             }
         }
 
+        impl<'me> TryInto<nd::ArrayViewD<'me, $num>> for &'me mf::ArrayView<'_> {
+            type Error = Error;
+            fn try_into(self) -> Result<nd::ArrayViewD<'me, $num>, Self::Error> {
+                match self.data() {
+                    mf::NumericDataView::$variant { ref real, imag: None } => {
+                        let dimension: nd::IxDyn = self.size().clone().into_dimension();
+                        nd::ArrayView::from_shape(dimension.set_f(true), real.as_ref())
+                            .map_err(|_err| Error::ShapeError)
+                    }
+                    _ => Err(Error::TypeError),
+                }
+            }
+        }
+
         impl TryInto<nd::ArrayD<$num>> for &mf::Array {
             type Error = Error;
             fn try_into(self) -> Result<nd::ArrayD<$num>, Self::Error> {
@@ -154,6 +171,26 @@ macro_rules! static_conversions_n {
             }
         }
         
+        impl<'me> TryInto<nd::ArrayView<'me, $num, nd::Dim<[nd::Ix; $ndims]>>> for &'me mf::ArrayView<'_> {
+            type Error = Error;
+            fn try_into(self) -> Result<nd::ArrayView<'me, $num, nd::Dim<[nd::Ix; $ndims]>>, Self::Error> {
+                let size = self.size();
+                if size.len() != $ndims {
+                    return Err(Error::ShapeError);
+                }
+                let mut shape = [0; $ndims];
+                shape.copy_from_slice(size);
+                match self.data() {
+                    mf::NumericDataView::$variant { ref real, imag: None } => {
+                        let dimension: nd::Dim<[nd::Ix; $ndims]> = shape.into_dimension();
+                        nd::ArrayView::from_shape(dimension.set_f(true), real.as_ref())
+                            .map_err(|_err| Error::ShapeError)
+                    }
+                    _ => Err(Error::TypeError),
+                }
+            }
+        }
+
         impl TryInto<nd::Array<$num, nd::Dim<[nd::Ix; $ndims]>>> for &mf::Array {
             type Error = Error;
             fn try_into(self) -> Result<nd::Array<$num, nd::Dim<[nd::Ix; $ndims]>>, Self::Error> {
@@ -224,3 +261,48 @@ all_conversions!(i16, Int16);
 all_conversions!(u16, UInt16);
 all_conversions!(i8, Int8);
 all_conversions!(u8, UInt8);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Checks that the view has the size of the array, keeps its column-major
+    // order and points to the same numbers
+    fn check_view<D: nd::Dimension>(view: &nd::ArrayView<f64, D>, real: &[f64]) {
+        assert_eq!(view.shape(), &[2, 3]);
+        assert_eq!(view.strides(), &[1, 2]);
+        assert_eq!(view.as_ptr(), real.as_ptr());
+        // Iterated in logical order, row by row
+        let values: Vec<f64> = view.iter().cloned().collect();
+        assert_eq!(values, vec![1.0, 3.0, 5.0, 2.0, 4.0, 6.0]);
+    }
+
+    #[test]
+    fn borrowed_views() {
+        let mat_file = mf::MatFileBuilder::new()
+            .add_array("A", vec![2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0].into())
+            .build()
+            .unwrap();
+        let mut data = Vec::new();
+        mat_file.write(&mut data).unwrap();
+        // Numbers can only be borrowed from an 8 byte aligned file
+        let mut buf = vec![0u8; data.len() + 8];
+        let start = buf.as_ptr().align_offset(8);
+        buf[start..start + data.len()].copy_from_slice(&data);
+        let view = mf::MatFileView::new(&buf[start..start + data.len()]).unwrap();
+        let array = view.array("A").unwrap().unwrap();
+        assert_eq!(array.data().is_borrowed(), cfg!(target_endian = "little"));
+        let real = match array.data() {
+            mf::NumericDataView::Double { real, imag: None } => real.as_ref(),
+            _ => panic!("Expected a real double array"),
+        };
+        let dynamic: nd::ArrayViewD<f64> = (&array).try_into().unwrap();
+        check_view(&dynamic, real);
+        let fixed: nd::ArrayView2<f64> = (&array).try_into().unwrap();
+        check_view(&fixed, real);
+        let wrong_ndims: Result<nd::ArrayView3<f64>, _> = (&array).try_into();
+        assert!(matches!(wrong_ndims, Err(Error::ShapeError)));
+        let wrong_type: Result<nd::ArrayViewD<f32>, _> = (&array).try_into();
+        assert!(matches!(wrong_type, Err(Error::TypeError)));
+    }
+}
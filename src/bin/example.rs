@@ -1,6 +1,6 @@
 use matfile::MatFile;
 
-fn main() -> Result<(), Box<std::error::Error>> {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let data = include_bytes!("../../tests/double.mat");
     let mat_file = crate::MatFile::parse(data.as_ref())?;
     println!("{:#?}", mat_file);
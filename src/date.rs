@@ -0,0 +1,102 @@
+// Matlab writes the creation date into the header text in the format of C's
+// `ctime`, e.g. "Fri Apr  5 10:19:30 2019". The time zone is not recorded.
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// Converts days since 1970-01-01 to a civil date (year, month, day), see
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// The inverse of `civil_from_days`, see
+// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// Formats a UNIX timestamp
+pub fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{} {} {:2} {:02}:{:02}:{:02} {}",
+        WEEKDAYS[days.rem_euclid(7) as usize],
+        MONTHS[month as usize - 1],
+        day,
+        secs_of_day / 3600,
+        (secs_of_day / 60) % 60,
+        secs_of_day % 60,
+        year
+    )
+}
+
+// Parses a date into a UNIX timestamp. The day of the week is ignored.
+pub fn parse_timestamp(text: &str) -> Option<u64> {
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    if parts.len() != 5 {
+        return None;
+    }
+    let month = MONTHS.iter().position(|&month| month == parts[1])? as i64 + 1;
+    let day: i64 = parts[2].parse().ok()?;
+    let year: i64 = parts[4].parse().ok()?;
+    let time: Vec<u64> = parts[3]
+        .split(':')
+        .map(|part| part.parse().ok())
+        .collect::<Option<_>>()?;
+    if time.len() != 3 || !(1..=31).contains(&day) || time[0] > 23 || time[1] > 59 || time[2] > 60 {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    if days < 0 {
+        return None;
+    }
+    Some(days as u64 * 86400 + time[0] * 3600 + time[1] * 60 + time[2])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format() {
+        assert_eq!(format_timestamp(0), "Thu Jan  1 00:00:00 1970");
+        assert_eq!(format_timestamp(1_554_459_570), "Fri Apr  5 10:19:30 2019");
+        assert_eq!(format_timestamp(1_709_210_096), "Thu Feb 29 12:34:56 2024");
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_timestamp("Fri Apr  5 10:19:30 2019"),
+            Some(1_554_459_570)
+        );
+        assert_eq!(
+            parse_timestamp("Thu Feb 29 12:34:56 2024"),
+            Some(1_709_210_096)
+        );
+        assert_eq!(parse_timestamp("Thu Jan  1 00:00:00 1970"), Some(0));
+        assert_eq!(parse_timestamp("Fri Foo  5 10:19:30 2019"), None);
+        assert_eq!(parse_timestamp("Fri Apr  5 10:19 2019"), None);
+        assert_eq!(parse_timestamp(""), None);
+    }
+}
@@ -0,0 +1,1014 @@
+use crate::{Error, ParseError, ParseErrorKind};
+use libflate::zlib::Decoder;
+use std::convert::TryFrom;
+use std::io::Read;
+
+// Reads the parts of the HDF5 file format that MAT-file version 7.3 files are
+// made of, following the HDF5 file format specification. Matlab writes files
+// in the format of the oldest HDF5 library version that supports them, which
+// means version 0 superblocks, version 1 object headers and groups stored as
+// symbol tables. Newer structures (e.g. version 2 object headers or groups
+// with compact or dense link storage) are reported as unsupported.
+//
+// All addresses are relative to the superblock, which follows the user block
+// holding the MAT-file header. Numbers describing the structure of the file
+// are always little endian.
+
+const SIGNATURE: &[u8] = b"\x89HDF\r\n\x1a\n";
+
+// Addresses of things that don't exist have all bits set
+const UNDEFINED_ADDRESS: u64 = u64::MAX;
+
+// Nesting of B-trees deeper than this can only come from a damaged file
+const MAX_BTREE_LEVEL: u8 = 32;
+
+// Deflate can't compress data by more than a factor of about 1032, which
+// bounds how large the data of a dataset can be compared to the file
+const MAX_COMPRESSION_RATIO: usize = 1100;
+
+// Header message types
+const DATASPACE: u16 = 0x0001;
+const LINK_INFO: u16 = 0x0002;
+const DATATYPE: u16 = 0x0003;
+const LAYOUT: u16 = 0x0008;
+const FILTER_PIPELINE: u16 = 0x000B;
+const ATTRIBUTE: u16 = 0x000C;
+const CONTINUATION: u16 = 0x0010;
+const SYMBOL_TABLE: u16 = 0x0011;
+const ATTRIBUTE_INFO: u16 = 0x0015;
+
+// Filters
+const DEFLATE: u16 = 1;
+const SHUFFLE: u16 = 2;
+const FLETCHER32: u16 = 3;
+
+fn error(kind: ParseErrorKind, offset: usize) -> Error {
+    Error::ParseError(ParseError::new(kind).or_at(offset as u64))
+}
+
+fn malformed(offset: usize) -> Error {
+    error(ParseErrorKind::Malformed, offset)
+}
+
+fn unsupported(offset: usize) -> Error {
+    error(ParseErrorKind::UnsupportedHdf5Feature, offset)
+}
+
+// Rounds up to a multiple of eight, as many structures are padded to
+fn padded(n: usize) -> usize {
+    n.div_ceil(8) * 8
+}
+
+// Reads the fields of a structure one after the other
+#[derive(Clone, Copy)]
+struct Cursor<'a> {
+    data: &'a [u8],
+    position: usize,
+    offset_size: usize,
+    length_size: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let data = self.data;
+        let bytes = self
+            .position
+            .checked_add(n)
+            .and_then(|end| data.get(self.position..end))
+            .ok_or_else(|| error(ParseErrorKind::Truncated, self.position))?;
+        self.position += n;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, n: usize) -> Result<(), Error> {
+        self.bytes(n).map(|_| ())
+    }
+
+    fn uint(&mut self, n: usize) -> Result<u64, Error> {
+        let bytes = self.bytes(n)?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | u64::from(byte)))
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(self.uint(2)? as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(self.uint(4)? as u32)
+    }
+
+    fn address(&mut self) -> Result<u64, Error> {
+        let address = self.uint(self.offset_size)?;
+        if address == u64::MAX >> (64 - 8 * self.offset_size) {
+            Ok(UNDEFINED_ADDRESS)
+        } else {
+            Ok(address)
+        }
+    }
+
+    fn length(&mut self) -> Result<u64, Error> {
+        self.uint(self.length_size)
+    }
+
+    // Checks for the signature that starts a structure
+    fn signature(&mut self, signature: &[u8]) -> Result<(), Error> {
+        let position = self.position;
+        if self.bytes(signature.len())? == signature {
+            Ok(())
+        } else {
+            Err(malformed(position))
+        }
+    }
+
+    // A null terminated string
+    fn string(&mut self) -> Result<String, Error> {
+        let position = self.position;
+        let rest = self.data.get(position..).unwrap_or(&[]);
+        let end = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| error(ParseErrorKind::Truncated, position))?;
+        self.position += end + 1;
+        String::from_utf8(rest[..end].to_vec())
+            .map_err(|_err| error(ParseErrorKind::InvalidUtf8, position))
+    }
+}
+
+// An HDF5 file in memory
+pub struct File<'a> {
+    data: &'a [u8],
+    // The position of the superblock, which all addresses are relative to
+    base: usize,
+    offset_size: usize,
+    length_size: usize,
+    root: u64,
+}
+
+// The type of the elements of a dataset or attribute
+#[derive(Clone, Debug, PartialEq)]
+pub enum Datatype {
+    Integer {
+        size: usize,
+        signed: bool,
+        big_endian: bool,
+    },
+    Float {
+        size: usize,
+        big_endian: bool,
+    },
+    // A fixed-length string
+    String {
+        size: usize,
+    },
+    Compound {
+        size: usize,
+        members: Vec<Member>,
+    },
+    // An object reference, i.e. the address of an object header
+    Reference {
+        size: usize,
+    },
+    // A variable-length sequence of elements of the given type
+    Sequence(Box<Datatype>),
+    // A variable-length string
+    VarString,
+    // Any other type, which can't be read
+    Other {
+        size: usize,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Member {
+    pub name: String,
+    pub offset: usize,
+    pub datatype: Datatype,
+}
+
+impl Datatype {
+    // The size of an element in bytes
+    pub fn size(&self, file: &File) -> usize {
+        match self {
+            Datatype::Integer { size, .. }
+            | Datatype::Float { size, .. }
+            | Datatype::String { size }
+            | Datatype::Compound { size, .. }
+            | Datatype::Reference { size }
+            | Datatype::Other { size } => *size,
+            // The length, followed by the global heap ID of the data
+            Datatype::Sequence(_) | Datatype::VarString => 4 + file.offset_size + 4,
+        }
+    }
+}
+
+// An attribute of an object
+#[derive(Debug)]
+pub struct Attribute<'a> {
+    pub name: String,
+    pub datatype: Datatype,
+    pub data: &'a [u8],
+    pub offset: usize,
+}
+
+// How the data of a dataset is stored
+#[derive(Debug)]
+enum Layout<'a> {
+    Compact(&'a [u8]),
+    Contiguous { address: u64 },
+    Chunked { btree: u64, dims: Vec<usize> },
+}
+
+#[derive(Debug)]
+struct Filter {
+    id: u16,
+    client_data: Vec<u32>,
+}
+
+// A group or dataset, as described by its object header
+#[derive(Debug)]
+pub struct Object<'a> {
+    // The position of the object header in the file
+    pub offset: usize,
+    // `None` for datasets without elements
+    pub dims: Option<Vec<u64>>,
+    pub datatype: Option<Datatype>,
+    pub attributes: Vec<Attribute<'a>>,
+    layout: Option<Layout<'a>>,
+    filters: Vec<Filter>,
+    // The addresses of the B-tree and local heap of a group
+    symbol_table: Option<(u64, u64)>,
+}
+
+impl<'a> Object<'a> {
+    pub fn is_group(&self) -> bool {
+        self.symbol_table.is_some()
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&Attribute<'a>> {
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.name == name)
+    }
+
+    // The number of elements of a dataset
+    pub fn len(&self) -> Result<usize, Error> {
+        element_count(self.dims.as_deref(), self.offset)
+    }
+}
+
+fn element_count(dims: Option<&[u64]>, offset: usize) -> Result<usize, Error> {
+    match dims {
+        Some(dims) => dims.iter().try_fold(1usize, |n, &d| {
+            usize::try_from(d)
+                .ok()
+                .and_then(|d| n.checked_mul(d))
+                .ok_or_else(|| malformed(offset))
+        }),
+        None => Ok(0),
+    }
+}
+
+// An entry of the lowest level of a version 1 B-tree, with the position of
+// the node it is in
+struct BTreeEntry<'a> {
+    key: &'a [u8],
+    child: u64,
+    offset: usize,
+}
+
+impl<'a> File<'a> {
+    // Finds the superblock, which is at the start of the file or after a user
+    // block of 512 bytes or a larger power of two
+    pub fn open(data: &'a [u8]) -> Result<Self, Error> {
+        let mut base = 0;
+        while data.get(base..base + SIGNATURE.len()) != Some(SIGNATURE) {
+            base = if base == 0 { 512 } else { base * 2 };
+            if base >= data.len() {
+                return Err(error(ParseErrorKind::InvalidHeader, 0));
+            }
+        }
+        let mut file = File {
+            data,
+            base,
+            offset_size: 8,
+            length_size: 8,
+            root: 0,
+        };
+        let mut cursor = file.cursor(base + SIGNATURE.len());
+        let position = cursor.position;
+        let version = cursor.u8()?;
+        if version > 1 {
+            return Err(unsupported(position));
+        }
+        // Versions of the free-space storage, of the root group symbol table
+        // entry and of the shared header message format, and a reserved byte
+        cursor.skip(4)?;
+        let position = cursor.position;
+        let offset_size = cursor.u8()? as usize;
+        let length_size = cursor.u8()? as usize;
+        if ![2, 4, 8].contains(&offset_size) || ![2, 4, 8].contains(&length_size) {
+            return Err(unsupported(position));
+        }
+        // Reserved, group leaf and internal node K, file consistency flags
+        cursor.skip(1 + 2 + 2 + 4)?;
+        if version == 1 {
+            // Indexed storage internal node K, reserved
+            cursor.skip(4)?;
+        }
+        // Like the HDF5 library does, the base address is ignored in favor
+        // of where the superblock was found. It is followed by the addresses
+        // of the free-space info, the end of the file and the driver
+        // information block, and by the root group symbol table entry, which
+        // starts with the offset of its name in a local heap.
+        cursor.skip(5 * offset_size)?;
+        file.root = cursor.uint(offset_size)?;
+        file.offset_size = offset_size;
+        file.length_size = length_size;
+        Ok(file)
+    }
+
+    // The address of the root group
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    fn cursor(&self, position: usize) -> Cursor<'a> {
+        Cursor {
+            data: self.data,
+            position,
+            offset_size: self.offset_size,
+            length_size: self.length_size,
+        }
+    }
+
+    // The position in the file of an address found at position `at`
+    fn position(&self, address: u64, at: usize) -> Result<usize, Error> {
+        usize::try_from(address)
+            .ok()
+            .and_then(|address| address.checked_add(self.base))
+            .filter(|&position| position < self.data.len())
+            .ok_or_else(|| malformed(at))
+    }
+
+    // Reads the object header at the given address, found at position `at`
+    pub fn object(&self, address: u64, at: usize) -> Result<Object<'a>, Error> {
+        let offset = self.position(address, at)?;
+        if self.data[offset..].starts_with(b"OHDR") {
+            return Err(unsupported(offset));
+        }
+        let mut cursor = self.cursor(offset);
+        if cursor.u8()? != 1 {
+            return Err(malformed(offset));
+        }
+        // Reserved, number of messages, reference count
+        cursor.skip(1 + 2 + 4)?;
+        let size = cursor.u32()? as usize;
+        // Messages are aligned to eight bytes
+        cursor.skip(4)?;
+        let mut object = Object {
+            offset,
+            dims: None,
+            datatype: None,
+            attributes: Vec::new(),
+            layout: None,
+            filters: Vec::new(),
+            symbol_table: None,
+        };
+        let mut blocks = vec![(cursor.position, size)];
+        let mut n_blocks = 0;
+        while let Some((start, size)) = blocks.pop() {
+            // Each block takes up at least a message of its own
+            n_blocks += 1;
+            if n_blocks > self.data.len() / 8 {
+                return Err(malformed(start));
+            }
+            let end = start.checked_add(size).ok_or_else(|| malformed(start))?;
+            let mut cursor = self.cursor(start);
+            while cursor.position + 8 <= end {
+                let message_type = cursor.u16()?;
+                let message_size = cursor.u16()? as usize;
+                let flags = cursor.u8()?;
+                cursor.skip(3)?;
+                let message = cursor;
+                let message_offset = message.position;
+                cursor.skip(message_size)?;
+                // Shared messages are stored elsewhere
+                if flags & 0x02 != 0 {
+                    return Err(unsupported(message_offset));
+                }
+                match message_type {
+                    DATASPACE => object.dims = self.dataspace(message)?,
+                    DATATYPE => object.datatype = Some(self.datatype(message, true)?.0),
+                    LAYOUT => object.layout = Some(self.layout(message)?),
+                    FILTER_PIPELINE => object.filters = self.filters(message)?,
+                    ATTRIBUTE => object.attributes.push(self.attribute(message)?),
+                    CONTINUATION => {
+                        let mut message = message;
+                        let address = message.address()?;
+                        let size = message.length()? as usize;
+                        blocks.push((self.position(address, message_offset)?, size));
+                    }
+                    SYMBOL_TABLE => {
+                        let mut message = message;
+                        object.symbol_table = Some((message.address()?, message.address()?));
+                    }
+                    // Newer ways of storing links and attributes
+                    LINK_INFO | ATTRIBUTE_INFO => return Err(unsupported(message_offset)),
+                    _ => (),
+                }
+            }
+        }
+        Ok(object)
+    }
+
+    // Parses a dataspace message, `None` meaning a dataspace without elements
+    fn dataspace(&self, mut cursor: Cursor) -> Result<Option<Vec<u64>>, Error> {
+        let position = cursor.position;
+        let version = cursor.u8()?;
+        let rank = cursor.u8()? as usize;
+        // Flags, telling whether maximum dimensions follow the dimensions
+        cursor.skip(1)?;
+        match version {
+            1 => cursor.skip(5)?,
+            2 => {
+                if cursor.u8()? == 2 {
+                    return Ok(None);
+                }
+            }
+            _ => return Err(unsupported(position)),
+        }
+        (0..rank)
+            .map(|_| cursor.length())
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+
+    // Parses a datatype message, returning the type and its size in bytes.
+    // Types that can't be read are only allowed at the top level, as their
+    // size can't be determined.
+    fn datatype(&self, mut cursor: Cursor, top_level: bool) -> Result<(Datatype, usize), Error> {
+        let start = cursor.position;
+        let class_and_version = cursor.u8()?;
+        let bits = cursor.uint(3)? as u32;
+        let size = cursor.u32()? as usize;
+        let version = class_and_version >> 4;
+        let big_endian = bits & 1 != 0;
+        let datatype = match class_and_version & 0x0F {
+            0 => {
+                // Bit offset and precision
+                cursor.skip(4)?;
+                Datatype::Integer {
+                    size,
+                    signed: bits & 0x08 != 0,
+                    big_endian,
+                }
+            }
+            1 => {
+                // Bit offset and precision, exponent and mantissa locations
+                // and sizes, exponent bias
+                cursor.skip(12)?;
+                if bits & 0x40 != 0 {
+                    // VAX byte order
+                    Datatype::Other { size }
+                } else {
+                    Datatype::Float { size, big_endian }
+                }
+            }
+            3 => Datatype::String { size },
+            6 => {
+                let mut members = Vec::new();
+                for _ in 0..bits & 0xFFFF {
+                    let name_start = cursor.position;
+                    let name = cursor.string()?;
+                    let offset = match version {
+                        1 | 2 => {
+                            cursor.position = name_start + padded(name.len() + 1);
+                            cursor.u32()? as usize
+                        }
+                        3 => {
+                            let n = match size {
+                                0..=0xFF => 1,
+                                0x100..=0xFFFF => 2,
+                                0x1_0000..=0xFF_FFFF => 3,
+                                _ => 4,
+                            };
+                            cursor.uint(n)? as usize
+                        }
+                        _ => return Err(unsupported(start)),
+                    };
+                    if version == 1 {
+                        let position = cursor.position;
+                        // Array members
+                        if cursor.u8()? != 0 {
+                            return Err(unsupported(position));
+                        }
+                        // Reserved, permutation, reserved and dimension sizes
+                        cursor.skip(3 + 4 + 4 + 16)?;
+                    }
+                    let (datatype, length) = self.datatype(cursor, false)?;
+                    cursor.skip(length)?;
+                    members.push(Member {
+                        name,
+                        offset,
+                        datatype,
+                    });
+                }
+                Datatype::Compound { size, members }
+            }
+            // Only object references can be followed
+            7 if bits & 0x0F == 0 => Datatype::Reference { size },
+            9 => {
+                let (base, length) = self.datatype(cursor, false)?;
+                cursor.skip(length)?;
+                if bits & 0x0F == 1 {
+                    Datatype::VarString
+                } else {
+                    Datatype::Sequence(Box::new(base))
+                }
+            }
+            _ if top_level => Datatype::Other { size },
+            _ => return Err(unsupported(start)),
+        };
+        Ok((datatype, cursor.position - start))
+    }
+
+    fn layout(&self, mut cursor: Cursor<'a>) -> Result<Layout<'a>, Error> {
+        let position = cursor.position;
+        match cursor.u8()? {
+            1 | 2 => {
+                let rank = cursor.u8()? as usize;
+                let class = cursor.u8()?;
+                cursor.skip(5)?;
+                let address = if class == 0 {
+                    UNDEFINED_ADDRESS
+                } else {
+                    cursor.address()?
+                };
+                let mut dims = (0..rank)
+                    .map(|_| cursor.u32().map(|d| d as usize))
+                    .collect::<Result<Vec<_>, _>>()?;
+                match class {
+                    0 => {
+                        let size = cursor.u32()? as usize;
+                        Ok(Layout::Compact(cursor.bytes(size)?))
+                    }
+                    1 => Ok(Layout::Contiguous { address }),
+                    2 => {
+                        // The size of the elements is stored separately
+                        dims.push(cursor.u32()? as usize);
+                        Ok(Layout::Chunked {
+                            btree: address,
+                            dims,
+                        })
+                    }
+                    _ => Err(malformed(position)),
+                }
+            }
+            3 => match cursor.u8()? {
+                0 => {
+                    let size = cursor.u16()? as usize;
+                    Ok(Layout::Compact(cursor.bytes(size)?))
+                }
+                1 => Ok(Layout::Contiguous {
+                    address: cursor.address()?,
+                }),
+                2 => {
+                    // The last dimension is the size of the elements
+                    let rank = cursor.u8()? as usize;
+                    let btree = cursor.address()?;
+                    let dims = (0..rank)
+                        .map(|_| cursor.u32().map(|d| d as usize))
+                        .collect::<Result<_, _>>()?;
+                    Ok(Layout::Chunked { btree, dims })
+                }
+                _ => Err(malformed(position)),
+            },
+            _ => Err(unsupported(position)),
+        }
+    }
+
+    fn filters(&self, mut cursor: Cursor) -> Result<Vec<Filter>, Error> {
+        let position = cursor.position;
+        let version = cursor.u8()?;
+        let n_filters = cursor.u8()?;
+        match version {
+            1 => cursor.skip(6)?,
+            2 => (),
+            _ => return Err(unsupported(position)),
+        }
+        let mut filters = Vec::new();
+        for _ in 0..n_filters {
+            let id = cursor.u16()?;
+            let name_length = if version == 1 || id >= 256 {
+                cursor.u16()? as usize
+            } else {
+                0
+            };
+            // Flags
+            cursor.skip(2)?;
+            let n_values = cursor.u16()? as usize;
+            cursor.skip(if version == 1 {
+                padded(name_length)
+            } else {
+                name_length
+            })?;
+            let client_data = (0..n_values)
+                .map(|_| cursor.u32())
+                .collect::<Result<_, _>>()?;
+            if version == 1 && n_values % 2 == 1 {
+                cursor.skip(4)?;
+            }
+            filters.push(Filter { id, client_data });
+        }
+        Ok(filters)
+    }
+
+    fn attribute(&self, mut cursor: Cursor<'a>) -> Result<Attribute<'a>, Error> {
+        let offset = cursor.position;
+        let version = cursor.u8()?;
+        if !(1..=3).contains(&version) {
+            return Err(unsupported(offset));
+        }
+        cursor.skip(1)?;
+        let name_size = cursor.u16()? as usize;
+        let datatype_size = cursor.u16()? as usize;
+        let dataspace_size = cursor.u16()? as usize;
+        if version == 3 {
+            // Character set of the name
+            cursor.skip(1)?;
+        }
+        // Only the first version pads the parts to eight bytes
+        let pad = |n: usize| if version == 1 { padded(n) } else { n };
+        let name_start = cursor.position;
+        let name = cursor.string()?;
+        if name.len() + 1 != name_size {
+            return Err(malformed(name_start));
+        }
+        cursor.position = name_start;
+        cursor.skip(pad(name_size))?;
+        let (datatype, _length) = self.datatype(cursor, true)?;
+        cursor.skip(pad(datatype_size))?;
+        let dims = self.dataspace(cursor)?;
+        cursor.skip(pad(dataspace_size))?;
+        let n_bytes = element_count(dims.as_deref(), offset)?
+            .checked_mul(datatype.size(self))
+            .ok_or_else(|| malformed(offset))?;
+        let data = cursor.bytes(n_bytes)?;
+        Ok(Attribute {
+            name,
+            datatype,
+            data,
+            offset,
+        })
+    }
+
+    // The names and addresses of the members of a group, ordered by name
+    pub fn members(&self, group: &Object) -> Result<Vec<(String, u64)>, Error> {
+        let (btree, heap) = group.symbol_table.ok_or_else(|| malformed(group.offset))?;
+        let mut cursor = self.cursor(self.position(heap, group.offset)?);
+        cursor.signature(b"HEAP")?;
+        // Version, reserved, size of the data segment and offset of its free
+        // list, followed by the address of the data segment with the names
+        cursor.skip(4 + 2 * self.length_size)?;
+        let names = cursor.address()?;
+        let mut members = Vec::new();
+        for entry in self.btree_leaves(btree, 0, self.length_size, group.offset)? {
+            let mut cursor = self.cursor(self.position(entry.child, entry.offset)?);
+            cursor.signature(b"SNOD")?;
+            // Version, reserved
+            cursor.skip(2)?;
+            for _ in 0..cursor.u16()? {
+                let position = cursor.position;
+                let name = cursor.length()?;
+                let address = cursor.address()?;
+                // Cache type, reserved, scratch-pad space
+                cursor.skip(4 + 4 + 16)?;
+                let name = names
+                    .checked_add(name)
+                    .ok_or_else(|| malformed(position))
+                    .and_then(|name| self.position(name, position))?;
+                members.push((self.cursor(name).string()?, address));
+            }
+        }
+        Ok(members)
+    }
+
+    // The entries of the lowest level of a version 1 B-tree, in order
+    fn btree_leaves(
+        &self,
+        address: u64,
+        node_type: u8,
+        key_size: usize,
+        at: usize,
+    ) -> Result<Vec<BTreeEntry<'a>>, Error> {
+        let mut entries = Vec::new();
+        self.collect_btree_leaves(address, node_type, key_size, None, at, &mut entries)?;
+        Ok(entries)
+    }
+
+    fn collect_btree_leaves(
+        &self,
+        address: u64,
+        node_type: u8,
+        key_size: usize,
+        expected_level: Option<u8>,
+        at: usize,
+        entries: &mut Vec<BTreeEntry<'a>>,
+    ) -> Result<(), Error> {
+        let offset = self.position(address, at)?;
+        let mut cursor = self.cursor(offset);
+        cursor.signature(b"TREE")?;
+        if cursor.u8()? != node_type {
+            return Err(malformed(offset));
+        }
+        // The level of each child node is one less than its parent's
+        let level = cursor.u8()?;
+        if level > MAX_BTREE_LEVEL || expected_level.is_some_and(|expected| level != expected) {
+            return Err(malformed(offset));
+        }
+        let n_entries = cursor.u16()?;
+        // Siblings
+        cursor.skip(2 * self.offset_size)?;
+        for _ in 0..n_entries {
+            let key = cursor.bytes(key_size)?;
+            let child = cursor.address()?;
+            if level == 0 {
+                entries.push(BTreeEntry { key, child, offset });
+            } else {
+                self.collect_btree_leaves(
+                    child,
+                    node_type,
+                    key_size,
+                    Some(level - 1),
+                    offset,
+                    entries,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Reads the data of a dataset, as the bytes of its elements in row-major
+    // order
+    pub fn read(&self, object: &Object) -> Result<Vec<u8>, Error> {
+        let offset = object.offset;
+        let datatype = object.datatype.as_ref().ok_or_else(|| malformed(offset))?;
+        let element_size = datatype.size(self);
+        let n_bytes = object
+            .len()?
+            .checked_mul(element_size)
+            .filter(|&n_bytes| n_bytes / MAX_COMPRESSION_RATIO <= self.data.len())
+            .ok_or_else(|| malformed(offset))?;
+        if n_bytes == 0 {
+            return Ok(Vec::new());
+        }
+        match object.layout.as_ref().ok_or_else(|| malformed(offset))? {
+            Layout::Compact(data) => data
+                .get(..n_bytes)
+                .map(|data| data.to_vec())
+                .ok_or_else(|| malformed(offset)),
+            // Data that was never written has the default fill value of zero
+            Layout::Contiguous { address } if *address == UNDEFINED_ADDRESS => Ok(vec![0; n_bytes]),
+            Layout::Contiguous { address } => Ok(self
+                .cursor(self.position(*address, offset)?)
+                .bytes(n_bytes)?
+                .to_vec()),
+            Layout::Chunked { btree, dims } => {
+                self.read_chunked(object, *btree, dims, element_size, n_bytes)
+            }
+        }
+    }
+
+    fn read_chunked(
+        &self,
+        object: &Object,
+        btree: u64,
+        chunk_dims: &[usize],
+        element_size: usize,
+        n_bytes: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let offset = object.offset;
+        // The dimensions fit into `usize` as the number of elements does
+        let dims: Vec<usize> = object.dims.iter().flatten().map(|&d| d as usize).collect();
+        let rank = dims.len();
+        if rank == 0
+            || chunk_dims.len() != rank + 1
+            || chunk_dims[rank] != element_size
+            || chunk_dims.contains(&0)
+        {
+            return Err(malformed(offset));
+        }
+        let chunk_size = chunk_dims
+            .iter()
+            .try_fold(1usize, |n, &d| n.checked_mul(d))
+            .ok_or_else(|| malformed(offset))?;
+        let mut data = vec![0; n_bytes];
+        if btree == UNDEFINED_ADDRESS {
+            return Ok(data);
+        }
+        // Chunks are stored as whole rows along the last dimension, of which
+        // only the part within the dataset is used
+        let row_length = chunk_dims[rank - 1];
+        let n_rows = chunk_size / element_size / row_length;
+        let key_size = 4 + 4 + 8 * (rank + 1);
+        for entry in self.btree_leaves(btree, 1, key_size, offset)? {
+            let mut key = self.cursor(0);
+            key.data = entry.key;
+            let size = key.u32()? as usize;
+            let filter_mask = key.u32()?;
+            let origin = (0..rank)
+                .map(|_| key.uint(8))
+                .collect::<Result<Vec<_>, _>>()?;
+            let position = self.position(entry.child, entry.offset)?;
+            let mut chunk = self.cursor(position).bytes(size)?.to_vec();
+            // Filters are undone in reverse, skipping those that the mask
+            // says weren't applied
+            for (k, filter) in object.filters.iter().enumerate().rev() {
+                if k < 32 && filter_mask & (1 << k) != 0 {
+                    continue;
+                }
+                chunk = unfilter(filter, chunk, element_size, chunk_size, position)?;
+            }
+            if chunk.len() != chunk_size {
+                return Err(malformed(position));
+            }
+            let last = origin[rank - 1];
+            if last >= dims[rank - 1] as u64 {
+                continue;
+            }
+            let n = row_length.min(dims[rank - 1] - last as usize) * element_size;
+            'rows: for row in 0..n_rows {
+                // The position of the row within the dataset
+                let mut index = 0;
+                let mut rest = row;
+                let mut stride = n_rows;
+                for k in 0..rank - 1 {
+                    stride /= chunk_dims[k];
+                    let i = origin[k] + (rest / stride) as u64;
+                    rest %= stride;
+                    if i >= dims[k] as u64 {
+                        continue 'rows;
+                    }
+                    index = index * dims[k] + i as usize;
+                }
+                let start = (index * dims[rank - 1] + last as usize) * element_size;
+                let row_start = row * row_length * element_size;
+                data[start..start + n].copy_from_slice(&chunk[row_start..row_start + n]);
+            }
+        }
+        Ok(data)
+    }
+
+    // The addresses of the objects referenced by the elements of a dataset
+    pub fn references(&self, object: &Object) -> Result<Vec<u64>, Error> {
+        match object.datatype {
+            Some(Datatype::Reference { size }) if size == self.offset_size => (),
+            _ => return Err(malformed(object.offset)),
+        }
+        let data = self.read(object)?;
+        let mut cursor = self.cursor(0);
+        cursor.data = &data;
+        (0..data.len() / self.offset_size)
+            .map(|_| cursor.address())
+            .collect()
+    }
+
+    // The number of elements and the data of a variable-length element, given
+    // its bytes in a dataset or attribute found at position `at`
+    pub fn heap_object(&self, element: &[u8], at: usize) -> Result<(usize, &'a [u8]), Error> {
+        let mut cursor = self.cursor(0);
+        cursor.data = element;
+        let length = cursor.u32()? as usize;
+        let collection = cursor.address()?;
+        let index = cursor.u32()?;
+        if length == 0 {
+            return Ok((0, &[]));
+        }
+        let start = self.position(collection, at)?;
+        let mut cursor = self.cursor(start);
+        cursor.signature(b"GCOL")?;
+        // Version, reserved
+        cursor.skip(4)?;
+        let end = usize::try_from(cursor.length()?)
+            .ok()
+            .and_then(|size| start.checked_add(size))
+            .ok_or_else(|| malformed(start))?;
+        while cursor.position < end {
+            let object_index = cursor.u16()?;
+            // Reference count, reserved
+            cursor.skip(6)?;
+            let size = cursor.length()? as usize;
+            // The free space at the end has index zero
+            if object_index == 0 {
+                break;
+            }
+            let position = cursor.position;
+            let data = cursor.bytes(size)?;
+            if u32::from(object_index) == index {
+                return Ok((length, data));
+            }
+            cursor.position = position;
+            cursor.skip(padded(size))?;
+        }
+        Err(malformed(at))
+    }
+}
+
+// Undoes a filter applied to a chunk
+fn unfilter(
+    filter: &Filter,
+    chunk: Vec<u8>,
+    element_size: usize,
+    chunk_size: usize,
+    at: usize,
+) -> Result<Vec<u8>, Error> {
+    match filter.id {
+        DEFLATE => {
+            let mut inflated = Vec::new();
+            // A chunk never inflates to more than its size
+            Decoder::new(&chunk[..])
+                .and_then(|decoder| {
+                    decoder
+                        .take(chunk_size as u64 + 4)
+                        .read_to_end(&mut inflated)
+                })
+                .map_err(|_err| error(ParseErrorKind::Decompression, at))?;
+            Ok(inflated)
+        }
+        // The first bytes of all elements come first, then the second bytes
+        // and so on. Bytes that don't make up a whole element are left as is.
+        SHUFFLE => {
+            let size = filter
+                .client_data
+                .first()
+                .map_or(element_size, |&size| size as usize);
+            if size <= 1 {
+                return Ok(chunk);
+            }
+            let n = chunk.len() / size;
+            let mut unshuffled = chunk.clone();
+            for (k, &byte) in chunk[..n * size].iter().enumerate() {
+                unshuffled[(k % n) * size + k / n] = byte;
+            }
+            Ok(unshuffled)
+        }
+        // The checksum at the end isn't checked
+        FLETCHER32 => {
+            let mut chunk = chunk;
+            let length = chunk.len().checked_sub(4).ok_or_else(|| malformed(at))?;
+            chunk.truncate(length);
+            Ok(chunk)
+        }
+        _ => Err(unsupported(at)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn kind(result: Result<File, Error>) -> Option<ParseErrorKind> {
+        match result {
+            Err(Error::ParseError(err)) => Some(err.kind()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn unshuffle() {
+        let filter = Filter {
+            id: SHUFFLE,
+            client_data: vec![2],
+        };
+        // Three elements of two bytes and a trailing byte
+        let shuffled = vec![1, 3, 5, 2, 4, 6, 7];
+        assert_eq!(
+            unfilter(&filter, shuffled, 2, 7, 0).unwrap(),
+            vec![1, 2, 3, 4, 5, 6, 7]
+        );
+    }
+
+    #[test]
+    fn superblock() {
+        let mut data = include_bytes!("../tests/v73.mat").to_vec();
+        let file = File::open(&data).unwrap();
+        assert_eq!(file.base, 512);
+        assert_eq!(file.offset_size, 8);
+
+        // Newer superblocks, and files without one
+        data[520] = 2;
+        assert_eq!(
+            kind(File::open(&data)),
+            Some(ParseErrorKind::UnsupportedHdf5Feature)
+        );
+        data[512] = 0;
+        assert_eq!(kind(File::open(&data)), Some(ParseErrorKind::InvalidHeader));
+    }
+}
@@ -1,21 +1,25 @@
 #![doc(html_root_url = "https://docs.rs/matfile/0.2.0")]
 
-//! Matfile is a library for reading (and in the future writing) Matlab ".mat" files.
+//! Matfile is a library for reading and writing Matlab ".mat" files.
 //!
 //! __Please note__: This library is still alpha quality software and only implements a subset of the features supported by .mat files.
 //!
 //! ## Feature Status
 //!
-//! Matfile currently allows you to load numeric arrays from .mat files (all floating point and integer types, including complex numbers). All other types are currently ignored.
+//! Matfile currently allows you to load numeric arrays from .mat files (all floating point and integer types, including complex numbers) as well as sparse, character, cell, structure and object arrays.
 //!
 //! * [ ] Loading .mat files
 //!   * [x] Numeric arrays
-//!   * [ ] Cell arrays
-//!   * [ ] Structure arrays
-//!   * [ ] Object arrays
-//!   * [ ] Character arrays
-//!   * [ ] Sparse arrays
-//! * [ ] Writing .mat files
+//!   * [x] Cell arrays
+//!   * [x] Structure arrays
+//!   * [x] Object arrays
+//!   * [x] Character arrays
+//!   * [x] Sparse arrays
+//!   * [x] Level 4 MAT-files
+//!   * [x] MAT-file version 7.3 (HDF5 based) files
+//! * [x] Writing .mat files
+//!   * [x] Level 5 MAT-files
+//!   * [x] Compression
 //!
 //! ## Examples
 //!
@@ -49,7 +53,10 @@
 //!                 10.0
 //!             ],
 //!             imag: None
-//!         }
+//!         },
+//!         logical: false,
+//!         global: false,
+//!         storage_type: Double
 //!     }
 //! )
 //! ```
@@ -58,9 +65,21 @@
 #[macro_use]
 extern crate enum_primitive_derive;
 
+mod date;
+mod hdf5;
+mod mcos;
 mod parse;
+mod parse_v4;
+mod parse_v73;
+mod reader;
+mod stream;
+mod view;
+mod write;
 
-/// MatFile is a collection of named arrays.
+use std::borrow::Cow;
+use std::convert::TryFrom;
+
+/// MatFile is a collection of named variables.
 ///
 /// You can load a ".mat" file from disk like this:
 /// ```rust
@@ -72,10 +91,73 @@ mod parse;
 /// ```
 #[derive(Clone, Debug)]
 pub struct MatFile {
-    arrays: Vec<Array>,
+    header: Option<Header>,
+    variables: Vec<Variable>,
+    warnings: Vec<ParseWarning>,
+}
+
+/// The header of a Level 5 MAT-file, or of a MAT-file version 7.3 file,
+/// which starts with a header of the same layout.
+///
+/// The header starts with a descriptive text, which in files written by
+/// Matlab names the platform and the time the file was created on:
+/// ```rust
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let file = std::fs::File::open("tests/double.mat")?;
+/// # let mat_file = matfile::MatFile::parse(file)?;
+/// if let Some(header) = mat_file.header() {
+///     println!("{}", header.text());
+///     println!("Written on {:?} at {:?}", header.platform(), header.created());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Header {
+    text: String,
+    version: u16,
+    byte_order: ByteOrder,
+    subsystem_offset: Option<u64>,
+}
+
+/// The byte order of the numbers in a MAT-file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteOrder {
+    LittleEndian,
+    BigEndian,
+}
+
+/// A variable stored in a .mat file.
+///
+/// Each kind of Matlab array that this library can read is represented by
+/// one variant, all others are kept as `Variable::Unsupported`:
+/// ```rust
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let file = std::fs::File::open("tests/sparse1.mat")?;
+/// # let mat_file = matfile::MatFile::parse(file)?;
+/// for variable in mat_file.variables() {
+///     match variable {
+///         matfile::Variable::Numeric(array) => println!("Numeric array {}", array.name()),
+///         matfile::Variable::Sparse(array) => println!("Sparse array {}", array.name()),
+///         other => println!("Other variable {}", other.name()),
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub enum Variable {
+    Numeric(Array),
+    Sparse(SparseArray),
+    Char(CharArray),
+    Cell(CellArray),
+    Struct(StructArray),
+    Object(ObjectArray),
+    Opaque(OpaqueObject),
+    Unsupported(UnsupportedVariable),
 }
 
-/// A numeric array (the only type supported at the moment).
+/// A numeric array.
 ///
 /// You can access the arrays of a MatFile either by name or by iterating
 /// through all of them:
@@ -93,11 +175,182 @@ pub struct MatFile {
 /// # Ok(())
 /// # }
 /// ```
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Array {
     name: String,
     size: Vec<usize>,
     data: NumericData,
+    logical: bool,
+    global: bool,
+    storage_type: NumericType,
+}
+
+/// A sparse array in compressed sparse column (CSC) format.
+///
+/// Only the nonzero entries are stored. The entries of column `j` are found at
+/// the positions `column_pointer()[j]..column_pointer()[j + 1]` of the
+/// `row_index()` and `data()` vectors, with `row_index()` giving the
+/// (zero-based) row of each entry.
+///
+/// Matlab only supports two-dimensional sparse arrays of type double or
+/// logical. The values are always loaded as `NumericData::Double`, use
+/// `is_logical()` to find out whether the array is a logical one and
+/// `logical_data()` to get the values as booleans.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SparseArray {
+    name: String,
+    size: Vec<usize>,
+    row_index: Vec<usize>,
+    column_pointer: Vec<usize>,
+    data: NumericData,
+    nzmax: usize,
+    logical: bool,
+}
+
+/// A character array.
+///
+/// Matlab stores characters as UTF-16 code units, so this is what `data()`
+/// gives access to (in column-major order, like all other array data). Use
+/// `rows()` to get the text of a two-dimensional character array.
+/// ```rust
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let file = std::fs::File::open("tests/character.mat")?;
+/// # let mat_file = matfile::MatFile::parse(file)?;
+/// if let Some(matfile::Variable::Char(array)) = mat_file.find_variable_by_name("chr") {
+///     println!("{:?}", array.rows());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct CharArray {
+    name: String,
+    size: Vec<usize>,
+    data: Vec<u16>,
+}
+
+/// A cell array.
+///
+/// Every cell holds a variable of its own, which may itself be another cell
+/// array. The cells are stored in column-major order and, as is the case in
+/// Matlab, have empty names.
+/// ```rust
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let file = std::fs::File::open("tests/cell.mat")?;
+/// # let mat_file = matfile::MatFile::parse(file)?;
+/// if let Some(matfile::Variable::Cell(array)) = mat_file.find_variable_by_name("c") {
+///     for cell in array.data() {
+///         println!("Cell of size {:?}", cell.size());
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct CellArray {
+    name: String,
+    size: Vec<usize>,
+    data: Vec<Variable>,
+}
+
+/// A structure array.
+///
+/// All elements of a structure array share the same field names. The value
+/// of each field of each element is a variable of its own, with the elements
+/// being stored in column-major order.
+/// ```rust
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let file = std::fs::File::open("tests/struct.mat")?;
+/// # let mat_file = matfile::MatFile::parse(file)?;
+/// if let Some(matfile::Variable::Struct(array)) = mat_file.find_variable_by_name("cfg") {
+///     println!("Fields: {:?}", array.field_names());
+///     println!("Gain: {:#?}", array.get(0, "gain"));
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct StructArray {
+    name: String,
+    size: Vec<usize>,
+    field_names: Vec<String>,
+    data: Vec<Variable>,
+}
+
+/// An array of objects of a (pre-classdef) Matlab class.
+///
+/// Objects are stored like structure arrays, with the fields holding the
+/// properties of the objects:
+/// ```rust
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let file = std::fs::File::open("tests/object.mat")?;
+/// # let mat_file = matfile::MatFile::parse(file)?;
+/// if let Some(matfile::Variable::Object(array)) = mat_file.find_variable_by_name("cal") {
+///     println!("Object of class {}", array.class_name());
+///     println!("Offset: {:#?}", array.properties().get(0, "offset"));
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObjectArray {
+    class_name: String,
+    properties: StructArray,
+}
+
+/// An array of objects of a classdef class or of a type like `string`,
+/// `datetime` or `table`.
+///
+/// Matlab stores these as opaque objects, with their properties in the
+/// subsystem data of the file. Like the properties of `ObjectArray`s they are
+/// accessed as a structure array:
+/// ```rust
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let file = std::fs::File::open("tests/opaque.mat")?;
+/// # let mat_file = matfile::MatFile::parse(file)?;
+/// for variable in mat_file.variables() {
+///     if let matfile::Variable::Opaque(object) = variable {
+///         println!("{} of class {}", object.name(), object.class_name());
+///         println!("Properties: {:?}", object.properties().field_names());
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Properties that hold their default value are not stored with the objects,
+/// their values are taken from the default values of the class that the file
+/// holds. Opaque objects can't be written.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OpaqueObject {
+    class_name: String,
+    properties: StructArray,
+}
+
+/// A variable of a class that this library can't decode, like a function
+/// handle or an opaque object that isn't part of the MCOS type system.
+///
+/// The variable is kept as the bytes it is stored as, so that it can be
+/// listed and written to another file unchanged:
+/// ```rust
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let file = std::fs::File::open("tests/function_handle.mat")?;
+/// # let mat_file = matfile::MatFile::parse(file)?;
+/// for variable in mat_file.variables() {
+///     if let matfile::Variable::Unsupported(variable) = variable {
+///         println!("Skipping {} of class {}", variable.name(), variable.class().name());
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnsupportedVariable {
+    name: String,
+    class: Class,
+    size: Vec<usize>,
+    raw_bytes: Vec<u8>,
+    byte_order: ByteOrder,
 }
 
 /// Stores the data of a numerical array and abstracts over the actual data
@@ -107,7 +360,14 @@ pub struct Array {
 /// Numerical data is stored in column-major order. When talking about higher
 /// dimensional arrays this means that the index of the first dimension varies
 /// fastest.
-#[derive(Clone, Debug)]
+///
+/// Vectors of numbers convert into numerical data of the matching type, pairs
+/// of vectors into complex data:
+/// ```rust
+/// let real: matfile::NumericData = vec![1i16, 2, 3].into();
+/// let complex: matfile::NumericData = (vec![1.0f32], vec![-1.0f32]).into();
+/// ```
+#[derive(Clone, Debug, PartialEq)]
 pub enum NumericData {
     Int8 {
         real: Vec<i8>,
@@ -151,6 +411,208 @@ pub enum NumericData {
     },
 }
 
+/// The type of the numbers of numerical data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NumericType {
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Int64,
+    UInt64,
+    Single,
+    Double,
+}
+
+impl NumericType {
+    fn try_from(data_type: parse::DataType) -> Result<Self, Error> {
+        match data_type {
+            parse::DataType::Int8 => Ok(NumericType::Int8),
+            parse::DataType::UInt8 => Ok(NumericType::UInt8),
+            parse::DataType::Int16 => Ok(NumericType::Int16),
+            parse::DataType::UInt16 => Ok(NumericType::UInt16),
+            parse::DataType::Int32 => Ok(NumericType::Int32),
+            parse::DataType::UInt32 => Ok(NumericType::UInt32),
+            parse::DataType::Int64 => Ok(NumericType::Int64),
+            parse::DataType::UInt64 => Ok(NumericType::UInt64),
+            parse::DataType::Single => Ok(NumericType::Single),
+            parse::DataType::Double => Ok(NumericType::Double),
+            _ => Err(Error::InternalError),
+        }
+    }
+}
+
+/// Rust number types that arrays can hold, i.e. the element types of
+/// `NumericData`.
+///
+/// This trait is implemented for `i8`, `u8`, `i16`, `u16`, `i32`, `u32`,
+/// `i64`, `u64`, `f32` and `f64` and cannot be implemented for other types.
+pub trait Numeric: write::Number {
+    /// The type of the numbers when stored in a MAT-file.
+    const NUMERIC_TYPE: NumericType;
+}
+
+macro_rules! numeric_impls {
+    ( $( $num:ty => $variant:ident ),* ) => {
+        $(
+            impl Numeric for $num {
+                const NUMERIC_TYPE: NumericType = NumericType::$variant;
+            }
+        )*
+    };
+}
+
+numeric_impls!(
+    i8 => Int8,
+    u8 => UInt8,
+    i16 => Int16,
+    u16 => UInt16,
+    i32 => Int32,
+    u32 => UInt32,
+    i64 => Int64,
+    u64 => UInt64,
+    f32 => Single,
+    f64 => Double
+);
+
+/// The Matlab class of a variable, as shown by Matlab's `class` function and
+/// `whos` command.
+///
+/// Unlike in the file itself, logical arrays have a class of their own and
+/// sparse arrays have the class of their values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    Double,
+    Single,
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Int64,
+    UInt64,
+    Logical,
+    Char,
+    Cell,
+    Struct,
+    Object,
+    FunctionHandle,
+    /// Objects of classdef classes and of types like `string` or `datetime`
+    Opaque,
+}
+
+impl Class {
+    /// The name Matlab uses for this class, e.g. "double" or "struct".
+    ///
+    /// The actual class name of objects is available through
+    /// `ObjectArray::class_name()`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Class::Double => "double",
+            Class::Single => "single",
+            Class::Int8 => "int8",
+            Class::UInt8 => "uint8",
+            Class::Int16 => "int16",
+            Class::UInt16 => "uint16",
+            Class::Int32 => "int32",
+            Class::UInt32 => "uint32",
+            Class::Int64 => "int64",
+            Class::UInt64 => "uint64",
+            Class::Logical => "logical",
+            Class::Char => "char",
+            Class::Cell => "cell",
+            Class::Struct => "struct",
+            Class::Object => "object",
+            Class::FunctionHandle => "function_handle",
+            Class::Opaque => "opaque",
+        }
+    }
+}
+
+impl Class {
+    fn from_array_type(class: parse::ArrayType, logical: bool) -> Self {
+        match class {
+            _ if logical => Class::Logical,
+            parse::ArrayType::Cell => Class::Cell,
+            parse::ArrayType::Struct => Class::Struct,
+            parse::ArrayType::Object => Class::Object,
+            parse::ArrayType::Char => Class::Char,
+            parse::ArrayType::Sparse | parse::ArrayType::Double => Class::Double,
+            parse::ArrayType::Single => Class::Single,
+            parse::ArrayType::Int8 => Class::Int8,
+            parse::ArrayType::UInt8 => Class::UInt8,
+            parse::ArrayType::Int16 => Class::Int16,
+            parse::ArrayType::UInt16 => Class::UInt16,
+            parse::ArrayType::Int32 => Class::Int32,
+            parse::ArrayType::UInt32 => Class::UInt32,
+            parse::ArrayType::Int64 => Class::Int64,
+            parse::ArrayType::UInt64 => Class::UInt64,
+            parse::ArrayType::Function => Class::FunctionHandle,
+            parse::ArrayType::Opaque => Class::Opaque,
+        }
+    }
+
+    fn from_flags(flags: &parse::ArrayFlags) -> Self {
+        Class::from_array_type(flags.class, flags.logical)
+    }
+}
+
+impl From<NumericType> for Class {
+    fn from(numeric_type: NumericType) -> Self {
+        match numeric_type {
+            NumericType::Int8 => Class::Int8,
+            NumericType::UInt8 => Class::UInt8,
+            NumericType::Int16 => Class::Int16,
+            NumericType::UInt16 => Class::UInt16,
+            NumericType::Int32 => Class::Int32,
+            NumericType::UInt32 => Class::UInt32,
+            NumericType::Int64 => Class::Int64,
+            NumericType::UInt64 => Class::UInt64,
+            NumericType::Single => Class::Single,
+            NumericType::Double => Class::Double,
+        }
+    }
+}
+
+// Real data converts into `NumericData` of the matching type, a pair of real
+// and imaginary parts into complex data
+macro_rules! numeric_data_from {
+    ( $( $num:ty => $variant:ident ),* ) => {
+        $(
+            impl From<Vec<$num>> for NumericData {
+                fn from(real: Vec<$num>) -> Self {
+                    NumericData::$variant { real, imag: None }
+                }
+            }
+
+            impl From<(Vec<$num>, Vec<$num>)> for NumericData {
+                fn from((real, imag): (Vec<$num>, Vec<$num>)) -> Self {
+                    NumericData::$variant {
+                        real,
+                        imag: Some(imag),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+numeric_data_from!(
+    i8 => Int8,
+    u8 => UInt8,
+    i16 => Int16,
+    u16 => UInt16,
+    i32 => Int32,
+    u32 => UInt32,
+    i64 => Int64,
+    u64 => UInt64,
+    f32 => Single,
+    f64 => Double
+);
+
 fn try_convert_number_format(
     target_type: parse::ArrayType,
     data: parse::NumericData,
@@ -273,6 +735,46 @@ fn try_convert_number_format(
 }
 
 impl NumericData {
+    /// The type of the numbers.
+    pub fn numeric_type(&self) -> NumericType {
+        match self {
+            NumericData::Int8 { .. } => NumericType::Int8,
+            NumericData::UInt8 { .. } => NumericType::UInt8,
+            NumericData::Int16 { .. } => NumericType::Int16,
+            NumericData::UInt16 { .. } => NumericType::UInt16,
+            NumericData::Int32 { .. } => NumericType::Int32,
+            NumericData::UInt32 { .. } => NumericType::UInt32,
+            NumericData::Int64 { .. } => NumericType::Int64,
+            NumericData::UInt64 { .. } => NumericType::UInt64,
+            NumericData::Single { .. } => NumericType::Single,
+            NumericData::Double { .. } => NumericType::Double,
+        }
+    }
+
+    /// Whether there is an imaginary part.
+    pub fn is_complex(&self) -> bool {
+        self.lengths().1.is_some()
+    }
+
+    // The lengths of the real and (if present) imaginary parts
+    fn lengths(&self) -> (usize, Option<usize>) {
+        fn lengths<T>(real: &[T], imag: &Option<Vec<T>>) -> (usize, Option<usize>) {
+            (real.len(), imag.as_ref().map(|imag| imag.len()))
+        }
+        match self {
+            NumericData::Int8 { real, imag } => lengths(real, imag),
+            NumericData::UInt8 { real, imag } => lengths(real, imag),
+            NumericData::Int16 { real, imag } => lengths(real, imag),
+            NumericData::UInt16 { real, imag } => lengths(real, imag),
+            NumericData::Int32 { real, imag } => lengths(real, imag),
+            NumericData::UInt32 { real, imag } => lengths(real, imag),
+            NumericData::Int64 { real, imag } => lengths(real, imag),
+            NumericData::UInt64 { real, imag } => lengths(real, imag),
+            NumericData::Single { real, imag } => lengths(real, imag),
+            NumericData::Double { real, imag } => lengths(real, imag),
+        }
+    }
+
     fn try_from(
         target_type: parse::ArrayType,
         real: parse::NumericData,
@@ -285,107 +787,87 @@ impl NumericData {
         };
         // The next step should never fail unless there is a bug in the code
         match (real, imag) {
-            (parse::NumericData::Double(real), None) => Ok(NumericData::Double {
-                real: real,
-                imag: None,
-            }),
+            (parse::NumericData::Double(real), None) => {
+                Ok(NumericData::Double { real, imag: None })
+            }
             (parse::NumericData::Double(real), Some(parse::NumericData::Double(imag))) => {
                 Ok(NumericData::Double {
-                    real: real,
+                    real,
                     imag: Some(imag),
                 })
             }
-            (parse::NumericData::Single(real), None) => Ok(NumericData::Single {
-                real: real,
-                imag: None,
-            }),
+            (parse::NumericData::Single(real), None) => {
+                Ok(NumericData::Single { real, imag: None })
+            }
             (parse::NumericData::Single(real), Some(parse::NumericData::Single(imag))) => {
                 Ok(NumericData::Single {
-                    real: real,
+                    real,
                     imag: Some(imag),
                 })
             }
-            (parse::NumericData::UInt64(real), None) => Ok(NumericData::UInt64 {
-                real: real,
-                imag: None,
-            }),
+            (parse::NumericData::UInt64(real), None) => {
+                Ok(NumericData::UInt64 { real, imag: None })
+            }
             (parse::NumericData::UInt64(real), Some(parse::NumericData::UInt64(imag))) => {
                 Ok(NumericData::UInt64 {
-                    real: real,
+                    real,
                     imag: Some(imag),
                 })
             }
-            (parse::NumericData::Int64(real), None) => Ok(NumericData::Int64 {
-                real: real,
-                imag: None,
-            }),
+            (parse::NumericData::Int64(real), None) => Ok(NumericData::Int64 { real, imag: None }),
             (parse::NumericData::Int64(real), Some(parse::NumericData::Int64(imag))) => {
                 Ok(NumericData::Int64 {
-                    real: real,
+                    real,
                     imag: Some(imag),
                 })
             }
-            (parse::NumericData::UInt32(real), None) => Ok(NumericData::UInt32 {
-                real: real,
-                imag: None,
-            }),
+            (parse::NumericData::UInt32(real), None) => {
+                Ok(NumericData::UInt32 { real, imag: None })
+            }
             (parse::NumericData::UInt32(real), Some(parse::NumericData::UInt32(imag))) => {
                 Ok(NumericData::UInt32 {
-                    real: real,
+                    real,
                     imag: Some(imag),
                 })
             }
-            (parse::NumericData::Int32(real), None) => Ok(NumericData::Int32 {
-                real: real,
-                imag: None,
-            }),
+            (parse::NumericData::Int32(real), None) => Ok(NumericData::Int32 { real, imag: None }),
             (parse::NumericData::Int32(real), Some(parse::NumericData::Int32(imag))) => {
                 Ok(NumericData::Int32 {
-                    real: real,
+                    real,
                     imag: Some(imag),
                 })
             }
-            (parse::NumericData::UInt16(real), None) => Ok(NumericData::UInt16 {
-                real: real,
-                imag: None,
-            }),
+            (parse::NumericData::UInt16(real), None) => {
+                Ok(NumericData::UInt16 { real, imag: None })
+            }
             (parse::NumericData::UInt16(real), Some(parse::NumericData::UInt16(imag))) => {
                 Ok(NumericData::UInt16 {
-                    real: real,
+                    real,
                     imag: Some(imag),
                 })
             }
-            (parse::NumericData::Int16(real), None) => Ok(NumericData::Int16 {
-                real: real,
-                imag: None,
-            }),
+            (parse::NumericData::Int16(real), None) => Ok(NumericData::Int16 { real, imag: None }),
             (parse::NumericData::Int16(real), Some(parse::NumericData::Int16(imag))) => {
                 Ok(NumericData::Int16 {
-                    real: real,
+                    real,
                     imag: Some(imag),
                 })
             }
-            (parse::NumericData::UInt8(real), None) => Ok(NumericData::UInt8 {
-                real: real,
-                imag: None,
-            }),
+            (parse::NumericData::UInt8(real), None) => Ok(NumericData::UInt8 { real, imag: None }),
             (parse::NumericData::UInt8(real), Some(parse::NumericData::UInt8(imag))) => {
                 Ok(NumericData::UInt8 {
-                    real: real,
+                    real,
                     imag: Some(imag),
                 })
             }
-            (parse::NumericData::Int8(real), None) => Ok(NumericData::Int8 {
-                real: real,
-                imag: None,
-            }),
+            (parse::NumericData::Int8(real), None) => Ok(NumericData::Int8 { real, imag: None }),
             (parse::NumericData::Int8(real), Some(parse::NumericData::Int8(imag))) => {
                 Ok(NumericData::Int8 {
-                    real: real,
+                    real,
                     imag: Some(imag),
                 })
             }
-            _ => return Err(Error::InternalError),
+            _ => Err(Error::InternalError),
         }
     }
 }
@@ -393,8 +875,20 @@ impl NumericData {
 #[derive(Debug)]
 pub enum Error {
     IOError(std::io::Error),
-    ParseError(nom::Err<&'static [u8], u32>),
+    /// The file is malformed or uses features that are not supported. The
+    /// `ParseError` tells what is wrong and where.
+    ParseError(ParseError),
+    /// The file is a MAT-file version 7.3, which is based on HDF5. These can
+    /// only be loaded as a whole with `MatFile::parse()`, not on demand with
+    /// `MatFileReader`, `MatFileView` or `MatFile::whos()`, and can't be
+    /// appended to.
+    Hdf5NotSupported,
+    /// The operation is only supported for Level 5 MAT-files.
+    Level4NotSupported,
     ConversionError,
+    /// An array's size doesn't match its data or it is otherwise malformed.
+    /// The message describes the problem.
+    InvalidArray(String),
     InternalError,
 }
 
@@ -402,124 +896,2750 @@ impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Error::IOError(_) => write!(f, "An I/O error occurred"),
-            Error::ParseError(_) => write!(f, "An error occurred while parsing the file"),
+            Error::ParseError(err) => {
+                write!(f, "An error occurred while parsing the file: {}", err)
+            }
+            Error::Hdf5NotSupported => write!(
+                f,
+                "This is not supported for MAT-file version 7.3 (HDF5 based) files"
+            ),
+            Error::Level4NotSupported => {
+                write!(f, "This is not supported for Level 4 MAT-files")
+            }
             Error::ConversionError => {
                 write!(f, "An error occurred while converting number formats")
             }
+            Error::InvalidArray(message) => write!(f, "Invalid array: {}", message),
             Error::InternalError => write!(f, "An internal error occurred, this is a bug"),
         }
     }
 }
 
-impl std::error::Error for Error {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+fn invalid_array(name: &str, size: &[usize], message: String) -> Error {
+    Error::InvalidArray(format!("\"{}\" of size {:?}: {}", name, size, message))
+}
+
+// The number of elements of an array of the given size, which needs at least
+// two dimensions that fit into the i32 dimensions of a MAT-file
+fn element_count(size: &[usize]) -> Result<usize, String> {
+    if size.len() < 2 {
+        return Err("arrays need at least two dimensions".to_owned());
+    }
+    if size.iter().any(|&d| d > i32::MAX as usize) {
+        return Err(format!("dimensions can be at most {}", i32::MAX));
+    }
+    size.iter()
+        .try_fold(1usize, |n_elements, &d| n_elements.checked_mul(d))
+        .ok_or_else(|| "too many elements".to_owned())
+}
+
+// Matlab variable and field names start with a letter, followed by at most 62
+// letters, digits or underscores
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && name.len() <= 63
+}
+
+// Checks what is required of variables at the top level of a file
+fn validate_variable(variable: &Variable) -> Result<(), Error> {
+    if !is_valid_name(variable.name()) {
+        return Err(Error::InvalidArray(format!(
+            "\"{}\" is not a valid variable name",
+            variable.name()
+        )));
+    }
+    variable.validate()
+}
+
+impl ByteOrder {
+    fn from_endianness(endianness: nom::Endianness) -> Self {
+        match endianness {
+            nom::Endianness::Little => ByteOrder::LittleEndian,
+            nom::Endianness::Big => ByteOrder::BigEndian,
+        }
+    }
+
+    fn endianness(self) -> nom::Endianness {
         match self {
-            Error::IOError(ref err) => Some(err),
-            _ => None,
+            ByteOrder::LittleEndian => nom::Endianness::Little,
+            ByteOrder::BigEndian => nom::Endianness::Big,
         }
     }
 }
 
-impl Array {
-    /// The name of this array.
-    pub fn name(&self) -> &str {
-        &self.name
+impl Header {
+    fn from(header: parse::Header) -> Self {
+        Header {
+            text: header.text.trim_end_matches([' ', '\0']).to_owned(),
+            version: header.version,
+            byte_order: if header.is_little_endian {
+                ByteOrder::LittleEndian
+            } else {
+                ByteOrder::BigEndian
+            },
+            subsystem_offset: header.subsystem_offset,
+        }
     }
 
-    /// The size of this array.
-    ///
-    /// The number of entries in this vector is equal to the number of
-    /// dimensions of this array. Each array has at least two dimensions.
-    /// For two-dimensional arrays the first dimension is the number of rows
-    /// while the second dimension is the number of columns.
-    pub fn size(&self) -> &Vec<usize> {
-        &self.size
+    /// The descriptive text at the start of the file, without trailing
+    /// padding.
+    pub fn text(&self) -> &str {
+        &self.text
     }
 
-    /// The number of dimensions of this array. Is at least two.
-    pub fn ndims(&self) -> usize {
-        self.size.len()
+    /// The platform the file was written on according to the header text,
+    /// e.g. "GLNXA64" or "PCWIN64".
+    pub fn platform(&self) -> Option<&str> {
+        let start = self.text.find("Platform: ")? + "Platform: ".len();
+        let end = self.text[start..]
+            .find(',')
+            .map_or(self.text.len(), |end| start + end);
+        Some(self.text[start..end].trim())
     }
 
-    /// The actual numerical data stored in this array.
-    ///
-    /// ```rust
-    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
-    /// # let file = std::fs::File::open("tests/double.mat")?;
-    /// # let mat_file = matfile::MatFile::parse(file)?;
-    /// # let array = &mat_file.arrays()[0];
-    /// if let matfile::NumericData::Double { real: real, imag: _ } = array.data() {
-    ///     println!("Real part of the data: {:?}", real);
-    /// }
-    /// # Ok(())
-    /// # }
-    /// ```
+    /// The time the file was created at according to the header text.
     ///
-    /// For a more convenient access to the data, consider using the
-    /// `matfile-ndarray` crate.
-    pub fn data(&self) -> &NumericData {
-        &self.data
+    /// Matlab writes the local time without a time zone, which is
+    /// interpreted as UTC here. `None` is returned if the header text has no
+    /// creation date in the format used by Matlab.
+    pub fn created(&self) -> Option<std::time::SystemTime> {
+        let start = self.text.find("Created on: ")? + "Created on: ".len();
+        let secs = date::parse_timestamp(&self.text[start..])?;
+        Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs))
     }
-}
 
-impl MatFile {
-    /// Tries to parse a byte sequence as a ".mat" file.
-    pub fn parse<R: std::io::Read>(mut reader: R) -> Result<Self, Error> {
-        let mut buf = Vec::new();
-        reader
-            .read_to_end(&mut buf)
-            .map_err(|err| Error::IOError(err))?;
-        let (_remaining, parse_result) = parse::parse_all(&buf)
-            .map_err(|err| Error::ParseError(parse::replace_err_slice(err, &[])))?;
-        let arrays: Result<Vec<Array>, Error> = parse_result
-            .data_elements
-            .into_iter()
-            .filter_map(|data_element| match data_element {
-                parse::DataElement::NumericMatrix(flags, dims, name, real, imag) => {
-                    let size = dims.into_iter().map(|d| d as usize).collect();
-                    let numeric_data = match NumericData::try_from(flags.class, real, imag) {
-                        Ok(numeric_data) => numeric_data,
-                        Err(err) => return Some(Err(err)),
-                    };
-                    Some(Ok(Array {
-                        size: size,
-                        name: name,
-                        data: numeric_data,
-                    }))
-                }
-                _ => None,
-            })
-            .collect();
-        let arrays = arrays?;
-        Ok(MatFile { arrays: arrays })
+    /// The MAT-file version, 0x0100 for Level 5 MAT-files and 0x0200 for
+    /// MAT-file version 7.3 files.
+    pub fn version(&self) -> u16 {
+        self.version
     }
 
-    /// List of all arrays in this .mat file.
-    ///
-    /// When parsing a .mat file all arrays of unsupported type (currently all
-    /// non-numerical and sparse arrays) will be ignored and will thus not be
-    /// part of this list.
-    pub fn arrays(&self) -> &Vec<Array> {
-        &self.arrays
+    /// The byte order of the file.
+    pub fn byte_order(&self) -> ByteOrder {
+        self.byte_order
     }
 
-    /// Returns an array with the given name if it exists. Case sensitive.
+    /// The offset of the subsystem data from the start of the file, if there
+    /// is any.
     ///
-    /// When parsing a .mat file all arrays of unsupported type (currently all
-    /// non-numerical and sparse arrays) will be ignored and will thus not be
-    /// returned by this function.
-    pub fn find_by_name<'me>(&'me self, name: &'_ str) -> Option<&'me Array> {
-        for array in &self.arrays {
-            if array.name == name {
-                return Some(array);
-            }
+    /// Matlab stores data of some types of variables (e.g. `string` or
+    /// `table`) in the subsystem data. It is read along with the variables
+    /// to load them as `OpaqueObject`s.
+    pub fn subsystem_offset(&self) -> Option<u64> {
+        self.subsystem_offset
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IOError(ref err) => Some(err),
+            Error::ParseError(ref err) => Some(err),
+            _ => None,
         }
-        None
     }
 }
 
-// TODO: improve tests.
-// The tests are not very comprehensive yet and they only test whether
+/// What is wrong with a file that could not be parsed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// The file doesn't start with a valid MAT-file header.
+    InvalidHeader,
+    /// The header announces a MAT-file version other than the supported
+    /// Level 5 version.
+    UnsupportedVersion,
+    /// A data element has a type that is not defined by the file format.
+    UnknownDataType,
+    /// An array has a class that is not defined by the file format.
+    UnknownClass,
+    /// The number of values of an array doesn't match its dimensions, or
+    /// they are stored as a type that doesn't fit the class of the array.
+    SizeMismatch,
+    /// A variable or field name is not valid UTF-8.
+    InvalidUtf8,
+    /// The data of a compressed variable can't be inflated.
+    Decompression,
+    /// The file ends in the middle of a variable.
+    Truncated,
+    /// A Level 4 MAT-file stores numbers in a format other than IEEE little
+    /// or big endian, e.g. the VAX or Cray formats.
+    UnsupportedNumberFormat,
+    /// The structure of the file is broken in some other way.
+    Malformed,
+    /// A MAT-file version 7.3 file uses a feature of the HDF5 format that is
+    /// not supported, e.g. structures written by newer HDF5 library versions
+    /// than Matlab uses, or a compression filter other than deflate.
+    UnsupportedHdf5Feature,
+}
+
+impl ParseErrorKind {
+    // The parsers report errors as custom nom errors holding the kind
+    const ALL: [ParseErrorKind; 11] = [
+        ParseErrorKind::InvalidHeader,
+        ParseErrorKind::UnsupportedVersion,
+        ParseErrorKind::UnknownDataType,
+        ParseErrorKind::UnknownClass,
+        ParseErrorKind::SizeMismatch,
+        ParseErrorKind::InvalidUtf8,
+        ParseErrorKind::Decompression,
+        ParseErrorKind::Truncated,
+        ParseErrorKind::UnsupportedNumberFormat,
+        ParseErrorKind::Malformed,
+        ParseErrorKind::UnsupportedHdf5Feature,
+    ];
+
+    fn code(self) -> u32 {
+        self as u32
+    }
+
+    fn from_code(code: u32) -> Option<Self> {
+        ParseErrorKind::ALL.get(code as usize).copied()
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            ParseErrorKind::InvalidHeader => "invalid header",
+            ParseErrorKind::UnsupportedVersion => "unsupported MAT-file version",
+            ParseErrorKind::UnknownDataType => "unknown data type",
+            ParseErrorKind::UnknownClass => "unknown array class",
+            ParseErrorKind::SizeMismatch => "data doesn't match the size or class of the array",
+            ParseErrorKind::InvalidUtf8 => "name is not valid UTF-8",
+            ParseErrorKind::Decompression => "compressed data can't be inflated",
+            ParseErrorKind::Truncated => "unexpected end of file",
+            ParseErrorKind::UnsupportedNumberFormat => "unsupported number format",
+            ParseErrorKind::Malformed => "malformed data",
+            ParseErrorKind::UnsupportedHdf5Feature => "unsupported HDF5 feature",
+        }
+    }
+}
+
+/// Describes what is wrong with a file that could not be parsed, and where.
+///
+/// ```rust
+/// let mut data = std::fs::read("tests/double.mat").unwrap();
+/// data.truncate(150);
+/// match matfile::MatFile::parse(data.as_slice()) {
+///     Err(matfile::Error::ParseError(err)) => {
+///         assert_eq!(err.kind(), matfile::ParseErrorKind::Truncated);
+///         println!("{}", err);
+///     }
+///     _ => unreachable!(),
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    kind: ParseErrorKind,
+    offset: Option<u64>,
+    variable_name: Option<String>,
+}
+
+impl ParseError {
+    fn new(kind: ParseErrorKind) -> Self {
+        ParseError {
+            kind,
+            offset: None,
+            variable_name: None,
+        }
+    }
+
+    // Positions an error that has no more precise position yet
+    fn or_at(mut self, offset: u64) -> Self {
+        self.offset = self.offset.or(Some(offset));
+        self
+    }
+
+    fn in_variable(mut self, name: Option<String>) -> Self {
+        self.variable_name = self.variable_name.or(name);
+        self
+    }
+
+    /// What is wrong with the file.
+    pub fn kind(&self) -> ParseErrorKind {
+        self.kind
+    }
+
+    /// The position in the file, in bytes from its start, at which the
+    /// problem was found, if known.
+    ///
+    /// Problems within compressed variables are reported at the start of
+    /// their compressed data.
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    /// The name of the variable that was being parsed, if known.
+    pub fn variable_name(&self) -> Option<&str> {
+        self.variable_name.as_deref()
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.kind.description())?;
+        if let Some(offset) = self.offset {
+            write!(f, " at byte {}", offset)?;
+        }
+        if let Some(name) = &self.variable_name {
+            write!(f, " in variable \"{}\"", name)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// What happens when a variable can't be parsed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnError {
+    /// Parsing fails with `Error::ParseError`.
+    Fail,
+    /// The variable is left out and parsing continues with the next one,
+    /// with a `ParseWarning::Skipped` describing the problem. Parsing still
+    /// fails if the header of the file is broken.
+    ///
+    /// Variables are skipped by the size stored at their start, so this
+    /// salvages all intact variables of files whose last variable was only
+    /// partially written, or whose variables have damaged content. If the
+    /// size itself is damaged, the rest of the file is skipped.
+    Skip,
+}
+
+/// Options for parsing a MAT-file.
+///
+/// The default options fail on the first variable that can't be parsed.
+#[derive(Clone, Debug)]
+pub struct ParseOptions {
+    on_error: OnError,
+}
+
+impl ParseOptions {
+    /// Creates the default options.
+    pub fn new() -> Self {
+        ParseOptions {
+            on_error: OnError::Fail,
+        }
+    }
+
+    /// Sets what happens when a variable can't be parsed.
+    pub fn on_error(mut self, on_error: OnError) -> Self {
+        self.on_error = on_error;
+        self
+    }
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions::new()
+    }
+}
+
+/// A problem found while parsing a file that didn't keep it from being
+/// loaded, see `MatFile::warnings()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseWarning {
+    /// A variable couldn't be parsed and was left out, see `OnError::Skip`.
+    Skipped(ParseError),
+    /// A variable of a kind that is not supported, or another kind of data
+    /// that is not a variable, was left out.
+    Unsupported {
+        offset: u64,
+        variable_name: Option<String>,
+    },
+}
+
+impl ParseWarning {
+    /// The position of the problem in the file, in bytes from its start, if
+    /// known.
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            ParseWarning::Skipped(err) => err.offset(),
+            ParseWarning::Unsupported { offset, .. } => Some(*offset),
+        }
+    }
+
+    /// The name of the variable that was left out, if known.
+    pub fn variable_name(&self) -> Option<&str> {
+        match self {
+            ParseWarning::Skipped(err) => err.variable_name(),
+            ParseWarning::Unsupported { variable_name, .. } => variable_name.as_deref(),
+        }
+    }
+}
+
+impl std::fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseWarning::Skipped(err) => write!(f, "skipped variable: {}", err),
+            ParseWarning::Unsupported {
+                offset,
+                variable_name,
+            } => {
+                write!(f, "unsupported data at byte {}", offset)?;
+                if let Some(name) = variable_name {
+                    write!(f, " in variable \"{}\"", name)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Array {
+    /// Creates a numeric array of the given size.
+    ///
+    /// The name has to be a valid Matlab variable name (or empty, e.g. for
+    /// arrays stored in cells) and the data has to hold exactly one value
+    /// per element of the array, in column-major order:
+    /// ```rust
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let array = matfile::Array::new("A", vec![2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0].into())?;
+    /// assert!(matfile::Array::new("A", vec![2, 2], vec![1.0, 2.0].into()).is_err());
+    /// assert!(matfile::Array::new("1st", vec![1, 1], vec![1.0].into()).is_err());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn new<S: Into<String>>(
+        name: S,
+        size: Vec<usize>,
+        data: NumericData,
+    ) -> Result<Self, Error> {
+        let array = Array {
+            name: name.into(),
+            size,
+            storage_type: data.numeric_type(),
+            data,
+            logical: false,
+            global: false,
+        };
+        array.validated()
+    }
+
+    /// Creates a logical array of the given size.
+    ///
+    /// Matlab stores logical arrays as `uint8` arrays with a logical flag, so
+    /// `data()` of the new array holds ones and zeros:
+    /// ```rust
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mask = matfile::Array::new_logical("mask", vec![1, 3], vec![true, false, true])?;
+    /// assert!(mask.is_logical());
+    /// assert_eq!(mask.logical_data(), Some(vec![true, false, true]));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn new_logical<S: Into<String>>(
+        name: S,
+        size: Vec<usize>,
+        data: Vec<bool>,
+    ) -> Result<Self, Error> {
+        let array = Array {
+            name: name.into(),
+            size,
+            data: NumericData::UInt8 {
+                real: data.into_iter().map(u8::from).collect(),
+                imag: None,
+            },
+            logical: true,
+            global: false,
+            storage_type: NumericType::UInt8,
+        };
+        array.validated()
+    }
+
+    // Returns the new array if both its name and data are valid
+    fn validated(self) -> Result<Self, Error> {
+        if !self.name.is_empty() && !is_valid_name(&self.name) {
+            return Err(invalid_array(
+                &self.name,
+                &self.size,
+                "invalid name".to_owned(),
+            ));
+        }
+        self.validate()?;
+        Ok(self)
+    }
+
+    // Checks that the data of this array is consistent with its size
+    fn validate(&self) -> Result<(), Error> {
+        let invalid = |message: String| Err(invalid_array(&self.name, &self.size, message));
+        let n_elements = match element_count(&self.size) {
+            Ok(n_elements) => n_elements,
+            Err(message) => return invalid(message),
+        };
+        let (real, imag) = self.data.lengths();
+        if real != n_elements {
+            return invalid(format!("expected {} values, got {}", n_elements, real));
+        }
+        if self.logical && !matches!(self.data, NumericData::UInt8 { imag: None, .. }) {
+            return invalid("logical arrays must hold real uint8 data".to_owned());
+        }
+        match imag {
+            Some(imag) if imag != real => invalid(format!(
+                "real part has {} values, imaginary part has {}",
+                real, imag
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The name of this array.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The size of this array.
+    ///
+    /// The number of entries in this vector is equal to the number of
+    /// dimensions of this array. Each array has at least two dimensions.
+    /// For two-dimensional arrays the first dimension is the number of rows
+    /// while the second dimension is the number of columns.
+    pub fn size(&self) -> &Vec<usize> {
+        &self.size
+    }
+
+    /// The number of dimensions of this array. Is at least two.
+    pub fn ndims(&self) -> usize {
+        self.size.len()
+    }
+
+    /// The actual numerical data stored in this array.
+    ///
+    /// ```rust
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = std::fs::File::open("tests/double.mat")?;
+    /// # let mat_file = matfile::MatFile::parse(file)?;
+    /// # let array = &mat_file.arrays()[0];
+    /// if let matfile::NumericData::Double { real, imag: _ } = array.data() {
+    ///     println!("Real part of the data: {:?}", real);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// For a more convenient access to the data, consider using the
+    /// `matfile-ndarray` crate.
+    pub fn data(&self) -> &NumericData {
+        &self.data
+    }
+
+    /// Whether this is a logical array.
+    ///
+    /// The data of logical arrays is `NumericData::UInt8`, use
+    /// `logical_data()` to get it as booleans.
+    pub fn is_logical(&self) -> bool {
+        self.logical
+    }
+
+    /// The data of a logical array as booleans in column-major order.
+    ///
+    /// Returns `None` if this is not a logical array.
+    pub fn logical_data(&self) -> Option<Vec<bool>> {
+        match &self.data {
+            NumericData::UInt8 { real, imag: None } if self.logical => {
+                Some(real.iter().map(|&x| x != 0).collect())
+            }
+            _ => None,
+        }
+    }
+
+    /// The Matlab class of this array.
+    ///
+    /// This is the type of `data()`, except for logical arrays.
+    pub fn class(&self) -> Class {
+        if self.logical {
+            Class::Logical
+        } else {
+            self.data.numeric_type().into()
+        }
+    }
+
+    /// Whether this array has an imaginary part.
+    pub fn is_complex(&self) -> bool {
+        self.data.is_complex()
+    }
+
+    /// Whether this array was declared as a global variable in Matlab.
+    pub fn is_global(&self) -> bool {
+        self.global
+    }
+
+    /// Sets whether this array is written as a global variable.
+    pub fn global(mut self, global: bool) -> Self {
+        self.global = global;
+        self
+    }
+
+    /// The type the numbers of this array were stored as in the file.
+    ///
+    /// To save space Matlab stores numbers in the smallest type that can
+    /// represent them exactly, e.g. a double array holding only small
+    /// integers may be stored as `NumericType::Int16`. The data is always
+    /// converted to the type matching `class()` when loading.
+    pub fn storage_type(&self) -> NumericType {
+        self.storage_type
+    }
+}
+
+impl SparseArray {
+    /// The name of this array.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The size of this array. Sparse arrays are always two-dimensional, so
+    /// this is the number of rows followed by the number of columns.
+    pub fn size(&self) -> &Vec<usize> {
+        &self.size
+    }
+
+    /// The row indices of the stored entries. Has one entry per stored value.
+    pub fn row_index(&self) -> &Vec<usize> {
+        &self.row_index
+    }
+
+    /// The offsets into `row_index()` and `data()` at which each column starts.
+    ///
+    /// Has one entry more than there are columns, the last entry being equal
+    /// to the number of stored values.
+    pub fn column_pointer(&self) -> &Vec<usize> {
+        &self.column_pointer
+    }
+
+    /// The values of the stored entries, in the same order as `row_index()`.
+    pub fn data(&self) -> &NumericData {
+        &self.data
+    }
+
+    /// The maximum number of nonzero entries this array was allocated for in
+    /// Matlab.
+    pub fn nzmax(&self) -> usize {
+        self.nzmax
+    }
+
+    /// The number of stored entries.
+    pub fn nnz(&self) -> usize {
+        self.column_pointer[self.size[1]]
+    }
+
+    /// Whether this is a logical (as opposed to a double) sparse array.
+    pub fn is_logical(&self) -> bool {
+        self.logical
+    }
+
+    /// The values of the stored entries of a logical sparse array as
+    /// booleans, in the same order as `row_index()`.
+    ///
+    /// Returns `None` if this is not a logical array.
+    pub fn logical_data(&self) -> Option<Vec<bool>> {
+        match &self.data {
+            NumericData::Double { real, imag: None } if self.logical => {
+                Some(real.iter().map(|&x| x != 0.0).collect())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl CharArray {
+    /// The name of this array.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The size of this array.
+    ///
+    /// For a single line of text this is `[1, length]`.
+    pub fn size(&self) -> &Vec<usize> {
+        &self.size
+    }
+
+    /// The number of dimensions of this array. Is at least two.
+    pub fn ndims(&self) -> usize {
+        self.size.len()
+    }
+
+    /// The characters of this array as UTF-16 code units in column-major
+    /// order.
+    pub fn data(&self) -> &Vec<u16> {
+        &self.data
+    }
+
+    /// The rows of a two-dimensional character array as strings.
+    ///
+    /// Returns `None` if the array has more than two dimensions. Invalid
+    /// UTF-16 sequences are replaced by the replacement character U+FFFD.
+    pub fn rows(&self) -> Option<Vec<String>> {
+        if self.ndims() != 2 {
+            return None;
+        }
+        let n_rows = self.size[0];
+        let rows = (0..n_rows)
+            .map(|row| {
+                let code_units: Vec<u16> = self
+                    .data
+                    .iter()
+                    .skip(row)
+                    .step_by(n_rows)
+                    .cloned()
+                    .collect();
+                String::from_utf16_lossy(&code_units)
+            })
+            .collect();
+        Some(rows)
+    }
+}
+
+impl CellArray {
+    /// The name of this array.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The size of this array.
+    pub fn size(&self) -> &Vec<usize> {
+        &self.size
+    }
+
+    /// The number of dimensions of this array. Is at least two.
+    pub fn ndims(&self) -> usize {
+        self.size.len()
+    }
+
+    /// The cells of this array in column-major order.
+    pub fn data(&self) -> &Vec<Variable> {
+        &self.data
+    }
+}
+
+impl StructArray {
+    /// The name of this array.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The size of this array.
+    pub fn size(&self) -> &Vec<usize> {
+        &self.size
+    }
+
+    /// The number of dimensions of this array. Is at least two.
+    pub fn ndims(&self) -> usize {
+        self.size.len()
+    }
+
+    /// The number of elements of this array.
+    pub fn len(&self) -> usize {
+        self.size.iter().product()
+    }
+
+    /// Whether this array has no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The field names shared by all elements, in the order in which they
+    /// are stored in the file.
+    pub fn field_names(&self) -> &Vec<String> {
+        &self.field_names
+    }
+
+    /// The values of all fields of the element with the given (column-major)
+    /// index, in the same order as `field_names()`.
+    pub fn element(&self, index: usize) -> Option<&[Variable]> {
+        if index >= self.len() {
+            return None;
+        }
+        let n_fields = self.field_names.len();
+        Some(&self.data[index * n_fields..(index + 1) * n_fields])
+    }
+
+    /// The value of the field with the given name of the element with the
+    /// given (column-major) index. Field names are case sensitive.
+    pub fn get(&self, index: usize, field_name: &str) -> Option<&Variable> {
+        let field_index = self
+            .field_names
+            .iter()
+            .position(|name| name == field_name)?;
+        self.element(index).map(|element| &element[field_index])
+    }
+}
+
+impl ObjectArray {
+    /// The name of this array.
+    pub fn name(&self) -> &str {
+        self.properties.name()
+    }
+
+    /// The size of this array.
+    pub fn size(&self) -> &Vec<usize> {
+        self.properties.size()
+    }
+
+    /// The name of the class the objects belong to.
+    pub fn class_name(&self) -> &str {
+        &self.class_name
+    }
+
+    /// The properties of the objects as a structure array of the same name
+    /// and size.
+    pub fn properties(&self) -> &StructArray {
+        &self.properties
+    }
+}
+
+impl OpaqueObject {
+    /// The name of this array.
+    pub fn name(&self) -> &str {
+        self.properties.name()
+    }
+
+    /// The size of this array.
+    pub fn size(&self) -> &Vec<usize> {
+        self.properties.size()
+    }
+
+    /// The name of the class the objects belong to, including its package.
+    pub fn class_name(&self) -> &str {
+        &self.class_name
+    }
+
+    /// The properties of the objects as a structure array of the same name
+    /// and size.
+    pub fn properties(&self) -> &StructArray {
+        &self.properties
+    }
+}
+
+impl UnsupportedVariable {
+    /// The name of this variable.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The Matlab class of this variable.
+    pub fn class(&self) -> Class {
+        self.class
+    }
+
+    /// The size of this variable.
+    ///
+    /// Empty for opaque objects, which are stored without a size.
+    pub fn size(&self) -> &Vec<usize> {
+        &self.size
+    }
+
+    /// The contents of the matrix data element of the variable as stored in
+    /// the file, i.e. without the tag and after decompressing it.
+    pub fn raw_bytes(&self) -> &Vec<u8> {
+        &self.raw_bytes
+    }
+
+    /// The byte order of the numbers in `raw_bytes()`. The variable can only
+    /// be written to files of the same byte order.
+    pub fn byte_order(&self) -> ByteOrder {
+        self.byte_order
+    }
+}
+
+impl Variable {
+    /// The name of this variable.
+    pub fn name(&self) -> &str {
+        match self {
+            Variable::Numeric(array) => array.name(),
+            Variable::Sparse(array) => array.name(),
+            Variable::Char(array) => array.name(),
+            Variable::Cell(array) => array.name(),
+            Variable::Struct(array) => array.name(),
+            Variable::Object(array) => array.name(),
+            Variable::Opaque(object) => object.name(),
+            Variable::Unsupported(variable) => variable.name(),
+        }
+    }
+
+    /// The size of this variable.
+    pub fn size(&self) -> &Vec<usize> {
+        match self {
+            Variable::Numeric(array) => array.size(),
+            Variable::Sparse(array) => array.size(),
+            Variable::Char(array) => array.size(),
+            Variable::Cell(array) => array.size(),
+            Variable::Struct(array) => array.size(),
+            Variable::Object(array) => array.size(),
+            Variable::Opaque(object) => object.size(),
+            Variable::Unsupported(variable) => variable.size(),
+        }
+    }
+
+    /// The Matlab class of this variable.
+    pub fn class(&self) -> Class {
+        match self {
+            Variable::Numeric(array) => array.class(),
+            Variable::Sparse(array) if array.is_logical() => Class::Logical,
+            Variable::Sparse(_) => Class::Double,
+            Variable::Char(_) => Class::Char,
+            Variable::Cell(_) => Class::Cell,
+            Variable::Struct(_) => Class::Struct,
+            Variable::Object(_) => Class::Object,
+            Variable::Opaque(_) => Class::Opaque,
+            Variable::Unsupported(variable) => variable.class(),
+        }
+    }
+
+    // Checks that the variable can be written to a .mat file, i.e. that its
+    // data is consistent with its size
+    fn validate(&self) -> Result<(), Error> {
+        let invalid = |message: String| Err(invalid_array(self.name(), self.size(), message));
+        // Checked by `check_writable`
+        if let Variable::Opaque(_) | Variable::Unsupported(_) = self {
+            return Ok(());
+        }
+        let size = self.size();
+        let n_elements = match element_count(size) {
+            Ok(n_elements) => n_elements,
+            Err(message) => return invalid(message),
+        };
+        match self {
+            Variable::Numeric(array) => array.validate()?,
+            Variable::Sparse(array) => {
+                let (real, imag) = array.data.lengths();
+                if size.len() != 2 {
+                    return invalid("sparse arrays must be two-dimensional".to_owned());
+                }
+                if !matches!(array.data, NumericData::Double { .. }) {
+                    return invalid("sparse arrays must hold double values".to_owned());
+                }
+                if array.column_pointer.len() != size[1] + 1
+                    || array.column_pointer[0] != 0
+                    || array.column_pointer.windows(2).any(|w| w[0] > w[1])
+                    || array.column_pointer[size[1]] != array.row_index.len()
+                {
+                    return invalid("invalid column pointers".to_owned());
+                }
+                if array.row_index.iter().any(|&row| row >= size[0]) {
+                    return invalid("row index out of bounds".to_owned());
+                }
+                if real != array.row_index.len() || imag.is_some_and(|imag| imag != real) {
+                    return invalid("expected one value per row index".to_owned());
+                }
+            }
+            Variable::Char(array) => {
+                if array.data.len() != n_elements {
+                    return invalid(format!(
+                        "expected {} characters, got {}",
+                        n_elements,
+                        array.data.len()
+                    ));
+                }
+            }
+            Variable::Cell(array) => {
+                if array.data.len() != n_elements {
+                    return invalid(format!(
+                        "expected {} cells, got {}",
+                        n_elements,
+                        array.data.len()
+                    ));
+                }
+                for cell in &array.data {
+                    cell.validate()?;
+                }
+            }
+            Variable::Struct(array)
+            | Variable::Object(ObjectArray {
+                properties: array, ..
+            }) => {
+                for (i, field_name) in array.field_names.iter().enumerate() {
+                    if !is_valid_name(field_name) {
+                        return invalid(format!("invalid field name \"{}\"", field_name));
+                    }
+                    if array.field_names[..i].contains(field_name) {
+                        return invalid(format!("duplicate field name \"{}\"", field_name));
+                    }
+                }
+                let n_values = match n_elements.checked_mul(array.field_names.len()) {
+                    Some(n_values) => n_values,
+                    None => return invalid("too many field values".to_owned()),
+                };
+                if array.data.len() != n_values {
+                    return invalid(format!(
+                        "expected {} field values, got {}",
+                        n_values,
+                        array.data.len()
+                    ));
+                }
+                for value in &array.data {
+                    value.validate()?;
+                }
+            }
+            Variable::Opaque(_) | Variable::Unsupported(_) => unreachable!(),
+        }
+        Ok(())
+    }
+
+    // Checks that the variable holds no opaque objects, whose data is not
+    // part of the variable itself, and that unsupported variables, which can't
+    // be converted, are written in the byte order they were read in
+    fn check_writable(&self, byte_order: ByteOrder) -> Result<(), Error> {
+        let nested = match self {
+            Variable::Opaque(_) => {
+                return Err(invalid_array(
+                    self.name(),
+                    self.size(),
+                    "opaque objects can't be written".to_owned(),
+                ))
+            }
+            Variable::Cell(array) => &array.data,
+            Variable::Struct(array)
+            | Variable::Object(ObjectArray {
+                properties: array, ..
+            }) => &array.data,
+            Variable::Unsupported(variable) if variable.byte_order != byte_order => {
+                return Err(invalid_array(
+                    self.name(),
+                    self.size(),
+                    format!(
+                        "unsupported variables can only be written as {:?}",
+                        variable.byte_order
+                    ),
+                ))
+            }
+            _ => return Ok(()),
+        };
+        nested
+            .iter()
+            .try_for_each(|variable| variable.check_writable(byte_order))
+    }
+
+    // Whether the variable holds references to opaque objects that still
+    // have to be resolved
+    fn holds_opaque_objects(&self) -> bool {
+        match self {
+            Variable::Cell(array) => array.data.iter().any(Variable::holds_opaque_objects),
+            Variable::Struct(array)
+            | Variable::Object(ObjectArray {
+                properties: array, ..
+            }) => array.data.iter().any(Variable::holds_opaque_objects),
+            Variable::Unsupported(variable) => variable.class == Class::Opaque,
+            _ => false,
+        }
+    }
+
+    fn try_from(data_element: parse::DataElement) -> Result<Option<Self>, Error> {
+        match data_element {
+            parse::DataElement::NumericMatrix(flags, dims, name, real, imag) => {
+                let size = dims.into_iter().map(|d| d as usize).collect();
+                let storage_type = NumericType::try_from(real.data_type())?;
+                let data = NumericData::try_from(flags.class, real, imag)?;
+                Ok(Some(Variable::Numeric(Array {
+                    name,
+                    size,
+                    data,
+                    logical: flags.logical,
+                    global: flags.global,
+                    storage_type,
+                })))
+            }
+            parse::DataElement::SparseMatrix(
+                flags,
+                dims,
+                name,
+                row_index,
+                column_pointer,
+                real,
+                imag,
+            ) => {
+                let size: Vec<usize> = dims.into_iter().map(|d| d as usize).collect();
+                let malformed = || {
+                    Error::ParseError(
+                        ParseError::new(ParseErrorKind::Malformed).in_variable(Some(name.clone())),
+                    )
+                };
+                let (row_index, column_pointer) =
+                    sparse_indices(&size, row_index, column_pointer).ok_or_else(malformed)?;
+                let mut data = NumericData::try_from(parse::ArrayType::Double, real, imag)?;
+                // Matlab allocates room for `nzmax` entries, only the first
+                // of which, up to the last column pointer, are in use
+                if let NumericData::Double { real, imag } = &mut data {
+                    if real.len() < row_index.len() {
+                        return Err(malformed());
+                    }
+                    real.truncate(row_index.len());
+                    if let Some(imag) = imag {
+                        imag.truncate(row_index.len());
+                    }
+                }
+                Ok(Some(Variable::Sparse(SparseArray {
+                    name,
+                    size,
+                    row_index,
+                    column_pointer,
+                    data,
+                    nzmax: flags.nzmax,
+                    logical: flags.logical,
+                })))
+            }
+            parse::DataElement::CharacterMatrix(_flags, dims, name, data) => {
+                let size = dims.into_iter().map(|d| d as usize).collect();
+                Ok(Some(Variable::Char(CharArray { name, size, data })))
+            }
+            parse::DataElement::CellMatrix(_flags, dims, name, cells) => {
+                let size = dims.into_iter().map(|d| d as usize).collect();
+                Ok(Variable::try_from_all(cells)?
+                    .map(|data| Variable::Cell(CellArray { name, size, data })))
+            }
+            parse::DataElement::StructureMatrix(_flags, dims, name, field_names, values) => {
+                let size = dims.into_iter().map(|d| d as usize).collect();
+                Ok(Variable::try_from_all(values)?.map(|data| {
+                    Variable::Struct(StructArray {
+                        name,
+                        size,
+                        field_names,
+                        data,
+                    })
+                }))
+            }
+            parse::DataElement::ObjectMatrix(
+                _flags,
+                dims,
+                name,
+                class_name,
+                field_names,
+                values,
+            ) => {
+                let size = dims.into_iter().map(|d| d as usize).collect();
+                Ok(Variable::try_from_all(values)?.map(|data| {
+                    Variable::Object(ObjectArray {
+                        class_name,
+                        properties: StructArray {
+                            name,
+                            size,
+                            field_names,
+                            data,
+                        },
+                    })
+                }))
+            }
+            parse::DataElement::UnsupportedMatrix(flags, dims, name, raw_bytes, endianness) => {
+                Ok(Some(Variable::Unsupported(UnsupportedVariable {
+                    name,
+                    class: Class::from_flags(&flags),
+                    size: dims.into_iter().map(|d| d as usize).collect(),
+                    raw_bytes,
+                    byte_order: ByteOrder::from_endianness(endianness),
+                })))
+            }
+            parse::DataElement::Unsupported | parse::DataElement::Filler => Ok(None),
+        }
+    }
+
+    // Converts the nested elements of a cell or structure array. An array
+    // with unsupported nested elements can't be represented faithfully, so
+    // `None` is returned in that case to skip the array as a whole.
+    fn try_from_all(data_elements: Vec<parse::DataElement>) -> Result<Option<Vec<Self>>, Error> {
+        let mut variables = Vec::with_capacity(data_elements.len());
+        for data_element in data_elements {
+            match Variable::try_from(data_element)? {
+                Some(variable) => variables.push(variable),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(variables))
+    }
+}
+
+// Converts the row indices and column pointers of a sparse array of the
+// given size, dropping the row indices beyond the last column pointer.
+// Returns `None` if they don't describe a valid array.
+fn sparse_indices(
+    size: &[usize],
+    row_index: parse::RowIndex,
+    column_pointer: parse::ColumnShift,
+) -> Option<(Vec<usize>, Vec<usize>)> {
+    let to_usize = |indices: Vec<i32>| -> Option<Vec<usize>> {
+        indices
+            .into_iter()
+            .map(|i| usize::try_from(i).ok())
+            .collect()
+    };
+    let (rows, columns) = match *size {
+        [rows, columns] => (rows, columns),
+        _ => return None,
+    };
+    let mut row_index = to_usize(row_index)?;
+    let column_pointer = to_usize(column_pointer)?;
+    if column_pointer.len() != columns.checked_add(1)?
+        || column_pointer[0] != 0
+        || column_pointer.windows(2).any(|w| w[0] > w[1])
+        || column_pointer[columns] > row_index.len()
+    {
+        return None;
+    }
+    row_index.truncate(column_pointer[columns]);
+    if row_index.iter().any(|&row| row >= rows) {
+        return None;
+    }
+    Some((row_index, column_pointer))
+}
+
+impl MatFile {
+    /// Tries to parse a byte sequence as a ".mat" file.
+    ///
+    /// Both Level 5 (up to version 7) and the legacy Level 4 MAT-files are
+    /// supported, as well as MAT-file version 7.3 files (which are HDF5 files).
+    /// The format is detected automatically.
+    ///
+    /// Version 7.3 files are read as written by Matlab, with numeric, logical,
+    /// character, sparse, cell and structure arrays. Variables of other
+    /// classes, e.g. function handles or objects, are left out with a
+    /// `ParseWarning::Unsupported`. Variables are loaded in the order of their
+    /// names, which is how they are listed in the file. Version 7.3 files can
+    /// only be loaded as a whole, by this function and
+    /// `MatFile::parse_with_options()`: `MatFileReader`, `MatFile::whos()`,
+    /// `MatFileView` and `MatFileAppender` return `Error::Hdf5NotSupported` for
+    /// them.
+    ///
+    /// With the `rayon` feature enabled, the variables of Level 5 MAT-files
+    /// are inflated and parsed in parallel.
+    pub fn parse<R: std::io::Read>(reader: R) -> Result<Self, Error> {
+        MatFile::parse_with_options(reader, &ParseOptions::new())
+    }
+
+    /// Tries to parse a byte sequence as a ".mat" file using the given
+    /// options.
+    ///
+    /// To load whatever can be loaded of a damaged file, e.g. one that was
+    /// not completely written:
+    /// ```rust
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = std::fs::File::open("tests/two_arrays.mat")?;
+    /// let options = matfile::ParseOptions::new().on_error(matfile::OnError::Skip);
+    /// let mat_file = matfile::MatFile::parse_with_options(file, &options)?;
+    /// for warning in mat_file.warnings() {
+    ///     eprintln!("{}", warning);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn parse_with_options<R: std::io::Read>(
+        mut reader: R,
+        options: &ParseOptions,
+    ) -> Result<Self, Error> {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).map_err(Error::IOError)?;
+        if parse::is_hdf5_mat_file(&buf) {
+            let (header, variables, warnings) = parse_v73::parse_all(&buf, options.on_error)?;
+            return Ok(MatFile {
+                header: Some(header),
+                variables,
+                warnings,
+            });
+        }
+        let level4 = parse_v4::is_level4_mat_file(&buf);
+        let format = if level4 {
+            Some(reader::Format::Level4)
+        } else {
+            parse::parse_header(&buf)
+                .ok()
+                .map(|(_remaining, header)| reader::Format::of(&header))
+        };
+        // Name the variable at a position, if its header is intact
+        let variable_name_at = |offset| {
+            format.and_then(|format| {
+                reader::variable_name_at(&mut std::io::Cursor::new(&buf), format, offset)
+            })
+        };
+        let parse_error = |err| {
+            let err = parse::to_parse_error(err, &buf, 0);
+            let name = err.offset().and_then(variable_name_at);
+            err.in_variable(name)
+        };
+        let (header, data_elements) = if level4 {
+            let (_remaining, data_elements) = parse_v4::parse_all(&buf, options.on_error)
+                .map_err(|err| Error::ParseError(parse_error(err)))?;
+            (None, data_elements)
+        } else {
+            let (_remaining, parse_result) = parse::parse_all(&buf, options.on_error)
+                .map_err(|err| Error::ParseError(parse_error(err)))?;
+            (
+                Some(Header::from(parse_result.header)),
+                parse_result.data_elements,
+            )
+        };
+        let mut warnings: Vec<ParseWarning> = data_elements
+            .errors
+            .into_iter()
+            .map(|err| ParseWarning::Skipped(parse_error(err)))
+            .collect();
+        let subsystem_offset = header
+            .as_ref()
+            .and_then(|header| header.subsystem_offset)
+            .map(|offset| offset as usize);
+        let mut subsystem = None;
+        let mut variables = Vec::new();
+        for (offset, data_element) in data_elements.parsed {
+            match Variable::try_from(data_element)? {
+                Some(variable) if Some(offset) == subsystem_offset => {
+                    subsystem = Some((offset as u64, variable))
+                }
+                Some(variable) => variables.push(variable),
+                None => {
+                    let offset = offset as u64;
+                    warnings.push(ParseWarning::Unsupported {
+                        offset,
+                        variable_name: variable_name_at(offset),
+                    })
+                }
+            }
+        }
+        // Opaque objects refer to the subsystem data for their properties
+        if let Some((offset, subsystem)) = subsystem {
+            match mcos::parse_subsystem(&subsystem) {
+                Ok(subsystem) => {
+                    variables = variables
+                        .into_iter()
+                        .map(|variable| subsystem.resolve(variable))
+                        .collect()
+                }
+                Err(Error::ParseError(err)) if options.on_error == OnError::Skip => {
+                    warnings.push(ParseWarning::Skipped(err.or_at(offset)))
+                }
+                Err(Error::ParseError(err)) => return Err(Error::ParseError(err.or_at(offset))),
+                Err(err) => return Err(err),
+            }
+        }
+        warnings.sort_by_key(|warning| warning.offset());
+        Ok(MatFile {
+            header,
+            variables,
+            warnings,
+        })
+    }
+
+    /// Lists the variables of a ".mat" file without loading their data, like
+    /// Matlab's `whos -file`.
+    ///
+    /// Only the array flags, dimensions and name of each variable are read,
+    /// so this is much faster than `MatFile::parse` for large files:
+    /// ```rust
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let file = std::fs::File::open("tests/sparse1.mat")?;
+    /// for info in matfile::MatFile::whos(file)? {
+    ///     println!(
+    ///         "{} {:?} {:?} {}",
+    ///         info.name(),
+    ///         info.size(),
+    ///         info.bytes(),
+    ///         info.class().name()
+    ///     );
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn whos<R: std::io::Read + std::io::Seek>(reader: R) -> Result<Vec<VariableInfo>, Error> {
+        let reader = MatFileReader::new(reader)?;
+        Ok(reader
+            .directory
+            .into_iter()
+            .map(|entry| entry.info)
+            .collect())
+    }
+
+    /// The header of the file this MatFile was loaded from.
+    ///
+    /// Level 4 MAT-files don't have a header and neither do MatFiles created
+    /// with a `MatFileBuilder`, `None` is returned for those.
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    /// Problems found while parsing the file this MatFile was loaded from,
+    /// in the order of their position in the file.
+    pub fn warnings(&self) -> &Vec<ParseWarning> {
+        &self.warnings
+    }
+
+    /// Writes this MatFile as a Level 5 MAT-file (without compression).
+    ///
+    /// The result can be loaded by Matlab, Octave or SciPy, as well as by
+    /// `MatFile::parse`:
+    /// ```rust
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mat_file = matfile::MatFileBuilder::new()
+    ///     .add_array(
+    ///         "A",
+    ///         vec![2, 2],
+    ///         matfile::NumericData::Double {
+    ///             real: vec![1.0, 2.0, 3.0, 4.0],
+    ///             imag: None,
+    ///         },
+    ///     )
+    ///     .add_char_array("label", "Hello")
+    ///     .build()?;
+    /// let mut buf = Vec::new();
+    /// mat_file.write(&mut buf)?;
+    /// let loaded = matfile::MatFile::parse(buf.as_slice())?;
+    /// assert_eq!(loaded.variables().len(), 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn write<W: std::io::Write>(&self, writer: W) -> Result<(), Error> {
+        self.write_with_options(writer, &WriteOptions::new())
+    }
+
+    /// Writes this MatFile as a Level 5 MAT-file using the given options.
+    ///
+    /// To compress the variables like Matlab's `save -v7` does:
+    /// ```rust
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = std::fs::File::open("tests/double.mat")?;
+    /// # let mat_file = matfile::MatFile::parse(file)?;
+    /// let options = matfile::WriteOptions::new().compression(matfile::Compression::Default);
+    /// let mut buf = Vec::new();
+    /// mat_file.write_with_options(&mut buf, &options)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn write_with_options<W: std::io::Write>(
+        &self,
+        writer: W,
+        options: &WriteOptions,
+    ) -> Result<(), Error> {
+        for variable in &self.variables {
+            variable.check_writable(options.byte_order)?;
+        }
+        write::write_all(writer, self, options)
+    }
+
+    /// List of all variables in this .mat file, in the order in which they
+    /// are stored in the file.
+    ///
+    /// Variables of classes that can't be decoded, like function handles,
+    /// are listed as `Variable::Unsupported`. Arrays holding data that isn't
+    /// a variable at all are left out and reported by `MatFile::warnings()`.
+    pub fn variables(&self) -> &Vec<Variable> {
+        &self.variables
+    }
+
+    /// Returns a variable with the given name if it exists. Case sensitive.
+    pub fn find_variable_by_name<'me>(&'me self, name: &'_ str) -> Option<&'me Variable> {
+        self.variables
+            .iter()
+            .find(|variable| variable.name() == name)
+    }
+
+    /// List of all numeric arrays in this .mat file.
+    ///
+    /// Variables of any other type are not part of this list, use
+    /// `variables()` to access them.
+    pub fn arrays(&self) -> Vec<&Array> {
+        self.variables
+            .iter()
+            .filter_map(|variable| match variable {
+                Variable::Numeric(array) => Some(array),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns a numeric array with the given name if it exists. Case
+    /// sensitive.
+    ///
+    /// Variables of any other type will not be returned by this function, use
+    /// `find_variable_by_name()` to access them.
+    pub fn find_by_name<'me>(&'me self, name: &'_ str) -> Option<&'me Array> {
+        self.arrays().into_iter().find(|array| array.name == name)
+    }
+}
+
+/// Reads the variables of a ".mat" file on demand.
+///
+/// Unlike `MatFile::parse`, which loads all variables at once, a
+/// MatFileReader only reads the names, classes and sizes of the variables
+/// when it is created. Each variable can then be loaded on its own:
+/// ```rust
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let file = std::fs::File::open("tests/struct.mat")?;
+/// let mut reader = matfile::MatFileReader::new(file)?;
+/// for entry in reader.directory() {
+///     println!("{} {:?} {}", entry.name(), entry.size(), entry.class().name());
+/// }
+/// if let Some(cfg) = reader.read_variable("cfg")? {
+///     println!("{:#?}", cfg);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// For compressed variables only as much data is inflated as is needed to
+/// get to the name, which keeps creating a reader cheap even for large files.
+#[derive(Debug)]
+pub struct MatFileReader<R> {
+    reader: R,
+    format: reader::Format,
+    header: Option<Header>,
+    directory: Vec<DirectoryEntry>,
+    // Loaded when the first opaque object is read
+    subsystem: Option<mcos::Subsystem>,
+}
+
+/// Information about a variable that can be read without loading its data,
+/// like the listing of Matlab's `whos -file`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VariableInfo {
+    name: String,
+    class: Class,
+    size: Vec<usize>,
+    complex: bool,
+    global: bool,
+    sparse: bool,
+    nzmax: usize,
+}
+
+impl VariableInfo {
+    /// The name of the variable.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The Matlab class of the variable.
+    pub fn class(&self) -> Class {
+        self.class
+    }
+
+    /// The size of the variable.
+    pub fn size(&self) -> &Vec<usize> {
+        &self.size
+    }
+
+    /// Whether the variable has complex values.
+    pub fn is_complex(&self) -> bool {
+        self.complex
+    }
+
+    /// Whether the variable was saved as a global variable.
+    pub fn is_global(&self) -> bool {
+        self.global
+    }
+
+    /// Whether the variable is a sparse array.
+    pub fn is_sparse(&self) -> bool {
+        self.sparse
+    }
+
+    /// The number of bytes the data of the variable takes up in memory, as
+    /// reported by Matlab's `whos`.
+    ///
+    /// Sparse arrays account for their values, row indices and column
+    /// pointers. Returns `None` for cell, structure and object arrays, whose
+    /// footprint depends on the values they hold, and for variables whose
+    /// size in the file is too large to make sense of.
+    pub fn bytes(&self) -> Option<u64> {
+        let element_size = match self.class {
+            Class::Double | Class::Int64 | Class::UInt64 => 8,
+            Class::Single | Class::Int32 | Class::UInt32 => 4,
+            Class::Int16 | Class::UInt16 | Class::Char => 2,
+            Class::Int8 | Class::UInt8 | Class::Logical => 1,
+            Class::Cell | Class::Struct | Class::Object | Class::FunctionHandle | Class::Opaque => {
+                return None
+            }
+        };
+        let element_size = if self.complex {
+            2 * element_size
+        } else {
+            element_size
+        };
+        if self.sparse {
+            let columns = self.size.get(1).copied().unwrap_or(0) as u64;
+            let values = (self.nzmax as u64).checked_mul(element_size + 8)?;
+            let column_pointers = columns.checked_add(1)?.checked_mul(8)?;
+            values.checked_add(column_pointers)
+        } else {
+            self.size
+                .iter()
+                .try_fold(element_size, |bytes, &d| bytes.checked_mul(d as u64))
+        }
+    }
+}
+
+/// A variable in the directory of a `MatFileReader`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DirectoryEntry {
+    info: VariableInfo,
+    offset: u64,
+    byte_size: u64,
+    compressed: bool,
+}
+
+impl DirectoryEntry {
+    /// The name of the variable.
+    pub fn name(&self) -> &str {
+        &self.info.name
+    }
+
+    /// The Matlab class of the variable.
+    pub fn class(&self) -> Class {
+        self.info.class
+    }
+
+    /// The size of the variable.
+    pub fn size(&self) -> &Vec<usize> {
+        &self.info.size
+    }
+
+    /// Further information about the variable.
+    pub fn info(&self) -> &VariableInfo {
+        &self.info
+    }
+
+    /// The offset in bytes from the start of the file at which the variable
+    /// is stored.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The number of bytes the (possibly compressed) variable takes up in
+    /// the file.
+    pub fn byte_size(&self) -> u64 {
+        self.byte_size
+    }
+
+    /// Whether the variable is stored compressed.
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    // The offset right after the variable and its padding
+    fn end(&self) -> u64 {
+        if self.compressed {
+            self.offset + self.byte_size
+        } else {
+            self.offset + self.byte_size.div_ceil(8) * 8
+        }
+    }
+}
+
+impl<R: std::io::Read + std::io::Seek> MatFileReader<R> {
+    /// Creates a reader for a ".mat" file and reads its directory.
+    ///
+    /// Like `MatFile::parse` this supports Level 5 and Level 4 MAT-files.
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let (format, header, directory) = reader::scan(&mut reader)?;
+        Ok(MatFileReader {
+            reader,
+            format,
+            header: header.map(Header::from),
+            directory,
+            subsystem: None,
+        })
+    }
+
+    /// The header of the file, `None` for Level 4 MAT-files.
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    /// All variables in the file, in the order in which they are stored.
+    ///
+    /// Unlike `MatFile::variables()` this also lists variables that can't be
+    /// loaded, e.g. cell arrays holding values of unsupported type.
+    pub fn directory(&self) -> &Vec<DirectoryEntry> {
+        &self.directory
+    }
+
+    /// Loads the variable with the given name. Case sensitive.
+    ///
+    /// Returns `None` if there is no such variable or if it is of an
+    /// unsupported type.
+    pub fn read_variable(&mut self, name: &str) -> Result<Option<Variable>, Error> {
+        let variable = match self.directory.iter().find(|entry| entry.name() == name) {
+            Some(entry) => reader::read_variable(&mut self.reader, self.format, entry)?,
+            None => return Ok(None),
+        };
+        match variable {
+            Some(variable) if variable.holds_opaque_objects() => {
+                let subsystem = self.subsystem()?;
+                Ok(Some(match subsystem {
+                    Some(subsystem) => subsystem.resolve(variable),
+                    None => variable,
+                }))
+            }
+            variable => Ok(variable),
+        }
+    }
+
+    fn subsystem(&mut self) -> Result<Option<&mcos::Subsystem>, Error> {
+        let offset = self
+            .header
+            .as_ref()
+            .and_then(|header| header.subsystem_offset);
+        if let (None, Some(offset)) = (&self.subsystem, offset) {
+            self.subsystem = Some(reader::read_subsystem(
+                &mut self.reader,
+                self.format,
+                offset,
+            )?);
+        }
+        Ok(self.subsystem.as_ref())
+    }
+
+    /// Gives back the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Zero-copy access to the numeric arrays of a ".mat" file held in memory,
+/// typically a memory mapped file.
+///
+/// For uncompressed Level 5 MAT-files whose byte order matches the one of
+/// the machine, the numbers of an array are stored contiguously in the file.
+/// `array()` borrows them directly from the underlying bytes in that case:
+/// ```rust
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let data = std::fs::read("tests/double.mat")?;
+/// let view = matfile::MatFileView::new(data.as_slice())?;
+/// if let Some(array) = view.array("A")? {
+///     if let matfile::NumericDataView::Double { real, .. } = array.data() {
+///         println!("{:?}", real);
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Arrays that are compressed, byte-swapped, misaligned or stored in a
+/// different type than their class (see `Array::storage_type()`) are copied
+/// instead, so `array()` works for every numeric array. With the `mmap`
+/// feature enabled, `MatFileView::map()` memory maps a file.
+#[derive(Debug)]
+pub struct MatFileView<B> {
+    bytes: B,
+    format: reader::Format,
+    header: Option<Header>,
+    directory: Vec<DirectoryEntry>,
+}
+
+impl<B: AsRef<[u8]>> MatFileView<B> {
+    /// Creates a view of the bytes of a ".mat" file and reads its directory.
+    pub fn new(bytes: B) -> Result<Self, Error> {
+        let (format, header, directory) = reader::scan(&mut std::io::Cursor::new(bytes.as_ref()))?;
+        Ok(MatFileView {
+            bytes,
+            format,
+            header: header.map(Header::from),
+            directory,
+        })
+    }
+
+    /// The header of the file, `None` for Level 4 MAT-files.
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    /// All variables in the file, in the order in which they are stored.
+    pub fn directory(&self) -> &Vec<DirectoryEntry> {
+        &self.directory
+    }
+
+    /// Gives access to the numeric array with the given name. Case sensitive.
+    ///
+    /// Returns `None` if there is no such variable or if it is not a numeric
+    /// array.
+    pub fn array(&self, name: &str) -> Result<Option<ArrayView<'_>>, Error> {
+        let entry = match self.directory.iter().find(|entry| entry.name() == name) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let bytes = self.bytes.as_ref();
+        if let reader::Format::Level5(endianness) = self.format {
+            let start = entry.offset as usize;
+            let element = bytes.get(start..start + entry.byte_size as usize);
+            if let Some(element) = element.filter(|_| !entry.compressed) {
+                if let Some(array) = view::borrowed_array_view(element, endianness) {
+                    return Ok(Some(array));
+                }
+            }
+        }
+        let mut reader = std::io::Cursor::new(bytes);
+        match reader::read_variable(&mut reader, self.format, entry)? {
+            Some(Variable::Numeric(array)) => Ok(Some(array.into())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Gives back the underlying bytes.
+    pub fn into_inner(self) -> B {
+        self.bytes
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl MatFileView<memmap2::Mmap> {
+    /// Memory maps a ".mat" file and reads its directory.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated while it is mapped, see
+    /// `memmap2::Mmap::map()`.
+    pub unsafe fn map(file: &std::fs::File) -> Result<Self, Error> {
+        let mmap = memmap2::Mmap::map(file).map_err(Error::IOError)?;
+        MatFileView::new(mmap)
+    }
+}
+
+/// A numeric array of a `MatFileView`, whose data may be borrowed from the
+/// underlying file.
+#[derive(Clone, Debug)]
+pub struct ArrayView<'a> {
+    name: String,
+    size: Vec<usize>,
+    data: NumericDataView<'a>,
+    logical: bool,
+    global: bool,
+    storage_type: NumericType,
+}
+
+impl<'a> ArrayView<'a> {
+    /// The name of this array.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The size of this array, see `Array::size()`.
+    pub fn size(&self) -> &Vec<usize> {
+        &self.size
+    }
+
+    /// The number of dimensions of this array. Is at least two.
+    pub fn ndims(&self) -> usize {
+        self.size.len()
+    }
+
+    /// The numerical data of this array.
+    pub fn data(&self) -> &NumericDataView<'a> {
+        &self.data
+    }
+
+    /// Whether this is a logical array, see `Array::is_logical()`.
+    pub fn is_logical(&self) -> bool {
+        self.logical
+    }
+
+    /// The Matlab class of this array.
+    pub fn class(&self) -> Class {
+        if self.logical {
+            Class::Logical
+        } else {
+            self.data.numeric_type().into()
+        }
+    }
+
+    /// Whether this array has an imaginary part.
+    pub fn is_complex(&self) -> bool {
+        self.data.is_complex()
+    }
+
+    /// Whether this array was declared as a global variable in Matlab.
+    pub fn is_global(&self) -> bool {
+        self.global
+    }
+
+    /// The type the numbers of this array were stored as in the file, see
+    /// `Array::storage_type()`.
+    pub fn storage_type(&self) -> NumericType {
+        self.storage_type
+    }
+
+    /// Copies the data of this array if it is borrowed.
+    pub fn into_owned(self) -> Array {
+        Array {
+            name: self.name,
+            size: self.size,
+            data: self.data.into_owned(),
+            logical: self.logical,
+            global: self.global,
+            storage_type: self.storage_type,
+        }
+    }
+}
+
+impl From<Array> for ArrayView<'static> {
+    fn from(array: Array) -> Self {
+        ArrayView {
+            name: array.name,
+            size: array.size,
+            data: array.data.into(),
+            logical: array.logical,
+            global: array.global,
+            storage_type: array.storage_type,
+        }
+    }
+}
+
+/// The data of an `ArrayView`.
+///
+/// Like `NumericData`, but the real and imaginary parts are either borrowed
+/// from the underlying file or owned copies.
+#[derive(Clone, Debug)]
+pub enum NumericDataView<'a> {
+    Int8 {
+        real: Cow<'a, [i8]>,
+        imag: Option<Cow<'a, [i8]>>,
+    },
+    UInt8 {
+        real: Cow<'a, [u8]>,
+        imag: Option<Cow<'a, [u8]>>,
+    },
+    Int16 {
+        real: Cow<'a, [i16]>,
+        imag: Option<Cow<'a, [i16]>>,
+    },
+    UInt16 {
+        real: Cow<'a, [u16]>,
+        imag: Option<Cow<'a, [u16]>>,
+    },
+    Int32 {
+        real: Cow<'a, [i32]>,
+        imag: Option<Cow<'a, [i32]>>,
+    },
+    UInt32 {
+        real: Cow<'a, [u32]>,
+        imag: Option<Cow<'a, [u32]>>,
+    },
+    Int64 {
+        real: Cow<'a, [i64]>,
+        imag: Option<Cow<'a, [i64]>>,
+    },
+    UInt64 {
+        real: Cow<'a, [u64]>,
+        imag: Option<Cow<'a, [u64]>>,
+    },
+    Single {
+        real: Cow<'a, [f32]>,
+        imag: Option<Cow<'a, [f32]>>,
+    },
+    Double {
+        real: Cow<'a, [f64]>,
+        imag: Option<Cow<'a, [f64]>>,
+    },
+}
+
+macro_rules! numeric_data_view_variants {
+    ( $data:expr, $real:pat, $imag:pat => $body:expr ) => {
+        match $data {
+            NumericDataView::Int8 {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericDataView::UInt8 {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericDataView::Int16 {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericDataView::UInt16 {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericDataView::Int32 {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericDataView::UInt32 {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericDataView::Int64 {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericDataView::UInt64 {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericDataView::Single {
+                real: $real,
+                imag: $imag,
+            } => $body,
+            NumericDataView::Double {
+                real: $real,
+                imag: $imag,
+            } => $body,
+        }
+    };
+}
+
+impl NumericDataView<'_> {
+    /// The type of the numbers.
+    pub fn numeric_type(&self) -> NumericType {
+        match self {
+            NumericDataView::Int8 { .. } => NumericType::Int8,
+            NumericDataView::UInt8 { .. } => NumericType::UInt8,
+            NumericDataView::Int16 { .. } => NumericType::Int16,
+            NumericDataView::UInt16 { .. } => NumericType::UInt16,
+            NumericDataView::Int32 { .. } => NumericType::Int32,
+            NumericDataView::UInt32 { .. } => NumericType::UInt32,
+            NumericDataView::Int64 { .. } => NumericType::Int64,
+            NumericDataView::UInt64 { .. } => NumericType::UInt64,
+            NumericDataView::Single { .. } => NumericType::Single,
+            NumericDataView::Double { .. } => NumericType::Double,
+        }
+    }
+
+    /// Whether there is an imaginary part.
+    pub fn is_complex(&self) -> bool {
+        numeric_data_view_variants!(self, _, imag => imag.is_some())
+    }
+
+    /// Whether the numbers are borrowed from the file rather than copied.
+    pub fn is_borrowed(&self) -> bool {
+        numeric_data_view_variants!(self, real, imag => {
+            matches!(real, Cow::Borrowed(_)) && !matches!(imag, Some(Cow::Owned(_)))
+        })
+    }
+
+    /// Copies the numbers if they are borrowed.
+    pub fn into_owned(self) -> NumericData {
+        macro_rules! into_owned {
+            ( $( $variant:ident ),* ) => {
+                match self {
+                    $(
+                        NumericDataView::$variant { real, imag } => NumericData::$variant {
+                            real: real.into_owned(),
+                            imag: imag.map(Cow::into_owned),
+                        },
+                    )*
+                }
+            };
+        }
+        into_owned!(Int8, UInt8, Int16, UInt16, Int32, UInt32, Int64, UInt64, Single, Double)
+    }
+}
+
+impl From<NumericData> for NumericDataView<'static> {
+    fn from(data: NumericData) -> Self {
+        macro_rules! from_owned {
+            ( $( $variant:ident ),* ) => {
+                match data {
+                    $(
+                        NumericData::$variant { real, imag } => NumericDataView::$variant {
+                            real: Cow::Owned(real),
+                            imag: imag.map(Cow::Owned),
+                        },
+                    )*
+                }
+            };
+        }
+        from_owned!(Int8, UInt8, Int16, UInt16, Int32, UInt32, Int64, UInt64, Single, Double)
+    }
+}
+
+/// How variables are compressed when writing a MAT-file.
+///
+/// Compressed variables can be loaded by Matlab 7 and later.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Variables are stored uncompressed, as with Matlab's `save -v6`.
+    None,
+    /// Variables are zlib compressed, trading some compression for speed.
+    Fast,
+    /// Variables are zlib compressed, as with Matlab's `save -v7`.
+    Default,
+}
+
+/// Options for writing a MAT-file.
+///
+/// The default options write the variables without compression in little
+/// endian byte order.
+///
+/// To write a big endian file, as Matlab does on SPARC or PowerPC machines:
+/// ```rust
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let file = std::fs::File::open("tests/double.mat")?;
+/// # let mat_file = matfile::MatFile::parse(file)?;
+/// let options = matfile::WriteOptions::new().byte_order(matfile::ByteOrder::BigEndian);
+/// let mut buf = Vec::new();
+/// mat_file.write_with_options(&mut buf, &options)?;
+/// assert_eq!(&buf[126..128], b"MI");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct WriteOptions {
+    compression: Compression,
+    byte_order: ByteOrder,
+}
+
+impl WriteOptions {
+    /// Creates the default options.
+    pub fn new() -> Self {
+        WriteOptions {
+            compression: Compression::None,
+            byte_order: ByteOrder::LittleEndian,
+        }
+    }
+
+    /// Sets how the variables are compressed.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the byte order of the file.
+    ///
+    /// `MatFileAppender` ignores this and always writes in the byte order of
+    /// the file it appends to.
+    pub fn byte_order(mut self, byte_order: ByteOrder) -> Self {
+        self.byte_order = byte_order;
+        self
+    }
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions::new()
+    }
+}
+
+/// Appends variables to an existing Level 5 MAT-file, like Matlab's
+/// `save -append`.
+///
+/// The header of the file is validated when opening it. New variables are
+/// written to the end of the file without touching the ones already in it:
+/// ```rust
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let path = std::env::temp_dir().join("matfile_appender_doc.mat");
+/// # std::fs::copy("tests/double.mat", &path)?;
+/// let file = std::fs::OpenOptions::new()
+///     .read(true)
+///     .write(true)
+///     .open(&path)?;
+/// let mut appender = matfile::MatFileAppender::open(file)?;
+/// let t = matfile::Array::new("t", vec![1, 1], vec![12.5].into())?;
+/// appender.append(&matfile::Variable::Numeric(t))?;
+/// # std::fs::remove_file(&path)?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Appending a variable with the name of one that is already in the file
+/// replaces it. If the new variable takes up as much space as the old one, it
+/// is written in its place. Otherwise the old variable is overwritten with a
+/// filler element, which holds no variable and is skipped when loading the
+/// file, and the new variable is appended. The space of replaced variables is
+/// not reused, writing the loaded file anew gets rid of it. Other variables
+/// are never moved, only the subsystem data, which Matlab stores after all
+/// variables, is moved behind new ones. If this is interrupted, the file may
+/// be left corrupted.
+#[derive(Debug)]
+pub struct MatFileAppender {
+    file: std::fs::File,
+    endianness: nom::Endianness,
+    directory: Vec<DirectoryEntry>,
+    subsystem_offset: Option<u64>,
+    options: WriteOptions,
+}
+
+impl MatFileAppender {
+    /// Opens a Level 5 MAT-file for appending variables without compression.
+    ///
+    /// The file has to be opened for both reading and writing.
+    pub fn open(file: std::fs::File) -> Result<Self, Error> {
+        MatFileAppender::open_with_options(file, &WriteOptions::new())
+    }
+
+    /// Opens a Level 5 MAT-file for appending variables with the given
+    /// options. Variables are written in the byte order of the file.
+    pub fn open_with_options(
+        mut file: std::fs::File,
+        options: &WriteOptions,
+    ) -> Result<Self, Error> {
+        let (format, header, directory) = reader::scan(&mut file)?;
+        match format {
+            reader::Format::Level5(endianness) => Ok(MatFileAppender {
+                file,
+                endianness,
+                directory,
+                subsystem_offset: header.and_then(|header| header.subsystem_offset),
+                options: options.clone(),
+            }),
+            reader::Format::Level4 => Err(Error::Level4NotSupported),
+        }
+    }
+
+    /// All variables in the file, in the order in which they are stored.
+    pub fn directory(&self) -> &Vec<DirectoryEntry> {
+        &self.directory
+    }
+
+    /// Appends a variable, replacing the variable of the same name if there
+    /// is one.
+    pub fn append(&mut self, variable: &Variable) -> Result<(), Error> {
+        use std::io::{Seek, SeekFrom};
+
+        validate_variable(variable)?;
+        variable.check_writable(ByteOrder::from_endianness(self.endianness))?;
+        let data_element =
+            write::variable_data_element(variable, self.endianness, self.options.compression)?;
+        let file_len = self.file.seek(SeekFrom::End(0)).map_err(Error::IOError)?;
+        let replaced = self
+            .directory
+            .iter()
+            .position(|entry| entry.name() == variable.name());
+        // The old variable is overwritten if the new one takes up the same
+        // space, otherwise it is replaced by a filler and the new one goes to
+        // the end
+        let in_place = replaced.filter(|&index| {
+            let entry = &self.directory[index];
+            entry.end() - entry.offset == data_element.len() as u64
+        });
+        if let Some(index) = in_place {
+            let offset = self.directory[index].offset;
+            self.write_at(offset, &data_element)?;
+            self.directory[index] = self.scan_entry(offset)?;
+            return Ok(());
+        }
+        // The last element of the file might lack its padding
+        let mut end = self
+            .directory
+            .iter()
+            .map(DirectoryEntry::end)
+            .fold(file_len, u64::max);
+        if let Some(index) = replaced {
+            let removed = self.directory.remove(index);
+            let filler = write::filler((removed.end() - removed.offset) as usize, self.endianness)
+                .map_err(Error::IOError)?;
+            self.write_at(removed.offset, &filler)?;
+            end = end.max(removed.end());
+        }
+        let subsystem = match self.subsystem_offset {
+            Some(subsystem_offset) => self.trailing_subsystem(subsystem_offset, end)?,
+            None => None,
+        };
+        let offset = match subsystem {
+            // Matlab stores the subsystem data last, so the variable goes
+            // before it and only the subsystem data is moved
+            Some((subsystem_offset, subsystem)) => {
+                let moved_offset = subsystem_offset + data_element.len() as u64;
+                self.write_at(moved_offset, &subsystem)?;
+                let bytes = match self.endianness {
+                    nom::Endianness::Little => moved_offset.to_le_bytes(),
+                    nom::Endianness::Big => moved_offset.to_be_bytes(),
+                };
+                self.write_at(116, &bytes)?;
+                self.subsystem_offset = Some(moved_offset);
+                subsystem_offset
+            }
+            None => {
+                self.write_at(file_len, &vec![0; (end - file_len) as usize])?;
+                end
+            }
+        };
+        self.write_at(offset, &data_element)?;
+        let entry = self.scan_entry(offset)?;
+        self.directory.push(entry);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        use std::io::{Seek, SeekFrom, Write};
+
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .map_err(Error::IOError)?;
+        self.file.write_all(data).map_err(Error::IOError)
+    }
+
+    // Scans the variable written at the given offset, so that the directory
+    // can be updated without scanning the whole file again
+    fn scan_entry(&mut self, offset: u64) -> Result<DirectoryEntry, Error> {
+        let format = reader::Format::Level5(self.endianness);
+        match reader::scan_entry(&mut self.file, format, offset)? {
+            Some((Some(entry), _next_offset)) => Ok(entry),
+            _ => Err(Error::InternalError),
+        }
+    }
+
+    // Reads the subsystem data stored at the given offset, padded to its full
+    // size, if nothing comes after it up to the given end of the file
+    fn trailing_subsystem(
+        &mut self,
+        subsystem_offset: u64,
+        end: u64,
+    ) -> Result<Option<(u64, Vec<u8>)>, Error> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let format = reader::Format::Level5(self.endianness);
+        let next_offset = match reader::scan_entry(&mut self.file, format, subsystem_offset)? {
+            Some((_entry, next_offset)) if next_offset >= end => next_offset,
+            _ => return Ok(None),
+        };
+        let mut subsystem = Vec::new();
+        self.file
+            .seek(SeekFrom::Start(subsystem_offset))
+            .map_err(Error::IOError)?;
+        (&mut self.file)
+            .take(next_offset - subsystem_offset)
+            .read_to_end(&mut subsystem)
+            .map_err(Error::IOError)?;
+        subsystem.resize((next_offset - subsystem_offset) as usize, 0);
+        Ok(Some((subsystem_offset, subsystem)))
+    }
+
+    /// Gives back the file.
+    pub fn into_inner(self) -> std::fs::File {
+        self.file
+    }
+}
+
+/// Writes a MAT-file one variable at a time, without holding all of them in
+/// memory.
+///
+/// Numeric arrays can also be written in chunks of values, so that even
+/// arrays larger than the available memory can be written. The values are
+/// expected in column-major order, first the real parts of all elements and
+/// then, for complex arrays, the imaginary parts:
+/// ```rust
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let file = std::fs::File::create(std::env::temp_dir().join("ramp.mat"))?;
+/// let mut writer = matfile::MatWriter::new(file)?;
+/// let mut ramp = writer.start_array::<f64, _>("ramp", vec![1000, 1000], false)?;
+/// for column in 0..1000 {
+///     let values: Vec<f64> = (0..1000).map(|row| f64::from(row + column)).collect();
+///     ramp.write(&values)?;
+/// }
+/// ramp.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// With compression, the compressed data of an array is held in memory until
+/// the array is finished, as its size has to be written before it. Writers
+/// that can seek, like files, avoid this when created with
+/// `MatWriter::new_seekable()`.
+#[derive(Debug)]
+pub struct MatWriter<W: std::io::Write> {
+    writer: W,
+    endianness: nom::Endianness,
+    options: WriteOptions,
+    names: Vec<String>,
+    // The name of an array that was started but not finished
+    unfinished: Option<String>,
+    // Overwrites bytes before the current position, for writers that can seek
+    write_back: Option<WriteBack<W>>,
+}
+
+type WriteBack<W> = fn(&mut W, u64, &[u8]) -> std::io::Result<()>;
+
+// Writes the bytes starting `distance` bytes before the current position of
+// the writer, and moves back to that position
+fn write_back<W: std::io::Write + std::io::Seek>(
+    writer: &mut W,
+    distance: u64,
+    bytes: &[u8],
+) -> std::io::Result<()> {
+    use std::io::SeekFrom;
+
+    // Compressed data elements are at most a few GB
+    let distance = distance as i64;
+    writer.seek(SeekFrom::Current(-distance))?;
+    writer.write_all(bytes)?;
+    writer.seek(SeekFrom::Current(distance - bytes.len() as i64))?;
+    Ok(())
+}
+
+impl<W: std::io::Write> MatWriter<W> {
+    /// Starts a MAT-file without compression by writing its header.
+    pub fn new(writer: W) -> Result<Self, Error> {
+        MatWriter::new_with_options(writer, &WriteOptions::new())
+    }
+
+    /// Starts a MAT-file with the given options by writing its header.
+    pub fn new_with_options(mut writer: W, options: &WriteOptions) -> Result<Self, Error> {
+        let endianness = options.byte_order.endianness();
+        writer
+            .write_all(&write::header(endianness))
+            .map_err(Error::IOError)?;
+        Ok(MatWriter {
+            writer,
+            endianness,
+            options: options.clone(),
+            names: Vec::new(),
+            unfinished: None,
+            write_back: None,
+        })
+    }
+
+    // Checks that a variable of this name can be written next
+    fn add_name(&mut self, name: &str) -> Result<(), Error> {
+        if let Some(unfinished) = &self.unfinished {
+            return Err(Error::InvalidArray(format!(
+                "\"{}\" was not finished",
+                unfinished
+            )));
+        }
+        if self.names.iter().any(|other| other == name) {
+            return Err(Error::InvalidArray(format!(
+                "there is more than one variable named \"{}\"",
+                name
+            )));
+        }
+        self.names.push(name.to_owned());
+        Ok(())
+    }
+
+    /// Writes a whole variable.
+    pub fn write_variable(&mut self, variable: &Variable) -> Result<(), Error> {
+        validate_variable(variable)?;
+        variable.check_writable(ByteOrder::from_endianness(self.endianness))?;
+        let data_element =
+            write::variable_data_element(variable, self.endianness, self.options.compression)?;
+        self.add_name(variable.name())?;
+        self.writer.write_all(&data_element).map_err(Error::IOError)
+    }
+
+    /// Starts a numeric array of the given size holding numbers of type `T`,
+    /// whose values are then written with `ArrayWriter::write()`.
+    ///
+    /// The array has to be finished with `ArrayWriter::finish()` before the
+    /// next variable can be written.
+    pub fn start_array<T: Numeric, S: Into<String>>(
+        &mut self,
+        name: S,
+        size: Vec<usize>,
+        complex: bool,
+    ) -> Result<ArrayWriter<'_, W, T>, Error> {
+        let name = name.into();
+        let invalid = |message: String| Err(invalid_array(&name, &size, message));
+        if !is_valid_name(&name) {
+            return Err(Error::InvalidArray(format!(
+                "\"{}\" is not a valid variable name",
+                name
+            )));
+        }
+        let len = match element_count(&size) {
+            Ok(len) => len,
+            Err(message) => return invalid(message),
+        };
+        let part_size = len.checked_mul(std::mem::size_of::<T>());
+        let start =
+            write::numeric_matrix_start(&name, &size, T::NUMERIC_TYPE, complex, self.endianness);
+        let byte_size = part_size.and_then(|part_size| {
+            let n_parts = if complex { 2 } else { 1 };
+            write::data_element_size(part_size)
+                .checked_mul(n_parts)?
+                .checked_add(start.len())
+                .filter(|&byte_size| write::fits_data_element(byte_size))
+        });
+        let (part_size, byte_size) = match (part_size, byte_size) {
+            (Some(part_size), Some(byte_size)) => (part_size, byte_size),
+            _ => return invalid("too large to be written to a MAT-file".to_owned()),
+        };
+        self.add_name(&name)?;
+        self.unfinished = Some(name);
+
+        let encoder = match self.options.compression {
+            Compression::None => None,
+            compression => Some(write::compressor(compression).map_err(Error::IOError)?),
+        };
+        if encoder.is_some() && self.write_back.is_some() {
+            // Filled in with the size of the compressed data once it is known
+            let tag = write::tag(parse::DataType::Compressed, 0, self.endianness);
+            self.writer.write_all(&tag).map_err(Error::IOError)?;
+        }
+        let mut array_writer = ArrayWriter {
+            mat_writer: self,
+            encoder,
+            n_compressed: 0,
+            len,
+            part_size,
+            complex,
+            n_written: 0,
+            marker: std::marker::PhantomData,
+        };
+        let endianness = array_writer.mat_writer.endianness;
+        array_writer.emit(&write::tag(parse::DataType::Matrix, byte_size, endianness))?;
+        array_writer.emit(&start)?;
+        array_writer.emit(&write::numeric_tag(T::NUMERIC_TYPE, part_size, endianness))?;
+        if len == 0 {
+            array_writer.end_of_real_part()?;
+        }
+        Ok(array_writer)
+    }
+
+    /// Gives back the writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: std::io::Write + std::io::Seek> MatWriter<W> {
+    /// Starts a MAT-file with the given options by writing its header.
+    ///
+    /// Compressed arrays started with `start_array()` are written to the
+    /// writer as they are compressed. The size of their compressed data is
+    /// filled in by seeking back when they are finished.
+    pub fn new_seekable(writer: W, options: &WriteOptions) -> Result<Self, Error> {
+        let mut mat_writer = MatWriter::new_with_options(writer, options)?;
+        mat_writer.write_back = Some(write_back::<W>);
+        Ok(mat_writer)
+    }
+}
+
+/// Writes the values of a numeric array that was started with
+/// `MatWriter::start_array()`.
+#[derive(Debug)]
+pub struct ArrayWriter<'a, W: std::io::Write, T> {
+    mat_writer: &'a mut MatWriter<W>,
+    encoder: Option<libflate::zlib::Encoder<Vec<u8>>>,
+    // The number of compressed bytes passed on to a seekable writer
+    n_compressed: usize,
+    // The number of elements and the size of their real or imaginary parts
+    len: usize,
+    part_size: usize,
+    complex: bool,
+    n_written: usize,
+    marker: std::marker::PhantomData<T>,
+}
+
+impl<W: std::io::Write, T: Numeric> ArrayWriter<'_, W, T> {
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        use std::io::Write;
+
+        match &mut self.encoder {
+            Some(encoder) => {
+                encoder.write_all(bytes).map_err(Error::IOError)?;
+                if self.mat_writer.write_back.is_some() {
+                    let data = std::mem::take(encoder.as_inner_mut());
+                    self.emit_compressed(&data)?;
+                }
+                Ok(())
+            }
+            None => self
+                .mat_writer
+                .writer
+                .write_all(bytes)
+                .map_err(Error::IOError),
+        }
+    }
+
+    // Passes compressed data on to a seekable writer
+    fn emit_compressed(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.n_compressed += data.len();
+        if !write::fits_data_element(self.n_compressed) {
+            return Err(self.too_large());
+        }
+        self.mat_writer
+            .writer
+            .write_all(data)
+            .map_err(Error::IOError)
+    }
+
+    fn too_large(&self) -> Error {
+        Error::InvalidArray(format!(
+            "the compressed data of \"{}\" is too large to be written to a MAT-file",
+            self.mat_writer.unfinished.as_deref().unwrap_or_default()
+        ))
+    }
+
+    // Pads the real part and starts the imaginary part, if there is one
+    fn end_of_real_part(&mut self) -> Result<(), Error> {
+        if self.complex {
+            let padding = vec![0; write::padding_size(self.part_size)];
+            self.emit(&padding)?;
+            let tag =
+                write::numeric_tag(T::NUMERIC_TYPE, self.part_size, self.mat_writer.endianness);
+            self.emit(&tag)?;
+        }
+        Ok(())
+    }
+
+    fn n_values(&self) -> usize {
+        if self.complex {
+            2 * self.len
+        } else {
+            self.len
+        }
+    }
+
+    /// Writes the next values of the array.
+    ///
+    /// Returns an error if the values don't fit into the array.
+    pub fn write(&mut self, values: &[T]) -> Result<(), Error> {
+        if values.len() > self.n_values() - self.n_written {
+            return Err(Error::InvalidArray(format!(
+                "{} values written to an array of {} elements",
+                self.n_written + values.len(),
+                self.n_values()
+            )));
+        }
+        let mut values = values;
+        while !values.is_empty() {
+            // Values may continue from the real into the imaginary part
+            let part_end = if self.n_written < self.len {
+                self.len
+            } else {
+                2 * self.len
+            };
+            let n = values.len().min(part_end - self.n_written);
+            let bytes = write::to_bytes(&values[..n], self.mat_writer.endianness);
+            self.emit(&bytes)?;
+            self.n_written += n;
+            if self.n_written == self.len {
+                self.end_of_real_part()?;
+            }
+            values = &values[n..];
+        }
+        Ok(())
+    }
+
+    /// Completes the array after all of its values have been written.
+    pub fn finish(mut self) -> Result<(), Error> {
+        if self.n_written != self.n_values() {
+            return Err(Error::InvalidArray(format!(
+                "expected {} values, got {}",
+                self.n_values(),
+                self.n_written
+            )));
+        }
+        let padding = vec![0; write::padding_size(self.part_size)];
+        self.emit(&padding)?;
+        if let Some(encoder) = self.encoder.take() {
+            let data = encoder.finish().into_result().map_err(Error::IOError)?;
+            let endianness = self.mat_writer.endianness;
+            match self.mat_writer.write_back {
+                Some(write_back) => {
+                    self.emit_compressed(&data)?;
+                    let tag =
+                        write::tag(parse::DataType::Compressed, self.n_compressed, endianness);
+                    let distance = 8 + self.n_compressed as u64;
+                    write_back(&mut self.mat_writer.writer, distance, &tag)
+                        .map_err(Error::IOError)?;
+                }
+                None => {
+                    if !write::fits_data_element(data.len()) {
+                        return Err(self.too_large());
+                    }
+                    let tag = write::tag(parse::DataType::Compressed, data.len(), endianness);
+                    let writer = &mut self.mat_writer.writer;
+                    writer.write_all(&tag).map_err(Error::IOError)?;
+                    writer.write_all(&data).map_err(Error::IOError)?;
+                }
+            }
+        }
+        self.mat_writer.unfinished = None;
+        Ok(())
+    }
+}
+
+/// Builds a MatFile from Rust data, e.g. to write it to disk.
+///
+/// Variables are stored in the order in which they are added. The data of
+/// numeric arrays is expected in column-major order.
+/// ```rust
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mat_file = matfile::MatFileBuilder::new()
+///     .add_array(
+///         "x",
+///         vec![1, 3],
+///         matfile::NumericData::Int32 {
+///             real: vec![1, 2, 3],
+///             imag: None,
+///         },
+///     )
+///     .build()?;
+/// let file = std::fs::File::create(std::env::temp_dir().join("x.mat"))?;
+/// mat_file.write(file)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MatFileBuilder {
+    variables: Vec<Variable>,
+}
+
+impl MatFileBuilder {
+    /// Creates a builder for a MatFile without any variables.
+    pub fn new() -> Self {
+        MatFileBuilder::default()
+    }
+
+    /// Adds a numeric array of the given size.
+    pub fn add_array<S: Into<String>>(self, name: S, size: Vec<usize>, data: NumericData) -> Self {
+        self.add_variable(Variable::Numeric(Array {
+            name: name.into(),
+            size,
+            storage_type: data.numeric_type(),
+            data,
+            logical: false,
+            global: false,
+        }))
+    }
+
+    /// Adds a logical array of the given size.
+    pub fn add_logical_array<S: Into<String>>(
+        self,
+        name: S,
+        size: Vec<usize>,
+        data: Vec<bool>,
+    ) -> Self {
+        self.add_variable(Variable::Numeric(Array {
+            name: name.into(),
+            size,
+            data: NumericData::UInt8 {
+                real: data.into_iter().map(u8::from).collect(),
+                imag: None,
+            },
+            logical: true,
+            global: false,
+            storage_type: NumericType::UInt8,
+        }))
+    }
+
+    /// Adds a character array holding a single line of text.
+    pub fn add_char_array<S: Into<String>>(self, name: S, text: &str) -> Self {
+        let data: Vec<u16> = text.encode_utf16().collect();
+        self.add_variable(Variable::Char(CharArray {
+            name: name.into(),
+            size: vec![1, data.len()],
+            data,
+        }))
+    }
+
+    /// Adds a variable of any kind, e.g. one that was loaded from another
+    /// .mat file.
+    pub fn add_variable(mut self, variable: Variable) -> Self {
+        self.variables.push(variable);
+        self
+    }
+
+    /// Checks that all variables have valid and distinct names and that their
+    /// data matches their size, and creates the MatFile.
+    pub fn build(self) -> Result<MatFile, Error> {
+        for (i, variable) in self.variables.iter().enumerate() {
+            validate_variable(variable)?;
+            if self.variables[..i]
+                .iter()
+                .any(|other| other.name() == variable.name())
+            {
+                return Err(Error::InvalidArray(format!(
+                    "there is more than one variable named \"{}\"",
+                    variable.name()
+                )));
+            }
+        }
+        Ok(MatFile {
+            header: None,
+            variables: self.variables,
+            warnings: Vec::new(),
+        })
+    }
+}
+
+// TODO: improve tests.
+// The tests are not very comprehensive yet and they only test whether
 // the files can be loaded without error, but not whether the result
 // is actually correct.
 #[cfg(test)]
@@ -544,6 +3664,44 @@ mod tests {
         let _mat_file = MatFile::parse(data.as_ref()).unwrap();
     }
 
+    #[test]
+    fn int32_array() {
+        // A Level 5 MAT-file holding x = int32([-1 2]), stored as int32 values
+        let mut data = vec![b' '; 116];
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&[0x00, 0x01, b'I', b'M']);
+        let words: [u32; 16] = [
+            14,
+            56,
+            // Array flags with the class mxINT32_CLASS
+            6,
+            8,
+            12,
+            0,
+            // Dimensions
+            5,
+            8,
+            1,
+            2,
+            // Name
+            0x0001_0001,
+            u32::from(b'x'),
+            // Real part
+            5,
+            8,
+            -1i32 as u32,
+            2,
+        ];
+        for word in &words {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        let mat_file = MatFile::parse(data.as_slice()).unwrap();
+        match mat_file.find_by_name("x").map(|array| array.data()) {
+            Some(NumericData::Int32 { real, imag: None }) => assert_eq!(real, &vec![-1, 2]),
+            data => panic!("unexpected data {:?}", data),
+        }
+    }
+
     #[test]
     fn single_complex_array() {
         let data = include_bytes!("../tests/single_complex.mat");
@@ -567,4 +3725,1266 @@ mod tests {
         let data = include_bytes!("../tests/long_name.mat");
         let _mat_file = MatFile::parse(data.as_ref()).unwrap();
     }
+
+    #[test]
+    fn character_array() {
+        let data = include_bytes!("../tests/character.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        match mat_file.find_variable_by_name("chr") {
+            Some(Variable::Char(array)) => {
+                assert_eq!(array.size(), &vec![1, 13]);
+                assert_eq!(array.rows(), Some(vec!["Hello, world!".to_owned()]));
+            }
+            _ => panic!("Expected a character array"),
+        }
+    }
+
+    #[test]
+    fn cell_array() {
+        let data = include_bytes!("../tests/cell.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let array = match mat_file.find_variable_by_name("c") {
+            Some(Variable::Cell(array)) => array,
+            _ => panic!("Expected a cell array"),
+        };
+        assert_eq!(array.size(), &vec![1, 4]);
+        match &array.data()[0] {
+            Variable::Numeric(cell) => {
+                assert_eq!(cell.name(), "");
+                assert_eq!(cell.size(), &vec![1, 3]);
+            }
+            _ => panic!("Expected a numeric array in the first cell"),
+        }
+        match &array.data()[1] {
+            Variable::Char(cell) => assert_eq!(cell.rows(), Some(vec!["abc".to_owned()])),
+            _ => panic!("Expected a character array in the second cell"),
+        }
+        match &array.data()[2] {
+            Variable::Cell(cell) => assert_eq!(cell.data().len(), 1),
+            _ => panic!("Expected a cell array in the third cell"),
+        }
+        assert_eq!(array.data()[3].size(), &vec![0, 0]);
+    }
+
+    #[test]
+    fn struct_array() {
+        let data = include_bytes!("../tests/struct.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let array = match mat_file.find_variable_by_name("s") {
+            Some(Variable::Struct(array)) => array,
+            _ => panic!("Expected a structure array"),
+        };
+        assert_eq!(array.size(), &vec![1, 2]);
+        assert_eq!(
+            array.field_names(),
+            &vec!["a".to_owned(), "name".to_owned()]
+        );
+        assert_eq!(array.element(1).unwrap().len(), 2);
+        assert!(array.element(2).is_none());
+        match array.get(1, "name") {
+            Some(Variable::Char(value)) => {
+                assert_eq!(value.rows(), Some(vec!["yz".to_owned()]))
+            }
+            _ => panic!("Expected a character array"),
+        }
+        assert_eq!(array.get(1, "a").unwrap().size(), &vec![0, 0]);
+        assert!(array.get(0, "b").is_none());
+
+        let cfg = match mat_file.find_variable_by_name("cfg") {
+            Some(Variable::Struct(array)) => array,
+            _ => panic!("Expected a structure array"),
+        };
+        match cfg.get(0, "inner") {
+            Some(Variable::Struct(inner)) => match inner.get(0, "c") {
+                Some(Variable::Cell(_)) => {}
+                _ => panic!("Expected a cell array"),
+            },
+            _ => panic!("Expected a nested structure array"),
+        }
+    }
+
+    #[test]
+    fn object_array() {
+        let data = include_bytes!("../tests/object.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let array = match mat_file.find_variable_by_name("cal") {
+            Some(Variable::Object(array)) => array,
+            _ => panic!("Expected an object array"),
+        };
+        assert_eq!(array.class_name(), "calibration");
+        assert_eq!(array.size(), &vec![1, 1]);
+        assert_eq!(
+            array.properties().field_names(),
+            &vec!["offset".to_owned(), "unit".to_owned()]
+        );
+        match array.properties().get(0, "unit") {
+            Some(Variable::Char(unit)) => assert_eq!(unit.rows(), Some(vec!["mV".to_owned()])),
+            _ => panic!("Expected a character array"),
+        }
+    }
+
+    #[test]
+    fn level4() {
+        let data = include_bytes!("../tests/v4.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        assert_eq!(mat_file.variables().len(), 5);
+        match mat_file.find_by_name("A").map(|array| array.data()) {
+            Some(NumericData::Double { real, imag: None }) => {
+                assert_eq!(real, &vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0])
+            }
+            _ => panic!("Expected a real double array"),
+        }
+        match mat_file.find_by_name("z").map(|array| array.data()) {
+            Some(NumericData::Double {
+                real,
+                imag: Some(imag),
+            }) => {
+                assert_eq!(real, &vec![1.0, 3.0]);
+                assert_eq!(imag, &vec![2.0, -4.0]);
+            }
+            _ => panic!("Expected a complex double array"),
+        }
+        match mat_file.find_variable_by_name("t") {
+            Some(Variable::Char(array)) => {
+                assert_eq!(array.rows(), Some(vec!["abc".to_owned(), "def".to_owned()]))
+            }
+            _ => panic!("Expected a character array"),
+        }
+        match mat_file.find_variable_by_name("S") {
+            Some(Variable::Sparse(array)) => {
+                assert_eq!(array.size(), &vec![3, 3]);
+                assert_eq!(array.nnz(), 3);
+            }
+            _ => panic!("Expected a sparse array"),
+        }
+        match mat_file.find_by_name("u").map(|array| array.data()) {
+            Some(NumericData::UInt8 { real, imag: None }) => assert_eq!(real, &vec![7, 8, 9]),
+            _ => panic!("Expected a uint8 array"),
+        }
+    }
+
+    #[test]
+    fn level4_big_endian() {
+        let data = include_bytes!("../tests/v4_big_endian.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        match mat_file.find_by_name("B").map(|array| array.data()) {
+            Some(NumericData::Double { real, imag: None }) => assert_eq!(real, &vec![1.5, -2.0]),
+            _ => panic!("Expected a real double array"),
+        }
+    }
+
+    #[test]
+    fn sparse_array() {
+        let data = include_bytes!("../tests/sparse2.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        match &mat_file.variables()[0] {
+            Variable::Sparse(array) => {
+                assert_eq!(array.size(), &vec![8, 8]);
+                assert_eq!(array.row_index(), &vec![5, 7, 2, 0, 1, 5, 3, 6]);
+                assert_eq!(array.column_pointer(), &vec![0, 1, 2, 2, 3, 4, 6, 7, 8]);
+                assert_eq!(array.nnz(), 8);
+                assert!(!array.is_logical());
+                match array.data() {
+                    NumericData::Double { real, imag } => {
+                        assert_eq!(real, &vec![2.0, 7.0, 4.0, 9.0, 5.0, 6.0, 8.0, 6.0]);
+                        assert_eq!(imag, &Some(vec![4.0, 0.0, 3.0, 7.0, 0.0, 1.0, 0.0, 0.0]));
+                    }
+                    _ => panic!("Sparse array data should be double"),
+                }
+            }
+            _ => panic!("Expected a sparse array"),
+        }
+    }
+
+    // A Level 5 MAT-file holding a 2x2 sparse array x with the given row
+    // indices, column pointers and (nzmax) values
+    fn sparse_mat_file(row_index: &[i32], column_pointer: &[i32], values: &[f64]) -> Vec<u8> {
+        // Array flags with the class mxSPARSE_CLASS, dimensions and name
+        let mut words = vec![6, 8, 5, values.len() as u32, 5, 8, 2, 2, 0x0001_0001];
+        words.push(u32::from(b'x'));
+        for indices in [row_index, column_pointer].iter() {
+            words.extend_from_slice(&[5, 4 * indices.len() as u32]);
+            words.extend(indices.iter().map(|&i| i as u32));
+            if indices.len() % 2 == 1 {
+                words.push(0);
+            }
+        }
+        words.extend_from_slice(&[9, 8 * values.len() as u32]);
+        let mut element: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        element.extend(values.iter().flat_map(|value| value.to_le_bytes()));
+
+        let mut data = vec![b' '; 116];
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&[0x00, 0x01, b'I', b'M']);
+        data.extend_from_slice(&14u32.to_le_bytes());
+        data.extend_from_slice(&(element.len() as u32).to_le_bytes());
+        data.extend_from_slice(&element);
+        data
+    }
+
+    #[test]
+    fn sparse_array_nzmax() {
+        // Room for three entries, only two of which are in use
+        let data = sparse_mat_file(&[0, 1, 0], &[0, 1, 2], &[1.0, 2.0, 0.0]);
+        let mat_file = MatFile::parse(data.as_slice()).unwrap();
+        match mat_file.find_variable_by_name("x") {
+            Some(Variable::Sparse(array)) => {
+                assert_eq!(array.nzmax(), 3);
+                assert_eq!(array.nnz(), 2);
+                assert_eq!(array.row_index(), &vec![0, 1]);
+                assert_eq!(
+                    array.data(),
+                    &NumericData::Double {
+                        real: vec![1.0, 2.0],
+                        imag: None
+                    }
+                );
+            }
+            variable => panic!("expected a sparse array, got {:?}", variable),
+        }
+        assert_eq!(round_trip(&mat_file).variables(), mat_file.variables());
+    }
+
+    #[test]
+    fn malformed_sparse_indices() {
+        let cases: [(&[i32], &[i32], &[f64]); 6] = [
+            // Negative row index
+            (&[-1], &[0, 1, 1], &[1.0]),
+            // Row index out of bounds
+            (&[2], &[0, 1, 1], &[1.0]),
+            // Too few column pointers
+            (&[0], &[0, 1], &[1.0]),
+            // Column pointers not starting at 0
+            (&[0], &[1, 1, 1], &[1.0]),
+            // Decreasing column pointers
+            (&[0, 1], &[0, 2, 1], &[1.0, 2.0]),
+            // More entries than row indices
+            (&[0], &[0, 1, 2], &[1.0]),
+        ];
+        for (row_index, column_pointer, values) in cases.iter() {
+            let data = sparse_mat_file(row_index, column_pointer, values);
+            match MatFile::parse(data.as_slice()) {
+                Err(Error::ParseError(err)) => {
+                    assert_eq!(err.kind(), ParseErrorKind::Malformed);
+                    assert_eq!(err.variable_name(), Some("x"));
+                }
+                result => panic!("unexpected result {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn empty_sparse_array() {
+        // A Level 4 MAT-file holding x = sparse(2, 3), stored as its size
+        // only
+        let mut data = Vec::new();
+        for &field in [2i32, 1, 3, 0, 2].iter() {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        data.extend_from_slice(b"x\0");
+        for &value in [2.0f64, 3.0, 0.0].iter() {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        let mat_file = MatFile::parse(data.as_slice()).unwrap();
+        match mat_file.find_variable_by_name("x") {
+            Some(Variable::Sparse(array)) => {
+                assert_eq!(array.size(), &vec![2, 3]);
+                assert_eq!(array.nnz(), 0);
+                assert_eq!(array.column_pointer(), &vec![0, 0, 0, 0]);
+            }
+            variable => panic!("expected a sparse array, got {:?}", variable),
+        }
+        assert_eq!(round_trip(&mat_file).variables(), mat_file.variables());
+    }
+
+    #[test]
+    fn hdf5_file() {
+        // Put together by tests/v73.py following the HDF5 specification, in
+        // the layout Matlab uses. It lists the Matlab code of the variables.
+        let data = include_bytes!("../tests/v73.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let header = mat_file.header().unwrap();
+        assert_eq!(header.version(), 0x0200);
+        assert_eq!(header.platform(), Some("GLNXA64"));
+        assert_eq!(header.subsystem_offset(), None);
+        let names: Vec<&str> = mat_file.variables().iter().map(|v| v.name()).collect();
+        assert_eq!(
+            names,
+            vec!["a", "b", "big", "c", "e", "g", "i16", "s", "sa", "sp", "st", "z"]
+        );
+        match mat_file.warnings().as_slice() {
+            [ParseWarning::Unsupported { variable_name, .. }] => {
+                assert_eq!(variable_name.as_deref(), Some("fh"))
+            }
+            warnings => panic!("unexpected warnings {:?}", warnings),
+        }
+
+        let array = |name| match mat_file.find_variable_by_name(name) {
+            Some(Variable::Numeric(array)) => array,
+            variable => panic!("expected a numeric array, got {:?}", variable),
+        };
+        assert_eq!(array("a").size(), &vec![2, 3]);
+        match array("a").data() {
+            NumericData::Double { real, imag: None } => {
+                assert_eq!(real, &vec![1.0, 4.0, 2.0, 5.0, 3.0, 6.0])
+            }
+            data => panic!("unexpected data {:?}", data),
+        }
+        // Stored in chunks that don't evenly divide it
+        assert_eq!(array("big").size(), &vec![40, 25]);
+        match array("big").data() {
+            NumericData::Double { real, imag: None } => {
+                assert!(real.iter().copied().eq((1..=1000).map(f64::from)))
+            }
+            data => panic!("unexpected data {:?}", data),
+        }
+        match array("z").data() {
+            NumericData::Single { real, imag } => {
+                assert_eq!(real, &vec![1.0, 3.0]);
+                assert_eq!(imag, &Some(vec![2.0, -4.0]));
+            }
+            data => panic!("unexpected data {:?}", data),
+        }
+        assert_eq!(array("i16").class(), Class::Int16);
+        assert_eq!(array("b").logical_data(), Some(vec![true, false, true]));
+        assert_eq!(array("e").size(), &vec![0, 3]);
+        assert!(array("g").is_global());
+
+        match mat_file.find_variable_by_name("s") {
+            Some(Variable::Char(array)) => assert_eq!(array.rows(), Some(vec!["hello".to_owned()])),
+            variable => panic!("expected a character array, got {:?}", variable),
+        }
+        match mat_file.find_variable_by_name("sp") {
+            Some(Variable::Sparse(array)) => {
+                assert_eq!(array.size(), &vec![3, 2]);
+                assert_eq!(array.row_index(), &vec![0, 2, 1]);
+                assert_eq!(array.column_pointer(), &vec![0, 2, 3]);
+            }
+            variable => panic!("expected a sparse array, got {:?}", variable),
+        }
+        match mat_file.find_variable_by_name("c") {
+            Some(Variable::Cell(array)) => {
+                assert_eq!(array.size(), &vec![2, 2]);
+                let classes: Vec<Class> = array.data().iter().map(|cell| cell.class()).collect();
+                assert_eq!(
+                    classes,
+                    vec![Class::Double, Class::Int8, Class::Char, Class::Cell]
+                );
+                assert_eq!(array.data()[3].size(), &vec![0, 0]);
+            }
+            variable => panic!("expected a cell array, got {:?}", variable),
+        }
+        match mat_file.find_variable_by_name("st") {
+            Some(Variable::Struct(array)) => {
+                assert_eq!(array.size(), &vec![1, 1]);
+                assert_eq!(
+                    array.field_names(),
+                    &vec!["x".to_owned(), "name".to_owned()]
+                );
+                assert_eq!(array.get(0, "name").unwrap().class(), Class::Char);
+            }
+            variable => panic!("expected a structure array, got {:?}", variable),
+        }
+        match mat_file.find_variable_by_name("sa") {
+            Some(Variable::Struct(array)) => {
+                assert_eq!(array.size(), &vec![1, 2]);
+                match array.get(1, "v") {
+                    Some(Variable::Numeric(value)) => {
+                        assert!(
+                            matches!(value.data(), NumericData::Double { real, .. } if real == &vec![2.0])
+                        )
+                    }
+                    value => panic!("unexpected value {:?}", value),
+                }
+            }
+            variable => panic!("expected a structure array, got {:?}", variable),
+        }
+
+        // Loads the same when written as a Level 5 MAT-file
+        let round_tripped = round_trip(&mat_file);
+        assert_eq!(round_tripped.variables(), mat_file.variables());
+
+        assert!(matches!(
+            MatFileReader::new(std::io::Cursor::new(data.as_ref())),
+            Err(Error::Hdf5NotSupported)
+        ));
+
+        // Damage the B-tree of the chunks of "big"
+        let mut damaged = data.to_vec();
+        let position = damaged
+            .windows(5)
+            .position(|window| window == b"TREE\x01")
+            .unwrap();
+        damaged[position] = b'X';
+        match MatFile::parse(damaged.as_slice()) {
+            Err(Error::ParseError(err)) => {
+                assert_eq!(err.kind(), ParseErrorKind::Malformed);
+                assert_eq!(err.offset(), Some(position as u64));
+                assert_eq!(err.variable_name(), Some("big"));
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        let options = ParseOptions::new().on_error(OnError::Skip);
+        let loaded = MatFile::parse_with_options(damaged.as_slice(), &options).unwrap();
+        assert_eq!(loaded.variables().len(), 11);
+        assert!(loaded.find_variable_by_name("big").is_none());
+        assert_eq!(loaded.warnings().len(), 2);
+    }
+
+    fn round_trip(mat_file: &MatFile) -> MatFile {
+        let mut buf = Vec::new();
+        mat_file.write(&mut buf).unwrap();
+        MatFile::parse(buf.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn write_compressed() {
+        let data = include_bytes!("../tests/struct.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let mut uncompressed = Vec::new();
+        mat_file.write(&mut uncompressed).unwrap();
+        for &compression in [Compression::Fast, Compression::Default].iter() {
+            let mut buf = Vec::new();
+            let options = WriteOptions::new().compression(compression);
+            mat_file.write_with_options(&mut buf, &options).unwrap();
+            assert_eq!(&buf[128..132], &[15, 0, 0, 0]);
+            assert!(buf.len() < uncompressed.len());
+            let loaded = MatFile::parse(buf.as_slice()).unwrap();
+            assert_eq!(loaded.variables(), mat_file.variables());
+        }
+    }
+
+    #[test]
+    fn write_round_trip() {
+        let files: [&[u8]; 15] = [
+            include_bytes!("../tests/double.mat"),
+            include_bytes!("../tests/double_as_int16.mat"),
+            include_bytes!("../tests/double_as_uint8.mat"),
+            include_bytes!("../tests/single_complex.mat"),
+            include_bytes!("../tests/two_arrays.mat"),
+            include_bytes!("../tests/multidimensional.mat"),
+            include_bytes!("../tests/long_name.mat"),
+            include_bytes!("../tests/character.mat"),
+            include_bytes!("../tests/sparse2.mat"),
+            include_bytes!("../tests/cell.mat"),
+            include_bytes!("../tests/struct.mat"),
+            include_bytes!("../tests/object.mat"),
+            include_bytes!("../tests/logical.mat"),
+            include_bytes!("../tests/metadata.mat"),
+            include_bytes!("../tests/v4.mat"),
+        ];
+        for data in files.iter() {
+            let mat_file = MatFile::parse(*data).unwrap();
+            assert_eq!(round_trip(&mat_file).variables(), mat_file.variables());
+        }
+    }
+
+    #[test]
+    fn write_big_endian() {
+        // Numbers of all sizes, in nested variables too
+        let files: [&[u8]; 5] = [
+            include_bytes!("../tests/single_complex.mat"),
+            include_bytes!("../tests/character.mat"),
+            include_bytes!("../tests/sparse2.mat"),
+            include_bytes!("../tests/cell.mat"),
+            include_bytes!("../tests/metadata.mat"),
+        ];
+        for data in files.iter() {
+            let mat_file = MatFile::parse(*data).unwrap();
+            for &compression in [Compression::None, Compression::Default].iter() {
+                let options = WriteOptions::new()
+                    .byte_order(ByteOrder::BigEndian)
+                    .compression(compression);
+                let mut written = Vec::new();
+                mat_file.write_with_options(&mut written, &options).unwrap();
+                let loaded = MatFile::parse(written.as_slice()).unwrap();
+                assert_eq!(loaded.header().unwrap().byte_order(), ByteOrder::BigEndian);
+                assert_eq!(loaded.variables(), mat_file.variables());
+                let mut reader = MatFileReader::new(std::io::Cursor::new(&written)).unwrap();
+                for variable in mat_file.variables() {
+                    assert_eq!(
+                        reader.read_variable(variable.name()).unwrap().as_ref(),
+                        Some(variable)
+                    );
+                }
+            }
+        }
+
+        // Tags, flags and numbers are all swapped
+        let mat_file = MatFileBuilder::new()
+            .add_array("x", vec![1, 1], vec![0x0102u16].into())
+            .build()
+            .unwrap();
+        let options = WriteOptions::new().byte_order(ByteOrder::BigEndian);
+        let mut written = Vec::new();
+        mat_file.write_with_options(&mut written, &options).unwrap();
+        assert_eq!(&written[124..128], b"\x01\x00MI");
+        assert_eq!(&written[128..132], &[0, 0, 0, 14]);
+        assert_eq!(&written[136..148], &[0, 0, 0, 6, 0, 0, 0, 8, 0, 0, 0, 11]);
+        assert_eq!(&written[176..184], &[0, 2, 0, 4, 1, 2, 0, 0]);
+
+        let mut writer = MatWriter::new_with_options(Vec::new(), &options).unwrap();
+        let mut x = writer
+            .start_array::<u16, _>("x", vec![1, 1], false)
+            .unwrap();
+        x.write(&[0x0102]).unwrap();
+        x.finish().unwrap();
+        assert_eq!(&writer.into_inner()[124..], &written[124..]);
+
+        // Appended variables keep the byte order of the file
+        let path =
+            std::env::temp_dir().join(format!("matfile_big_endian_{}.mat", std::process::id()));
+        std::fs::write(&path, &written).unwrap();
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut appender = MatFileAppender::open(file).unwrap();
+        let t = Array::new("t", vec![1, 2], vec![1.0, 2.0].into()).unwrap();
+        appender.append(&Variable::Numeric(t.clone())).unwrap();
+        let loaded = MatFile::parse(std::fs::File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.header().unwrap().byte_order(), ByteOrder::BigEndian);
+        assert_eq!(loaded.find_by_name("t").unwrap(), &t);
+
+        // Unsupported variables can't be converted
+        let mut data = Vec::new();
+        mat_file.write(&mut data).unwrap();
+        data[144] = 16;
+        let loaded = MatFile::parse(data.as_slice()).unwrap();
+        assert!(matches!(
+            loaded.write_with_options(Vec::new(), &options),
+            Err(Error::InvalidArray(_))
+        ));
+    }
+
+    #[test]
+    fn write_numeric_arrays() {
+        let mat_file = MatFileBuilder::new()
+            .add_array(
+                "i8",
+                vec![1, 1],
+                NumericData::Int8 {
+                    real: vec![-1],
+                    imag: None,
+                },
+            )
+            .add_array(
+                "i32",
+                vec![1, 2],
+                NumericData::Int32 {
+                    real: vec![-7, 8],
+                    imag: Some(vec![1, -2]),
+                },
+            )
+            .add_array(
+                "u64",
+                vec![1, 1, 1],
+                NumericData::UInt64 {
+                    real: vec![u64::MAX],
+                    imag: None,
+                },
+            )
+            .add_array(
+                "empty",
+                vec![0, 0],
+                NumericData::Double {
+                    real: vec![],
+                    imag: None,
+                },
+            )
+            .add_char_array("text", "x²")
+            .build()
+            .unwrap();
+        let loaded = round_trip(&mat_file);
+        assert_eq!(loaded.variables(), mat_file.variables());
+    }
+
+    #[test]
+    fn logical_array() {
+        let data = include_bytes!("../tests/logical.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let mask = mat_file.find_by_name("mask").unwrap();
+        assert!(mask.is_logical());
+        assert_eq!(mask.logical_data(), Some(vec![true, false, false, true]));
+        let img = mat_file.find_by_name("img").unwrap();
+        assert!(!img.is_logical());
+        assert_eq!(img.logical_data(), None);
+        match mat_file.find_variable_by_name("sl") {
+            Some(Variable::Sparse(array)) => {
+                assert!(array.is_logical());
+                assert_eq!(array.logical_data(), Some(vec![true, true, true]));
+            }
+            _ => panic!("Expected a sparse array"),
+        }
+        assert!(Array::new_logical("m", vec![2, 1], vec![true]).is_err());
+    }
+
+    #[test]
+    fn header() {
+        let data = include_bytes!("../tests/struct.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let header = mat_file.header().unwrap();
+        assert_eq!(
+            header.text(),
+            "MATLAB 5.0 MAT-file, Platform: GLNXA64, Created on: Sat Oct 17 12:00:00 2026"
+        );
+        assert_eq!(header.platform(), Some("GLNXA64"));
+        assert_eq!(
+            header.created(),
+            Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_792_238_400))
+        );
+        assert_eq!(header.version(), 0x0100);
+        assert_eq!(header.byte_order(), ByteOrder::LittleEndian);
+        assert_eq!(header.subsystem_offset(), None);
+
+        let written = round_trip(&mat_file);
+        assert!(written.header().unwrap().created().is_some());
+        let platform = written.header().unwrap().platform().unwrap();
+        assert!(["GLNXA64", "PCWIN64", "MACI64", "MACA64"].contains(&platform));
+
+        let data = include_bytes!("../tests/v4.mat");
+        assert!(MatFile::parse(data.as_ref()).unwrap().header().is_none());
+    }
+
+    #[test]
+    fn reader() {
+        let data = include_bytes!("../tests/struct.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let mut compressed = Vec::new();
+        let options = WriteOptions::new().compression(Compression::Default);
+        mat_file
+            .write_with_options(&mut compressed, &options)
+            .unwrap();
+        for (data, is_compressed) in [(data.to_vec(), false), (compressed, true)].iter() {
+            let mut reader = MatFileReader::new(std::io::Cursor::new(data)).unwrap();
+            let names: Vec<_> = reader.directory().iter().map(|e| e.name()).collect();
+            assert_eq!(names, vec!["s", "cfg"]);
+            let entry = &reader.directory()[0];
+            assert_eq!(entry.class(), Class::Struct);
+            assert_eq!(entry.size(), &vec![1, 2]);
+            assert_eq!(entry.offset(), 128);
+            assert_eq!(entry.is_compressed(), *is_compressed);
+            assert!(reader.header().is_some());
+            let cfg = reader.read_variable("cfg").unwrap().unwrap();
+            assert_eq!(&cfg, mat_file.find_variable_by_name("cfg").unwrap());
+            assert!(reader.read_variable("x").unwrap().is_none());
+        }
+
+        let data = include_bytes!("../tests/v4.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let mut reader = MatFileReader::new(std::io::Cursor::new(data.as_ref())).unwrap();
+        let entries: Vec<_> = reader
+            .directory()
+            .iter()
+            .map(|e| (e.name().to_owned(), e.class(), e.size().clone()))
+            .collect();
+        let expected: Vec<_> = mat_file
+            .variables()
+            .iter()
+            .map(|v| (v.name().to_owned(), v.class(), v.size().clone()))
+            .collect();
+        assert_eq!(entries, expected);
+        let s = reader.read_variable("S").unwrap().unwrap();
+        assert_eq!(&s, mat_file.find_variable_by_name("S").unwrap());
+    }
+
+    #[test]
+    fn metadata() {
+        let data = include_bytes!("../tests/metadata.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let g = mat_file.find_by_name("g").unwrap();
+        assert!(g.is_global());
+        assert!(!g.is_complex());
+        assert_eq!(g.class(), Class::Double);
+        assert_eq!(g.storage_type(), NumericType::UInt8);
+        let z = mat_file.find_by_name("z").unwrap();
+        assert!(!z.is_global());
+        assert!(z.is_complex());
+        assert_eq!(z.class(), Class::Int16);
+        assert_eq!(z.storage_type(), NumericType::Int16);
+        let d = mat_file.find_by_name("d").unwrap();
+        assert_eq!(d.class().name(), "double");
+        assert_eq!(d.storage_type(), NumericType::Int16);
+
+        let logical = include_bytes!("../tests/logical.mat");
+        let mat_file = MatFile::parse(logical.as_ref()).unwrap();
+        let classes: Vec<_> = mat_file.variables().iter().map(Variable::class).collect();
+        assert_eq!(classes, vec![Class::Logical, Class::UInt8, Class::Logical]);
+    }
+
+    #[test]
+    fn new_array() {
+        let array = Array::new("x_1", vec![1, 2, 1], vec![1u8, 2].into()).unwrap();
+        assert_eq!(array.ndims(), 3);
+        assert!(Array::new("", vec![0, 0], Vec::<f64>::new().into()).is_ok());
+        assert!(Array::new("A", vec![3], vec![1.0, 2.0, 3.0].into()).is_err());
+        assert!(Array::new("A", vec![1, 2], vec![1.0].into()).is_err());
+        assert!(Array::new("A", vec![1, 1], (vec![1.0], vec![]).into()).is_err());
+        for name in ["_a", "a b", "a-b", "ä", &"a".repeat(64)].iter() {
+            assert!(Array::new(*name, vec![1, 1], vec![1.0].into()).is_err());
+        }
+        assert!(Array::new("a".repeat(63), vec![1, 1], vec![1.0].into()).is_ok());
+    }
+
+    #[test]
+    fn build_invalid() {
+        let result = MatFileBuilder::new()
+            .add_array(
+                "A",
+                vec![2, 2],
+                NumericData::Double {
+                    real: vec![1.0],
+                    imag: None,
+                },
+            )
+            .build();
+        assert!(matches!(result, Err(Error::InvalidArray(_))));
+        let result = MatFileBuilder::new()
+            .add_array(
+                "A",
+                vec![1, 1],
+                NumericData::Double {
+                    real: vec![1.0],
+                    imag: Some(vec![]),
+                },
+            )
+            .build();
+        assert!(matches!(result, Err(Error::InvalidArray(_))));
+        let result = MatFileBuilder::new()
+            .add_char_array("A", "a")
+            .add_char_array("A", "b")
+            .build();
+        assert!(matches!(result, Err(Error::InvalidArray(_))));
+        // Dimensions are stored as i32
+        let result = MatFileBuilder::new()
+            .add_array("A", vec![0, 1 << 31], Vec::<f64>::new().into())
+            .build();
+        assert!(matches!(result, Err(Error::InvalidArray(_))));
+        let result = MatFileBuilder::new()
+            .add_array("A", vec![0, i32::MAX as usize], Vec::<f64>::new().into())
+            .build();
+        assert!(result.is_ok());
+        let result = MatFileBuilder::new()
+            .add_array("A", vec![usize::MAX, usize::MAX], Vec::<f64>::new().into())
+            .build();
+        assert!(matches!(result, Err(Error::InvalidArray(_))));
+    }
+
+    #[test]
+    fn whos() {
+        let data = include_bytes!("../tests/metadata.mat");
+        let infos = MatFile::whos(std::io::Cursor::new(data.as_ref())).unwrap();
+        let names: Vec<_> = infos.iter().map(|info| info.name()).collect();
+        assert_eq!(names, vec!["g", "z", "d"]);
+        assert!(infos[0].is_global());
+        assert_eq!(infos[0].bytes(), Some(8));
+        assert!(infos[1].is_complex());
+        assert_eq!(infos[1].class(), Class::Int16);
+        assert_eq!(infos[1].bytes(), Some(8));
+        assert_eq!(infos[2].size(), &vec![1, 3]);
+
+        let data = include_bytes!("../tests/sparse2.mat");
+        let infos = MatFile::whos(std::io::Cursor::new(data.as_ref())).unwrap();
+        assert!(infos[0].is_sparse() && infos[0].is_complex());
+        assert_eq!(infos[0].bytes(), Some(8 * 24 + 9 * 8));
+
+        // Compressed variables give the same information
+        let data = include_bytes!("../tests/struct.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let mut compressed = Vec::new();
+        let options = WriteOptions::new().compression(Compression::Default);
+        mat_file
+            .write_with_options(&mut compressed, &options)
+            .unwrap();
+        let infos = MatFile::whos(std::io::Cursor::new(data.as_ref())).unwrap();
+        assert_eq!(infos[0].class(), Class::Struct);
+        assert_eq!(infos[0].bytes(), None);
+        assert_eq!(
+            MatFile::whos(std::io::Cursor::new(compressed)).unwrap(),
+            infos
+        );
+
+        let data = include_bytes!("../tests/v4.mat");
+        let infos = MatFile::whos(std::io::Cursor::new(data.as_ref())).unwrap();
+        assert!(infos[1].is_complex());
+        assert_eq!(infos[1].bytes(), Some(32));
+        assert!(infos[3].is_sparse());
+        assert_eq!(infos[3].bytes(), Some(3 * 16 + 4 * 8));
+
+        // Sizes read from damaged files can be anything
+        let info = VariableInfo {
+            size: vec![usize::MAX, 2],
+            ..infos[0].clone()
+        };
+        assert_eq!(info.bytes(), None);
+    }
+
+    #[test]
+    fn view() {
+        let mat_file = MatFileBuilder::new()
+            .add_array("A", vec![2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0].into())
+            .add_array("z", vec![1, 2], (vec![1i32, 2], vec![3, 4]).into())
+            .add_logical_array("mask", vec![1, 2], vec![true, false])
+            .add_char_array("label", "abc")
+            .build()
+            .unwrap();
+        let mut data = Vec::new();
+        mat_file.write(&mut data).unwrap();
+
+        // Place the file at an 8 byte aligned address and right after it
+        let mut buf = vec![0u8; data.len() + 16];
+        let start = buf.as_ptr().align_offset(8);
+        for &offset in [start, start + 1].iter() {
+            buf[offset..offset + data.len()].copy_from_slice(&data);
+            let view = MatFileView::new(&buf[offset..offset + data.len()]).unwrap();
+            for name in ["A", "z", "mask"].iter() {
+                let array = view.array(name).unwrap().unwrap();
+                // Bytes never need to be aligned
+                let aligned = offset == start || *name == "mask";
+                assert_eq!(
+                    array.data().is_borrowed(),
+                    aligned && cfg!(target_endian = "little")
+                );
+                assert_eq!(&array.into_owned(), mat_file.find_by_name(name).unwrap());
+            }
+            assert_eq!(view.array("mask").unwrap().unwrap().class(), Class::Logical);
+            assert!(view.array("label").unwrap().is_none());
+            assert!(view.array("x").unwrap().is_none());
+        }
+
+        // Numbers stored in another type than their class are copied
+        let data = include_bytes!("../tests/double_as_int16.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let view = MatFileView::new(data.as_ref()).unwrap();
+        let array = &mat_file.arrays()[0];
+        let array_view = view.array(array.name()).unwrap().unwrap();
+        assert!(!array_view.data().is_borrowed());
+        assert_eq!(array_view.storage_type(), NumericType::Int16);
+        assert_eq!(&array_view.into_owned(), *array);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn view_mmap() {
+        let file = std::fs::File::open("tests/double.mat").unwrap();
+        let view = unsafe { MatFileView::map(&file) }.unwrap();
+        let data = include_bytes!("../tests/double.mat");
+        let mat_file = MatFile::parse(data.as_ref()).unwrap();
+        let array = &mat_file.arrays()[0];
+        let array_view = view.array(array.name()).unwrap().unwrap();
+        assert_eq!(&array_view.into_owned(), *array);
+    }
+
+    #[test]
+    fn append() {
+        let path = std::env::temp_dir().join(format!("matfile_append_{}.mat", std::process::id()));
+        let open = || {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .unwrap()
+        };
+        let load = || MatFile::parse(std::fs::File::open(&path).unwrap()).unwrap();
+        let names = |mat_file: &MatFile| -> Vec<String> {
+            mat_file
+                .variables()
+                .iter()
+                .map(|v| v.name().to_owned())
+                .collect()
+        };
+        std::fs::copy("tests/two_arrays.mat", &path).unwrap();
+        let original = load();
+        let (a, b) = (
+            original.variables()[0].name(),
+            original.variables()[1].name(),
+        );
+
+        let mut appender = MatFileAppender::open(open()).unwrap();
+        let t = Array::new("t", vec![1, 2], vec![1.0, 2.0].into()).unwrap();
+        appender.append(&Variable::Numeric(t)).unwrap();
+        assert_eq!(appender.directory().len(), 3);
+        let loaded = load();
+        assert_eq!(names(&loaded), vec![a, b, "t"]);
+        assert_eq!(&loaded.variables()[..2], original.variables());
+
+        // A replaced variable is appended anew
+        let options = WriteOptions::new().compression(Compression::Default);
+        let mut appender = MatFileAppender::open_with_options(open(), &options).unwrap();
+        let replacement = Array::new(a, vec![1, 1], vec![42u8].into()).unwrap();
+        appender
+            .append(&Variable::Numeric(replacement.clone()))
+            .unwrap();
+        let label = MatFileBuilder::new()
+            .add_char_array("label", "abc")
+            .build()
+            .unwrap();
+        appender.append(&label.variables()[0]).unwrap();
+        let loaded = load();
+        assert_eq!(names(&loaded), vec![b, "t", a, "label"]);
+        assert_eq!(loaded.find_by_name(a).unwrap(), &replacement);
+        let invalid = Array::new("", vec![1, 1], vec![1.0].into()).unwrap();
+        assert!(appender.append(&Variable::Numeric(invalid)).is_err());
+
+        // The directory is kept up to date without scanning the file again
+        let scanned = || {
+            let reader = MatFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+            reader.directory().clone()
+        };
+        assert_eq!(appender.directory(), &scanned());
+
+        // A variable of the same size is overwritten in place
+        let mut appender = MatFileAppender::open(open()).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        let t = Array::new("t", vec![2, 1], vec![3.0, 4.0].into()).unwrap();
+        appender.append(&Variable::Numeric(t.clone())).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(appender.directory(), &scanned());
+        let loaded = load();
+        assert_eq!(names(&loaded), vec![b, "t", a, "label"]);
+        assert_eq!(loaded.find_by_name("t").unwrap(), &t);
+
+        // The other variables stay where they are, the replaced ones are
+        // left as fillers, compressed (and so unpadded) ones included
+        let large = Array::new("large", vec![1, 20000], vec![0.5; 20000].into()).unwrap();
+        appender.append(&Variable::Numeric(large.clone())).unwrap();
+        let directory = appender.directory().clone();
+        let old = directory.iter().find(|entry| entry.name() == a).unwrap();
+        assert!(old.is_compressed() && old.byte_size() % 8 != 0);
+        let t = Array::new("t", vec![1, 1], vec![5.0].into()).unwrap();
+        appender.append(&Variable::Numeric(t.clone())).unwrap();
+        let mut appender = MatFileAppender::open_with_options(open(), &options).unwrap();
+        let replacement = Array::new(a, vec![1, 2], vec![7u8, 8].into()).unwrap();
+        appender
+            .append(&Variable::Numeric(replacement.clone()))
+            .unwrap();
+        assert_eq!(appender.directory(), &scanned());
+        for entry in &directory {
+            if entry.name() != "t" && entry.name() != a {
+                assert!(appender.directory().contains(entry));
+            }
+        }
+        let loaded = load();
+        assert_eq!(names(&loaded), vec![b, "label", "large", "t", a]);
+        assert!(loaded.warnings().is_empty());
+        assert_eq!(loaded.find_by_name("t").unwrap(), &t);
+        assert_eq!(loaded.find_by_name(a).unwrap(), &replacement);
+        assert_eq!(loaded.find_by_name("large").unwrap(), &large);
+        let infos = MatFile::whos(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(infos.len(), 5);
+        std::fs::remove_file(&path).unwrap();
+
+        std::fs::copy("tests/v4.mat", &path).unwrap();
+        assert!(matches!(
+            MatFileAppender::open(open()),
+            Err(Error::Level4NotSupported)
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn mat_writer() {
+        // Written in uneven chunks that cross from the real to the
+        // imaginary part, with a small data element in between
+        let real: Vec<i16> = (0..7).collect();
+        let imag: Vec<i16> = (0..7).map(|x| -x).collect();
+        let mat_file = MatFileBuilder::new()
+            .add_char_array("s", "text")
+            .add_array("z", vec![1, 7], (real.clone(), imag.clone()).into())
+            .add_array("x", vec![1, 2], vec![1u8, 2].into())
+            .add_array("e", vec![0, 3], Vec::<f64>::new().into())
+            .build()
+            .unwrap();
+        let values: Vec<i16> = real.into_iter().chain(imag).collect();
+        let settings = [
+            (Compression::None, false),
+            (Compression::Default, false),
+            (Compression::Default, true),
+        ];
+        for &(compression, seekable) in settings.iter() {
+            let options = WriteOptions::new().compression(compression);
+            let mut writer = if seekable {
+                MatWriter::new_seekable(std::io::Cursor::new(Vec::new()), &options).unwrap()
+            } else {
+                MatWriter::new_with_options(std::io::Cursor::new(Vec::new()), &options).unwrap()
+            };
+            writer.write_variable(&mat_file.variables()[0]).unwrap();
+            let mut z = writer.start_array::<i16, _>("z", vec![1, 7], true).unwrap();
+            for chunk in values.chunks(5) {
+                z.write(chunk).unwrap();
+            }
+            z.finish().unwrap();
+            let mut x = writer.start_array::<u8, _>("x", vec![1, 2], false).unwrap();
+            x.write(&[1, 2]).unwrap();
+            x.finish().unwrap();
+            let e = writer
+                .start_array::<f64, _>("e", vec![0, 3], false)
+                .unwrap();
+            e.finish().unwrap();
+            let written = writer.into_inner().into_inner();
+
+            let mut expected = Vec::new();
+            mat_file
+                .write_with_options(&mut expected, &options)
+                .unwrap();
+            assert_eq!(&written[128..], &expected[128..]);
+        }
+    }
+
+    #[test]
+    fn mat_writer_invalid() {
+        let mut writer = MatWriter::new(Vec::new()).unwrap();
+        assert!(writer
+            .start_array::<f64, _>("1st", vec![1, 1], false)
+            .is_err());
+        assert!(writer.start_array::<f64, _>("x", vec![3], false).is_err());
+        let mut x = writer
+            .start_array::<f64, _>("x", vec![1, 2], false)
+            .unwrap();
+        assert!(x.write(&[1.0, 2.0, 3.0]).is_err());
+        x.write(&[1.0]).unwrap();
+        assert!(x.finish().is_err());
+        // The unfinished array blocks further variables
+        assert!(writer
+            .start_array::<f64, _>("y", vec![1, 1], false)
+            .is_err());
+    }
+
+    #[test]
+    fn parse_errors() {
+        let mat_file = MatFileBuilder::new()
+            .add_array("a", vec![1, 1], vec![1.0].into())
+            .add_array("b", vec![1, 2], vec![1i32, 2].into())
+            .build()
+            .unwrap();
+        let mut data = Vec::new();
+        mat_file.write(&mut data).unwrap();
+        // "b" starts at byte 192
+        let b = 192;
+        let parse_error = |data: &[u8]| match MatFile::parse(data) {
+            Err(Error::ParseError(err)) => err,
+            result => panic!("unexpected result {:?}", result),
+        };
+
+        let mut corrupt = data.clone();
+        corrupt[124] = 3;
+        let err = parse_error(&corrupt);
+        assert_eq!(err.kind(), ParseErrorKind::UnsupportedVersion);
+        assert_eq!(err.offset(), Some(128));
+
+        let mut corrupt = data.clone();
+        corrupt[172] = 0xff;
+        let err = parse_error(&corrupt);
+        assert_eq!(err.kind(), ParseErrorKind::InvalidUtf8);
+        assert_eq!(err.offset(), Some(172));
+
+        let mut corrupt = data.clone();
+        corrupt[b + 16] = 99;
+        let err = parse_error(&corrupt);
+        assert_eq!(err.kind(), ParseErrorKind::UnknownClass);
+        assert_eq!(err.offset(), Some(b as u64 + 8));
+
+        // The second dimension of "b"
+        let mut corrupt = data.clone();
+        corrupt[b + 36] = 3;
+        let err = parse_error(&corrupt);
+        assert_eq!(err.kind(), ParseErrorKind::SizeMismatch);
+        assert_eq!(err.variable_name(), Some("b"));
+        let mut reader = MatFileReader::new(std::io::Cursor::new(&corrupt)).unwrap();
+        match reader.read_variable("b") {
+            Err(Error::ParseError(err)) => {
+                assert_eq!(err.kind(), ParseErrorKind::SizeMismatch);
+                assert_eq!(err.variable_name(), Some("b"));
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let err = parse_error(&data[..data.len() - 4]);
+        assert_eq!(err.kind(), ParseErrorKind::Truncated);
+        assert_eq!(err.offset(), Some(b as u64));
+        assert_eq!(err.variable_name(), Some("b"));
+        assert_eq!(
+            err.to_string(),
+            "unexpected end of file at byte 192 in variable \"b\""
+        );
+        let truncated = &data[..data.len() - 4];
+        let mut reader = MatFileReader::new(std::io::Cursor::new(truncated)).unwrap();
+        match reader.read_variable("b") {
+            Err(Error::ParseError(err)) => {
+                assert_eq!(err.kind(), ParseErrorKind::Truncated);
+                assert_eq!(err.variable_name(), Some("b"));
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn skip_errors() {
+        let mat_file = MatFileBuilder::new()
+            .add_array("a", vec![1, 1], vec![1.0].into())
+            .add_array("b", vec![1, 2], vec![1i32, 2].into())
+            .add_array("c", vec![1, 1], vec![3u8].into())
+            .build()
+            .unwrap();
+        let mut data = Vec::new();
+        mat_file.write(&mut data).unwrap();
+        let options = ParseOptions::new().on_error(OnError::Skip);
+        let names = |mat_file: &MatFile| -> Vec<String> {
+            mat_file
+                .variables()
+                .iter()
+                .map(|variable| variable.name().to_owned())
+                .collect()
+        };
+
+        // The second dimension of "b"
+        let mut corrupt = data.clone();
+        corrupt[228] = 3;
+        assert!(MatFile::parse(corrupt.as_slice()).is_err());
+        let loaded = MatFile::parse_with_options(corrupt.as_slice(), &options).unwrap();
+        assert_eq!(names(&loaded), ["a", "c"]);
+        match loaded.warnings().as_slice() {
+            [ParseWarning::Skipped(err)] => {
+                assert_eq!(err.kind(), ParseErrorKind::SizeMismatch);
+                assert_eq!(err.variable_name(), Some("b"));
+            }
+            warnings => panic!("unexpected warnings {:?}", warnings),
+        }
+
+        // A partially written file, followed by something that is not a
+        // variable
+        let mut corrupt = data[..data.len() - 4].to_vec();
+        let loaded = MatFile::parse_with_options(corrupt.as_slice(), &options).unwrap();
+        assert_eq!(names(&loaded), ["a", "b"]);
+        assert_eq!(loaded.warnings()[0].variable_name(), Some("c"));
+        corrupt.truncate(256);
+        corrupt.extend_from_slice(&[2, 0, 0, 0, 1, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0]);
+        let loaded = MatFile::parse_with_options(corrupt.as_slice(), &options).unwrap();
+        assert_eq!(names(&loaded), ["a", "b"]);
+        assert_eq!(
+            loaded.warnings(),
+            &vec![ParseWarning::Unsupported {
+                offset: 256,
+                variable_name: None
+            }]
+        );
+        assert!(MatFile::parse(data.as_slice())
+            .unwrap()
+            .warnings()
+            .is_empty());
+
+        // Damaged sizes within "b", which is compressed in the second case
+        let compressed = |data: &[u8]| {
+            let mut encoder = libflate::zlib::Encoder::new(Vec::new()).unwrap();
+            std::io::Write::write_all(&mut encoder, &data[192..256]).unwrap();
+            let element = encoder.finish().into_result().unwrap();
+            let mut compressed = data[..192].to_vec();
+            compressed.extend_from_slice(&15u32.to_le_bytes());
+            compressed.extend_from_slice(&(element.len() as u32).to_le_bytes());
+            compressed.extend_from_slice(&element);
+            compressed.extend_from_slice(&data[256..]);
+            compressed
+        };
+        let damages: [(usize, &[u8], ParseErrorKind, Option<&str>); 4] = [
+            // A name tag claiming more than the four bytes of a small tag
+            (234, &[6, 0], ParseErrorKind::Malformed, None),
+            // Dimensions whose product overflows an i32
+            (
+                224,
+                &[0, 0, 1, 0, 0, 0, 1, 0],
+                ParseErrorKind::SizeMismatch,
+                Some("b"),
+            ),
+            (
+                224,
+                &[0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff, 0x7f],
+                ParseErrorKind::SizeMismatch,
+                Some("b"),
+            ),
+            // A negative dimension
+            (
+                224,
+                &[0xff, 0xff, 0xff, 0xff],
+                ParseErrorKind::Malformed,
+                Some("b"),
+            ),
+        ];
+        for &(position, damage, kind, name) in damages.iter() {
+            let mut corrupt = data.clone();
+            corrupt[position..position + damage.len()].copy_from_slice(damage);
+            for corrupt in [compressed(&corrupt), corrupt] {
+                let loaded = MatFile::parse_with_options(corrupt.as_slice(), &options).unwrap();
+                assert_eq!(names(&loaded), ["a", "c"]);
+                match loaded.warnings().as_slice() {
+                    [ParseWarning::Skipped(err)] => {
+                        assert_eq!(err.kind(), kind);
+                        assert_eq!(err.variable_name(), name);
+                    }
+                    warnings => panic!("unexpected warnings {:?}", warnings),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unsupported_variables() {
+        let cell = Variable::Cell(CellArray {
+            name: "c".to_owned(),
+            size: vec![1, 1],
+            data: vec![Variable::Numeric(
+                Array::new("", vec![1, 1], vec![2.0].into()).unwrap(),
+            )],
+        });
+        let mat_file = MatFileBuilder::new()
+            .add_array("a", vec![1, 1], vec![1.0].into())
+            .add_variable(cell)
+            .build()
+            .unwrap();
+        let mut data = Vec::new();
+        mat_file.write(&mut data).unwrap();
+        // Turn "a" and the value in "c" into function handles
+        data[144] = 16;
+        data[256] = 16;
+        // The fixture used by the documentation, apart from its creation time
+        let fixture = include_bytes!("../tests/function_handle.mat");
+        assert_eq!(&fixture[116..], &data[116..]);
+
+        let loaded = MatFile::parse(data.as_slice()).unwrap();
+        assert!(loaded.warnings().is_empty());
+        match loaded.variables().as_slice() {
+            [Variable::Unsupported(a), Variable::Cell(c)] => {
+                assert_eq!(a.name(), "a");
+                assert_eq!(a.class(), Class::FunctionHandle);
+                assert_eq!(a.size(), &vec![1, 1]);
+                assert_eq!(a.raw_bytes().as_slice(), &data[136..192]);
+                assert_eq!(a.byte_order(), ByteOrder::LittleEndian);
+                assert_eq!(c.data()[0].class(), Class::FunctionHandle);
+            }
+            variables => panic!("unexpected variables {:?}", variables),
+        }
+
+        // Written back unchanged
+        let mut written = Vec::new();
+        loaded.write(&mut written).unwrap();
+        assert_eq!(written[128..], data[128..]);
+        let options = WriteOptions::new().compression(Compression::Default);
+        let mut compressed = Vec::new();
+        loaded
+            .write_with_options(&mut compressed, &options)
+            .unwrap();
+        assert_eq!(
+            MatFile::parse(compressed.as_slice()).unwrap().variables(),
+            loaded.variables()
+        );
+
+        let mut reader = MatFileReader::new(std::io::Cursor::new(&compressed)).unwrap();
+        assert_eq!(reader.directory()[0].info.class(), Class::FunctionHandle);
+        assert_eq!(reader.directory()[0].info.bytes(), None);
+        assert!(matches!(
+            reader.read_variable("a"),
+            Ok(Some(Variable::Unsupported(_)))
+        ));
+    }
 }
@@ -0,0 +1,700 @@
+use crate::parse::{self, DataElement};
+use crate::{
+    Array, CharArray, Class, Error, NumericData, NumericType, OpaqueObject, ParseError,
+    ParseErrorKind, StructArray, UnsupportedVariable, Variable,
+};
+
+// Objects of classdef classes and of types like `string`, `datetime` or
+// `table` belong to the MCOS ("MATLAB Class Object System") type system.
+// Variables only hold references to such objects, which are stored in the
+// subsystem data at the end of the file. None of this is documented, the
+// layout decoded here is what has been worked out by reading files written
+// by Matlab.
+//
+// The subsystem data is a uint8 array holding a small MAT-file of its own: 8
+// bytes with the version and byte order, followed by a structure whose "MCOS"
+// field holds an opaque object of class "FileWrapper__". Its data is a cell
+// array, with the first cell holding the metadata of all objects, the cells
+// from the third on holding the values of their properties and the last cell
+// holding the default values of the properties of each class.
+
+// Marks the data of opaque objects that refer to objects in the subsystem
+const REFERENCE_MARKER: u32 = 0xDD00_0000;
+
+// The size of an entry of the object table of the metadata
+const OBJECT_ENTRY_SIZE: usize = 24;
+
+// A property of an object as stored in the metadata, with the index of its
+// name and a value whose meaning depends on the kind of property
+#[derive(Clone, Copy, Debug)]
+struct Property {
+    name: u32,
+    kind: u32,
+    value: u32,
+}
+
+// An object as stored in the metadata, with the id of its class and the
+// properties that differ from their default value
+#[derive(Clone, Debug)]
+struct Object {
+    class: usize,
+    properties: Vec<Property>,
+}
+
+// The MCOS objects of a file
+#[derive(Debug)]
+pub struct Subsystem {
+    names: Vec<String>,
+    // Indexed by object id
+    objects: Vec<Object>,
+    cells: Vec<Variable>,
+}
+
+fn malformed() -> Error {
+    Error::ParseError(ParseError::new(ParseErrorKind::Malformed))
+}
+
+fn u32_at(data: &[u8], offset: usize, endianness: nom::Endianness) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+    Some(match endianness {
+        nom::Endianness::Little => u32::from_le_bytes(bytes),
+        nom::Endianness::Big => u32::from_be_bytes(bytes),
+    })
+}
+
+fn bytes(variable: &Variable) -> Option<&[u8]> {
+    match variable {
+        Variable::Numeric(Array {
+            data: NumericData::UInt8 { real, .. },
+            ..
+        }) => Some(real),
+        _ => None,
+    }
+}
+
+fn to_variable(data_element: DataElement) -> Result<Variable, Error> {
+    Variable::try_from(data_element)?.ok_or_else(malformed)
+}
+
+// The type system, class name and data of an opaque object
+fn parse_opaque(variable: &UnsupportedVariable) -> Result<(String, String, Variable), Error> {
+    let (_remaining, (_name, type_system, class_name, data_element)) =
+        parse::parse_opaque_matrix(variable.raw_bytes(), variable.byte_order().endianness())
+            .map_err(|err| Error::ParseError(parse::to_parse_error(err, &[], 0)))?;
+    Ok((type_system, class_name, to_variable(data_element)?))
+}
+
+// Parses the subsystem data, given as the uint8 array it is stored in. Files
+// without MCOS objects result in an empty subsystem.
+pub fn parse_subsystem(variable: &Variable) -> Result<Subsystem, Error> {
+    let data = bytes(variable).ok_or_else(malformed)?;
+    let endianness = match data.get(2..4) {
+        Some(b"IM") => nom::Endianness::Little,
+        Some(b"MI") => nom::Endianness::Big,
+        _ => return Err(malformed()),
+    };
+    let (_remaining, data_element) = parse::parse_next_data_element(&data[8..], endianness)
+        .map_err(|err| Error::ParseError(parse::to_parse_error(err, &[], 0)))?;
+    let file_wrapper = match to_variable(data_element)? {
+        Variable::Struct(array) => match array.get(0, "MCOS") {
+            Some(Variable::Unsupported(file_wrapper)) => parse_opaque(file_wrapper)?,
+            _ => {
+                return Ok(Subsystem {
+                    names: Vec::new(),
+                    objects: Vec::new(),
+                    cells: Vec::new(),
+                })
+            }
+        },
+        _ => return Err(malformed()),
+    };
+    let cells = match file_wrapper {
+        (_type_system, _class_name, Variable::Cell(array)) => array.data,
+        _ => return Err(malformed()),
+    };
+    let metadata = cells.first().and_then(bytes).ok_or_else(malformed)?;
+    let (names, objects) = parse_metadata(metadata, endianness).ok_or_else(malformed)?;
+    Ok(Subsystem {
+        names,
+        objects,
+        cells,
+    })
+}
+
+// The metadata starts with a version, the number of names and the offsets of
+// the regions that follow the names:
+// 1. The classes, as indices of their package and class names
+// 2. Property lists of objects saved with `saveobj`
+// 3. The objects, each with its class and the property lists it uses
+// 4. Property lists of all other objects
+// 5. Dynamic properties
+// All regions start with an entry of zeros, so that ids start at one.
+fn parse_metadata(data: &[u8], endianness: nom::Endianness) -> Option<(Vec<String>, Vec<Object>)> {
+    let u32_at = |offset: usize| u32_at(data, offset, endianness);
+    let n_names = u32_at(4)? as usize;
+    let mut offsets = [0; 8];
+    for (k, offset) in offsets.iter_mut().enumerate() {
+        *offset = u32_at(8 + 4 * k)? as usize;
+    }
+
+    // Null terminated names
+    let mut names = Vec::with_capacity(n_names.min(data.len()));
+    let mut names_data = data.get(40..offsets[0])?;
+    for _ in 0..n_names {
+        let end = names_data.iter().position(|&c| c == 0)?;
+        names.push(String::from_utf8(names_data[..end].to_vec()).ok()?);
+        names_data = &names_data[end + 1..];
+    }
+
+    let saveobj_properties = parse_property_lists(data, offsets[1], offsets[2], endianness)?;
+    let properties = parse_property_lists(data, offsets[3], offsets[4], endianness)?;
+    let n_objects = offsets[3].checked_sub(offsets[2])? / OBJECT_ENTRY_SIZE;
+    let mut objects = Vec::with_capacity(n_objects);
+    for k in 0..n_objects {
+        let entry = offsets[2] + k * OBJECT_ENTRY_SIZE;
+        let class = u32_at(entry)? as usize;
+        let saveobj_id = u32_at(entry + 12)? as usize;
+        let id = u32_at(entry + 16)? as usize;
+        let object_properties = if saveobj_id > 0 {
+            saveobj_properties.get(saveobj_id)?
+        } else {
+            properties.get(id)?
+        };
+        objects.push(Object {
+            class,
+            properties: object_properties.clone(),
+        });
+    }
+    Some((names, objects))
+}
+
+// A region of property lists. Each list is the number of properties followed
+// by the name index, kind and value of each property, padded to 8 bytes.
+fn parse_property_lists(
+    data: &[u8],
+    start: usize,
+    end: usize,
+    endianness: nom::Endianness,
+) -> Option<Vec<Vec<Property>>> {
+    let u32_at = |offset: usize| u32_at(data, offset, endianness);
+    let mut lists = Vec::new();
+    let mut offset = start;
+    while offset < end {
+        let n_properties = u32_at(offset)? as usize;
+        if n_properties > (end - offset) / 12 {
+            return None;
+        }
+        let mut list = Vec::with_capacity(n_properties);
+        for k in 0..n_properties {
+            let property = offset + 4 + 12 * k;
+            list.push(Property {
+                name: u32_at(property)?,
+                kind: u32_at(property + 4)?,
+                value: u32_at(property + 8)?,
+            });
+        }
+        offset = (offset + 4 + 12 * n_properties).next_multiple_of(8);
+        lists.push(list);
+    }
+    Some(lists)
+}
+
+impl Subsystem {
+    // Replaces the references to MCOS objects within the variable by the
+    // objects they refer to. References that can't be resolved are left as
+    // they are.
+    pub fn resolve(&self, variable: Variable) -> Variable {
+        self.resolve_within(variable, &mut Vec::new())
+    }
+
+    // `within` holds the ids of the objects whose properties are being
+    // resolved, to break reference cycles
+    fn resolve_within(&self, variable: Variable, within: &mut Vec<usize>) -> Variable {
+        match variable {
+            Variable::Unsupported(variable) if variable.class() == Class::Opaque => {
+                match self.object(&variable, within) {
+                    Some(object) => Variable::Opaque(object),
+                    None => Variable::Unsupported(variable),
+                }
+            }
+            Variable::Cell(mut array) => {
+                array.data = self.resolve_all(array.data, within);
+                Variable::Cell(array)
+            }
+            Variable::Struct(mut array) => {
+                array.data = self.resolve_all(array.data, within);
+                Variable::Struct(array)
+            }
+            Variable::Object(mut array) => {
+                array.properties.data = self.resolve_all(array.properties.data, within);
+                Variable::Object(array)
+            }
+            variable => variable,
+        }
+    }
+
+    fn resolve_all(&self, variables: Vec<Variable>, within: &mut Vec<usize>) -> Vec<Variable> {
+        variables
+            .into_iter()
+            .map(|variable| self.resolve_within(variable, within))
+            .collect()
+    }
+
+    // The data of a reference is a uint32 array holding the marker, the
+    // number of dimensions, the dimensions, the ids of the objects and the id
+    // of their class
+    fn object(
+        &self,
+        variable: &UnsupportedVariable,
+        within: &mut Vec<usize>,
+    ) -> Option<OpaqueObject> {
+        let (type_system, class_name, data) = parse_opaque(variable).ok()?;
+        let reference = match data {
+            Variable::Numeric(Array {
+                data: NumericData::UInt32 { real, .. },
+                ..
+            }) if type_system == "MCOS" => real,
+            _ => return None,
+        };
+        if reference.first() != Some(&REFERENCE_MARKER) {
+            return None;
+        }
+        let ndims = *reference.get(1)? as usize;
+        let size: Vec<usize> = reference
+            .get(2..ndims.checked_add(2)?)?
+            .iter()
+            .map(|&d| d as usize)
+            .collect();
+        let n_objects = size
+            .iter()
+            .try_fold(1usize, |n_objects, &d| n_objects.checked_mul(d))?;
+        let ids_start = ndims.checked_add(2)?;
+        let ids = reference.get(ids_start..ids_start.checked_add(n_objects)?)?;
+
+        let mut field_names: Vec<String> = Vec::new();
+        let mut objects = Vec::with_capacity(ids.len());
+        for &id in ids {
+            let id = id as usize;
+            if within.contains(&id) {
+                return None;
+            }
+            within.push(id);
+            let properties = self.properties(id, within);
+            within.pop();
+            let properties = properties?;
+            for (name, _value) in &properties {
+                if !field_names.contains(name) {
+                    field_names.push(name.clone());
+                }
+            }
+            objects.push(properties);
+        }
+
+        // The default values of a class list all its properties. Properties
+        // missing from them get an empty array, which is what Matlab uses for
+        // properties that don't declare a default value.
+        let mut data = Vec::with_capacity(objects.len() * field_names.len());
+        for mut properties in objects {
+            for field_name in &field_names {
+                let value = match properties.iter().position(|(name, _)| name == field_name) {
+                    Some(k) => properties.swap_remove(k).1,
+                    None => empty_array(),
+                };
+                data.push(value);
+            }
+        }
+        Some(OpaqueObject {
+            class_name,
+            properties: StructArray {
+                name: variable.name().to_owned(),
+                size,
+                field_names,
+                data,
+            },
+        })
+    }
+
+    // The names and values of the properties of an object. Properties are
+    // only stored if they differ from their default value, the others are
+    // taken from the default values of the class.
+    fn properties(&self, id: usize, within: &mut Vec<usize>) -> Option<Vec<(String, Variable)>> {
+        let object = self.objects.get(id)?;
+        let mut properties = Vec::new();
+        for property in &object.properties {
+            let name = self.name(property.name)?;
+            let value = match property.kind {
+                // The name of an enumeration member or the like
+                0 => text(self.name(property.value)?),
+                1 => {
+                    let value = self.cells.get(property.value as usize + 2)?.clone();
+                    self.resolve_within(value, within)
+                }
+                2 => logical(property.value != 0),
+                _ => return None,
+            };
+            properties.push((name.to_owned(), value));
+        }
+        if let Some(defaults) = self.defaults(object.class) {
+            for (name, value) in defaults.field_names.iter().zip(&defaults.data) {
+                if properties.iter().all(|(stored, _)| stored != name) {
+                    let value = self.resolve_within(value.clone(), within);
+                    properties.push((name.clone(), value));
+                }
+            }
+        }
+        Some(properties)
+    }
+
+    // The last cell holds a structure with the default values for each class,
+    // indexed by class id
+    fn defaults(&self, class: usize) -> Option<&StructArray> {
+        if self.cells.len() < 3 {
+            return None;
+        }
+        match self.cells.last()? {
+            Variable::Cell(array) => match array.data.get(class)? {
+                Variable::Struct(defaults) => Some(defaults),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Names are numbered from one
+    fn name(&self, index: u32) -> Option<&str> {
+        let index = (index as usize).checked_sub(1)?;
+        self.names.get(index).map(|name| name.as_str())
+    }
+}
+
+fn empty_array() -> Variable {
+    Variable::Numeric(Array {
+        name: String::new(),
+        size: vec![0, 0],
+        data: NumericData::Double {
+            real: Vec::new(),
+            imag: None,
+        },
+        logical: false,
+        global: false,
+        storage_type: NumericType::Double,
+    })
+}
+
+fn text(text: &str) -> Variable {
+    let data: Vec<u16> = text.encode_utf16().collect();
+    Variable::Char(CharArray {
+        name: String::new(),
+        size: vec![1, data.len()],
+        data,
+    })
+}
+
+fn logical(value: bool) -> Variable {
+    Variable::Numeric(Array {
+        name: String::new(),
+        size: vec![1, 1],
+        data: NumericData::UInt8 {
+            real: vec![u8::from(value)],
+            imag: None,
+        },
+        logical: true,
+        global: false,
+        storage_type: NumericType::UInt8,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse::DataType;
+    use crate::{
+        write, ByteOrder, CellArray, Compression, MatFile, MatFileAppender, MatFileReader,
+    };
+
+    const LITTLE: nom::Endianness = nom::Endianness::Little;
+
+    fn u32s(buf: &mut Vec<u8>, values: &[u32]) {
+        for value in values {
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn pad(buf: &mut Vec<u8>) {
+        buf.resize(buf.len().next_multiple_of(8), 0);
+    }
+
+    fn subelement(data_type: DataType, data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        u32s(&mut buf, &[data_type as u32, data.len() as u32]);
+        buf.extend_from_slice(data);
+        pad(&mut buf);
+        buf
+    }
+
+    fn array(size: Vec<usize>, data: NumericData) -> Variable {
+        Variable::Numeric(Array::new("", size, data).unwrap())
+    }
+
+    fn opaque(name: &str, class_name: &str, data: &Variable) -> Variable {
+        let mut raw_bytes = subelement(DataType::UInt32, &[17, 0, 0, 0, 0, 0, 0, 0]);
+        for name in [name, "MCOS", class_name].iter() {
+            raw_bytes.extend(subelement(DataType::Int8, name.as_bytes()));
+        }
+        raw_bytes.extend(write::variable_data_element(data, LITTLE, Compression::None).unwrap());
+        Variable::Unsupported(UnsupportedVariable {
+            name: name.to_owned(),
+            class: Class::Opaque,
+            size: Vec::new(),
+            raw_bytes,
+            byte_order: ByteOrder::LittleEndian,
+        })
+    }
+
+    // A reference to objects of the first class
+    fn reference(name: &str, size: [u32; 2], ids: &[u32]) -> Variable {
+        let mut data = vec![REFERENCE_MARKER, 2, size[0], size[1]];
+        data.extend_from_slice(ids);
+        data.push(1);
+        opaque(name, "Point", &array(vec![data.len(), 1], data.into()))
+    }
+
+    // Metadata for objects of a single class, each with its own property list
+    fn metadata(names: &[&str], property_lists: &[&[[u32; 3]]]) -> Vec<u8> {
+        let mut buf = vec![0; 40];
+        let mut offsets = Vec::new();
+        for name in names {
+            buf.extend_from_slice(name.as_bytes());
+            buf.push(0);
+        }
+        pad(&mut buf);
+        offsets.push(buf.len() as u32);
+        u32s(&mut buf, &[0, 0, 0, 0, 0, 1, 0, 0]);
+        offsets.push(buf.len() as u32);
+        u32s(&mut buf, &[0, 0]);
+        offsets.push(buf.len() as u32);
+        u32s(&mut buf, &[0; 6]);
+        for id in 1..=property_lists.len() as u32 {
+            u32s(&mut buf, &[1, 0, 0, 0, id, id]);
+        }
+        offsets.push(buf.len() as u32);
+        u32s(&mut buf, &[0, 0]);
+        for properties in property_lists {
+            u32s(&mut buf, &[properties.len() as u32]);
+            for property in properties.iter() {
+                u32s(&mut buf, property);
+            }
+            pad(&mut buf);
+        }
+        offsets.resize(8, buf.len() as u32);
+        let mut header = Vec::new();
+        u32s(&mut header, &[4, names.len() as u32]);
+        u32s(&mut header, &offsets);
+        buf[..40].copy_from_slice(&header);
+        buf
+    }
+
+    fn mcos_file() -> Vec<u8> {
+        let names = ["Point", "x", "label", "visible", "next", "origin"];
+        // Property values refer to the cells from the third on. Object 1
+        // refers to itself, object 2 to object 1. Object 3 takes "next" and
+        // "label" from the default values of the class.
+        let metadata = metadata(
+            &names,
+            &[
+                &[[2, 1, 0], [3, 0, 6], [4, 2, 1], [5, 1, 2]],
+                &[[2, 1, 1], [5, 1, 2]],
+                &[[2, 1, 3]],
+            ],
+        );
+        let cells = vec![
+            array(vec![1, metadata.len()], metadata.into()),
+            array(vec![0, 0], Vec::<f64>::new().into()),
+            array(vec![1, 1], vec![1.0].into()),
+            array(vec![1, 1], vec![2.0].into()),
+            reference("", [1, 1], &[1]),
+            array(vec![1, 1], vec![3.0].into()),
+            array(vec![0, 0], Vec::<f64>::new().into()),
+            Variable::Cell(CellArray {
+                name: String::new(),
+                size: vec![2, 1],
+                data: vec![
+                    array(vec![0, 0], Vec::<f64>::new().into()),
+                    Variable::Struct(StructArray {
+                        name: String::new(),
+                        size: vec![1, 1],
+                        field_names: vec!["x".to_owned(), "label".to_owned(), "next".to_owned()],
+                        data: vec![
+                            array(vec![1, 1], vec![0.0].into()),
+                            text("none"),
+                            array(vec![0, 0], Vec::<f64>::new().into()),
+                        ],
+                    }),
+                ],
+            }),
+        ];
+        let file_wrapper = opaque(
+            "",
+            "FileWrapper__",
+            &Variable::Cell(CellArray {
+                name: String::new(),
+                size: vec![cells.len(), 1],
+                data: cells,
+            }),
+        );
+        let subsystem_struct = Variable::Struct(StructArray {
+            name: String::new(),
+            size: vec![1, 1],
+            field_names: vec!["MCOS".to_owned()],
+            data: vec![file_wrapper],
+        });
+        let mut subsystem = vec![0, 1, b'I', b'M', 0, 0, 0, 0];
+        subsystem.extend(
+            write::variable_data_element(&subsystem_struct, LITTLE, Compression::None).unwrap(),
+        );
+
+        let c = Variable::Cell(CellArray {
+            name: "c".to_owned(),
+            size: vec![1, 1],
+            data: vec![reference("", [1, 2], &[2, 3])],
+        });
+        let mut file = write::header(LITTLE);
+        for variable in [reference("p", [1, 1], &[1]), c].iter() {
+            file.extend(write::variable_data_element(variable, LITTLE, Compression::None).unwrap());
+        }
+        let subsystem_offset = file.len() as u64;
+        file[116..124].copy_from_slice(&subsystem_offset.to_le_bytes());
+        let subsystem = array(vec![1, subsystem.len()], subsystem.into());
+        file.extend(
+            write::variable_data_element(&subsystem, LITTLE, Compression::Default).unwrap(),
+        );
+        file
+    }
+
+    fn double(variable: Option<&Variable>) -> Option<f64> {
+        match variable {
+            Some(Variable::Numeric(array)) => match array.data() {
+                NumericData::Double { real, .. } => real.first().copied(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn check_c(c: &Variable) {
+        let objects = match c {
+            Variable::Cell(array) => match &array.data()[0] {
+                Variable::Opaque(objects) => objects,
+                other => panic!("unexpected cell {:?}", other),
+            },
+            other => panic!("unexpected variable {:?}", other),
+        };
+        assert_eq!(objects.size(), &vec![1, 2]);
+        let properties = objects.properties();
+        assert_eq!(properties.field_names(), &vec!["x", "next", "label"]);
+        assert_eq!(double(properties.get(1, "x")), Some(3.0));
+        assert_eq!(properties.get(1, "next").unwrap().size(), &vec![0, 0]);
+        assert_eq!(properties.get(1, "label"), Some(&text("none")));
+        match properties.get(0, "next") {
+            Some(Variable::Opaque(next)) => {
+                assert_eq!(double(next.properties().get(0, "x")), Some(1.0));
+                assert!(matches!(
+                    next.properties().get(0, "next"),
+                    Some(Variable::Unsupported(_))
+                ));
+            }
+            other => panic!("unexpected property {:?}", other),
+        }
+    }
+
+    #[test]
+    fn opaque_objects() {
+        let data = mcos_file();
+        let mat_file = MatFile::parse(data.as_slice()).unwrap();
+        assert!(mat_file.warnings().is_empty());
+        assert_eq!(mat_file.variables().len(), 2);
+        match mat_file.find_variable_by_name("p") {
+            Some(Variable::Opaque(p)) => {
+                assert_eq!(p.class_name(), "Point");
+                assert_eq!(p.size(), &vec![1, 1]);
+                let properties = p.properties();
+                assert_eq!(
+                    properties.field_names(),
+                    &vec!["x", "label", "visible", "next"]
+                );
+                assert_eq!(double(properties.get(0, "x")), Some(1.0));
+                match properties.get(0, "label") {
+                    Some(Variable::Char(label)) => {
+                        assert_eq!(label.rows(), Some(vec!["origin".to_owned()]))
+                    }
+                    other => panic!("unexpected label {:?}", other),
+                }
+                match properties.get(0, "visible") {
+                    Some(Variable::Numeric(visible)) => assert!(visible.is_logical()),
+                    other => panic!("unexpected visible {:?}", other),
+                }
+            }
+            other => panic!("unexpected variable {:?}", other),
+        }
+        check_c(mat_file.find_variable_by_name("c").unwrap());
+        assert!(mat_file.write(Vec::new()).is_err());
+
+        let mut reader = MatFileReader::new(std::io::Cursor::new(&data)).unwrap();
+        assert_eq!(reader.directory().len(), 2);
+        check_c(&reader.read_variable("c").unwrap().unwrap());
+
+        // The fixture used by the documentation was written by `mcos_file`,
+        // only its creation time differs
+        let fixture = include_bytes!("../tests/opaque.mat");
+        assert_eq!(&fixture[116..], &data[116..]);
+    }
+
+    #[test]
+    fn malformed_references() {
+        let subsystem = Subsystem {
+            names: Vec::new(),
+            objects: Vec::new(),
+            cells: Vec::new(),
+        };
+        let references: [&[u32]; 3] = [
+            &[REFERENCE_MARKER, u32::MAX],
+            &[REFERENCE_MARKER, 2, u32::MAX, u32::MAX, 1, 1],
+            &[REFERENCE_MARKER, 3, 1 << 31, 1 << 31, 1 << 31, 1, 1],
+        ];
+        for reference in references.iter() {
+            let data = array(vec![reference.len(), 1], reference.to_vec().into());
+            let variable = match opaque("x", "Point", &data) {
+                Variable::Unsupported(variable) => variable,
+                _ => unreachable!(),
+            };
+            assert!(subsystem.object(&variable, &mut Vec::new()).is_none());
+        }
+    }
+
+    #[test]
+    fn append_before_subsystem() {
+        let path = std::env::temp_dir().join(format!("matfile_mcos_{}.mat", std::process::id()));
+        std::fs::write(&path, mcos_file()).unwrap();
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut appender = MatFileAppender::open(file).unwrap();
+        let p = Array::new("p", vec![1, 1], vec![4.0].into()).unwrap();
+        appender.append(&Variable::Numeric(p)).unwrap();
+        let mat_file = MatFile::parse(std::fs::File::open(&path).unwrap()).unwrap();
+        // The subsystem data stays behind all variables
+        let reader = MatFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let subsystem_offset = mat_file.header().unwrap().subsystem_offset().unwrap();
+        assert!(reader
+            .directory()
+            .iter()
+            .all(|entry| entry.offset() < subsystem_offset));
+        assert!(mat_file.warnings().is_empty());
+        check_c(mat_file.find_variable_by_name("c").unwrap());
+        assert_eq!(double(mat_file.find_variable_by_name("p")), Some(4.0));
+    }
+}
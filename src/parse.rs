@@ -1,20 +1,23 @@
+use crate::{stream, Error, OnError, ParseError, ParseErrorKind};
 use libflate::zlib::Decoder;
 use nom::{
     alt, apply, be_f32, be_f64, be_i16, be_i32, be_i64, be_i8, be_u16, be_u32, be_u64, be_u8, char,
-    complete, cond, count, count_fixed, do_parse, error_position, i32, le_f32, le_f64, le_i16,
-    le_i32, le_i64, le_i8, le_u16, le_u32, le_u64, le_u8, length_value, many0, map, map_res, not,
-    opt, pair, peek, switch, tag, take, u16, u32, value, IResult,
+    complete, cond, count, do_parse, error_position, i32, le_f32, le_f64, le_i16, le_i32, le_i64,
+    le_i8, le_u16, le_u32, le_u64, le_u8, length_value, map, map_res, not, opt, pair, peek, switch,
+    tag, take, u16, u32, value, IResult,
 };
 use num_traits::FromPrimitive;
-use std::io::Read;
+use std::convert::TryFrom;
 
 // https://www.mathworks.com/help/pdf_doc/matlab/matfile_format.pdf
 // https://www.mathworks.com/help/matlab/import_export/mat-file-versions.html
 
 #[derive(Clone, Debug)]
 pub struct Header {
-    text: String,
-    is_little_endian: bool,
+    pub text: String,
+    pub subsystem_offset: Option<u64>,
+    pub version: u16,
+    pub is_little_endian: bool,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -32,7 +35,24 @@ pub enum NumericData {
 }
 
 impl NumericData {
-    fn len(&self) -> usize {
+    // No numbers of the given type yet, `None` if it is not a numeric type
+    pub fn with_capacity(data_type: DataType, capacity: usize) -> Option<Self> {
+        match data_type {
+            DataType::Int8 => Some(NumericData::Int8(Vec::with_capacity(capacity))),
+            DataType::UInt8 => Some(NumericData::UInt8(Vec::with_capacity(capacity))),
+            DataType::Int16 => Some(NumericData::Int16(Vec::with_capacity(capacity))),
+            DataType::UInt16 => Some(NumericData::UInt16(Vec::with_capacity(capacity))),
+            DataType::Int32 => Some(NumericData::Int32(Vec::with_capacity(capacity))),
+            DataType::UInt32 => Some(NumericData::UInt32(Vec::with_capacity(capacity))),
+            DataType::Int64 => Some(NumericData::Int64(Vec::with_capacity(capacity))),
+            DataType::UInt64 => Some(NumericData::UInt64(Vec::with_capacity(capacity))),
+            DataType::Single => Some(NumericData::Single(Vec::with_capacity(capacity))),
+            DataType::Double => Some(NumericData::Double(Vec::with_capacity(capacity))),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
         match self {
             NumericData::Single(vec) => vec.len(),
             NumericData::Double(vec) => vec.len(),
@@ -47,7 +67,7 @@ impl NumericData {
         }
     }
 
-    fn data_type(&self) -> DataType {
+    pub fn data_type(&self) -> DataType {
         match self {
             NumericData::Single(_) => DataType::Single,
             NumericData::Double(_) => DataType::Double,
@@ -63,7 +83,7 @@ impl NumericData {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum DataElement {
     NumericMatrix(
         ArrayFlags,
@@ -81,11 +101,23 @@ pub enum DataElement {
         NumericData,
         Option<NumericData>,
     ),
-    // CharacterMatrix,
-    // Cell Matrix,
-    // Structure Matrix,
-    // Object Matrix,
+    CharacterMatrix(ArrayFlags, Dimensions, String, CharacterData),
+    CellMatrix(ArrayFlags, Dimensions, String, Vec<DataElement>),
+    StructureMatrix(ArrayFlags, Dimensions, String, FieldNames, Vec<DataElement>),
+    ObjectMatrix(
+        ArrayFlags,
+        Dimensions,
+        String,
+        String,
+        FieldNames,
+        Vec<DataElement>,
+    ),
+    // Arrays of classes that aren't decoded, with the contents of the matrix
+    // element in the byte order they were read in
+    UnsupportedMatrix(ArrayFlags, Dimensions, String, Vec<u8>, nom::Endianness),
     Unsupported,
+    // Space that holds no variable, see `write::filler`
+    Filler,
 }
 
 // #[cfg(feature = "ndarray")]
@@ -100,14 +132,90 @@ pub enum DataElement {
 //     }
 // }
 
-fn assert(i: &[u8], v: bool) -> IResult<&[u8], ()> {
+// Errors are reported to nom as custom errors holding their kind
+pub fn failure(i: &[u8], kind: ParseErrorKind) -> nom::Err<&[u8], u32> {
+    nom::Err::Failure(error_position!(i, nom::ErrorKind::Custom(kind.code())))
+}
+
+fn check(i: &[u8], v: bool, kind: ParseErrorKind) -> IResult<&[u8], ()> {
     if v {
         Ok((i, ()))
     } else {
-        Err(nom::Err::Failure(error_position!(
-            i,
-            nom::ErrorKind::Custom(48)
-        )))
+        Err(failure(i, kind))
+    }
+}
+
+pub fn assert(i: &[u8], v: bool) -> IResult<&[u8], ()> {
+    check(i, v, ParseErrorKind::Malformed)
+}
+
+// Checks that the data of an array matches its size and class
+fn assert_size(i: &[u8], v: bool) -> IResult<&[u8], ()> {
+    check(i, v, ParseErrorKind::SizeMismatch)
+}
+
+// The position of `slice` within `input`, if it is a part of it
+fn position_within(input: &[u8], slice: &[u8]) -> Option<usize> {
+    let position = (slice.as_ptr() as usize).checked_sub(input.as_ptr() as usize)?;
+    if position <= input.len() {
+        Some(position)
+    } else {
+        None
+    }
+}
+
+// Converts an error of the parsers into a `ParseError`. `input` is what was
+// parsed, starting at byte `base` of the file. Errors that point elsewhere,
+// e.g. into inflated data, are left without a position.
+pub fn to_parse_error(err: nom::Err<&[u8], u32>, input: &[u8], base: u64) -> ParseError {
+    let (slice, error_kind) = match err {
+        nom::Err::Incomplete(_) => (&input[input.len()..], nom::ErrorKind::Complete),
+        nom::Err::Error(context) | nom::Err::Failure(context) => match context {
+            nom::Context::Code(slice, error_kind) => (slice, error_kind),
+            nom::Context::List(mut list) => match list.pop() {
+                Some((slice, error_kind)) => (slice, error_kind),
+                None => (
+                    &[][..],
+                    nom::ErrorKind::Custom(ParseErrorKind::Malformed.code()),
+                ),
+            },
+        },
+    };
+    let kind = match error_kind {
+        nom::ErrorKind::Custom(code) => {
+            ParseErrorKind::from_code(code).unwrap_or(ParseErrorKind::Malformed)
+        }
+        nom::ErrorKind::Complete | nom::ErrorKind::Eof => ParseErrorKind::Truncated,
+        _ => ParseErrorKind::Malformed,
+    };
+    let error = ParseError::new(kind);
+    match position_within(input, slice) {
+        Some(position) => error.or_at(base + position as u64),
+        None => error,
+    }
+}
+
+// MAT-file version 7.3 files start with a Level 5 style header with version
+// 0x0200, followed by an HDF5 file (the header being the HDF5 user block).
+pub fn is_hdf5_mat_file(i: &[u8]) -> bool {
+    const HDF5_SIGNATURE: &[u8] = b"\x89HDF\r\n\x1a\n";
+    let has_version = matches!(i.get(124..128), Some(b"\x00\x02IM") | Some(b"\x02\x00MI"));
+    let has_signature = i.get(512..520) == Some(HDF5_SIGNATURE);
+    has_version || has_signature
+}
+
+// Files without subsystem data have either all zeros or all spaces in the
+// subsystem data offset field
+fn parse_subsystem_offset(ssdo: &[u8], is_little_endian: bool) -> Option<u64> {
+    if ssdo.iter().all(|&b| b == 0) || ssdo.iter().all(|&b| b == b' ') {
+        return None;
+    }
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(ssdo);
+    if is_little_endian {
+        Some(u64::from_le_bytes(bytes))
+    } else {
+        Some(u64::from_be_bytes(bytes))
     }
 }
 
@@ -115,24 +223,33 @@ pub fn parse_header(i: &[u8]) -> IResult<&[u8], Header> {
     do_parse!(
         i,
         // Make sure that the first four bytes are not null.
-        peek!(count_fixed!(_, pair!(not!(char!('\0')), take!(1)), 4)) >>
+        peek!(count!(pair!(not!(char!('\0')), take!(1)), 4)) >>
         text: take!(116) >> // text field
-        _ssdo: take!(8) >> // subsystem data offset
+        ssdo: take!(8) >> // subsystem data offset
         // Assume little endian for now
         version: u16!(nom::Endianness::Little) >>
         // Check the endianness
         is_little_endian: alt!(value!(true, tag!("IM")) | value!(false, tag!("MI"))) >>
         // Fix endianness of the version field if we assumed the wrong one
         version: value!(if is_little_endian { version } else { version.swap_bytes() }) >>
-        apply!(assert, version == 0x0100) >>
+        apply!(check, version == 0x0100, ParseErrorKind::UnsupportedVersion) >>
         (Header {
-            text: std::str::from_utf8(text).unwrap_or(&"").to_owned(),
-            is_little_endian: is_little_endian,
+            text: std::str::from_utf8(text).unwrap_or("").to_owned(),
+            subsystem_offset: parse_subsystem_offset(ssdo, is_little_endian),
+            version,
+            is_little_endian,
         })
     )
+    .map_err(|err| match err {
+        nom::Err::Failure(context) => nom::Err::Failure(context),
+        _ => failure(i, ParseErrorKind::InvalidHeader),
+    })
 }
 
-fn parse_next_data_element(i: &[u8], endianness: nom::Endianness) -> IResult<&[u8], DataElement> {
+pub fn parse_next_data_element(
+    i: &[u8],
+    endianness: nom::Endianness,
+) -> IResult<&[u8], DataElement> {
     do_parse!(
         i,
         data_element_tag: apply!(parse_data_element_tag, endianness) >>
@@ -140,10 +257,8 @@ fn parse_next_data_element(i: &[u8], endianness: nom::Endianness) -> IResult<&[u
             match data_element_tag.data_type {
                 DataType::Matrix => parse_matrix_data_element,
                 DataType::Compressed => parse_compressed_data_element,
-                _ => {
-                    println!("Unsupported variable type: {:?} (must be Matrix or Compressed)", data_element_tag.data_type);
-                    parse_unsupported_data_element
-                }
+                // Reported as a warning by `MatFile::parse_with_options`
+                _ => parse_unsupported_data_element
             }
         ) >>
         data_element: length_value!(value!(data_element_tag.data_byte_size), apply!(next_parser, endianness)) >>
@@ -157,15 +272,12 @@ fn parse_next_data_element(i: &[u8], endianness: nom::Endianness) -> IResult<&[u
     )
 }
 
-fn ceil_to_multiple(x: u32, multiple: u32) -> u32 {
-    if x > 0 {
-        (((x - 1) / multiple) + 1) * multiple
-    } else {
-        0
-    }
+// The number of padding bytes that align data of the given size to 8 bytes
+fn padding_size(byte_size: u32) -> u32 {
+    (8 - byte_size % 8) % 8
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct ArrayFlags {
     pub complex: bool,
     pub global: bool,
@@ -193,17 +305,17 @@ pub enum DataType {
     Utf32 = 18,
 }
 
-// impl DataType {
-//     fn byte_size(&self) -> Option<usize> {
-//         match self {
-//             DataType::Int8 | DataType::UInt8 | DataType::Utf8 => Some(1),
-//             DataType::Int16 | DataType::UInt16 | DataType::Utf16 => Some(2),
-//             DataType::Int32 | DataType::UInt32 | DataType::Single | DataType::Utf32 => Some(4),
-//             DataType::Int64 | DataType::UInt64 | DataType::Double => Some(8),
-//             _ => None,
-//         }
-//     }
-// }
+impl DataType {
+    pub fn byte_size(&self) -> Option<usize> {
+        match self {
+            DataType::Int8 | DataType::UInt8 | DataType::Utf8 => Some(1),
+            DataType::Int16 | DataType::UInt16 | DataType::Utf16 => Some(2),
+            DataType::Int32 | DataType::UInt32 | DataType::Single | DataType::Utf32 => Some(4),
+            DataType::Int64 | DataType::UInt64 | DataType::Double => Some(8),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Clone, Copy, Primitive)]
 pub enum ArrayType {
@@ -222,6 +334,10 @@ pub enum ArrayType {
     UInt32 = 13,
     Int64 = 14,
     UInt64 = 15,
+    // Not part of the documented format, but used by Matlab for function
+    // handles and for objects of classdef classes and types like `string`
+    Function = 16,
+    Opaque = 17,
 }
 
 impl ArrayType {
@@ -236,7 +352,7 @@ impl ArrayType {
     //     }
     // }
 
-    fn numeric_data_type(&self) -> Option<DataType> {
+    pub fn numeric_data_type(&self) -> Option<DataType> {
         match self {
             ArrayType::Double => Some(DataType::Double),
             ArrayType::Single => Some(DataType::Single),
@@ -244,7 +360,7 @@ impl ArrayType {
             ArrayType::UInt8 => Some(DataType::UInt8),
             ArrayType::Int16 => Some(DataType::Int16),
             ArrayType::UInt16 => Some(DataType::UInt16),
-            ArrayType::Int32 => Some(DataType::UInt32),
+            ArrayType::Int32 => Some(DataType::Int32),
             ArrayType::UInt32 => Some(DataType::UInt32),
             ArrayType::Int64 => Some(DataType::Int64),
             ArrayType::UInt64 => Some(DataType::UInt64),
@@ -255,14 +371,42 @@ impl ArrayType {
 
 pub type Dimensions = Vec<i32>;
 
+// The number of elements of an array with the given dimensions, `None` if a
+// dimension is negative or the number doesn't fit into a `usize`
+pub fn element_count(dimensions: &[i32]) -> Option<usize> {
+    dimensions.iter().try_fold(1usize, |count, &dimension| {
+        count.checked_mul(usize::try_from(dimension).ok()?)
+    })
+}
+
+// The number of elements of an array times `factor`, e.g. the number of
+// fields of a structure array, failing for malformed dimensions
+fn count_elements<'a>(i: &'a [u8], dimensions: &[i32], factor: usize) -> IResult<&'a [u8], usize> {
+    match element_count(dimensions).and_then(|count| count.checked_mul(factor)) {
+        Some(count) => Ok((i, count)),
+        None => Err(failure(i, ParseErrorKind::Malformed)),
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct DataElementTag {
-    data_type: DataType,
-    data_byte_size: u32,
-    padding_byte_size: u32,
+    pub data_type: DataType,
+    pub data_byte_size: u32,
+    pub padding_byte_size: u32,
 }
 
-fn parse_data_element_tag(i: &[u8], endianness: nom::Endianness) -> IResult<&[u8], DataElementTag> {
+impl DataElementTag {
+    // Whether the data is stored within the tag, using the small data element
+    // format. Long tags are always followed by a multiple of 8 bytes.
+    pub fn is_small(&self) -> bool {
+        u64::from(self.data_byte_size) + u64::from(self.padding_byte_size) == 4
+    }
+}
+
+pub fn parse_data_element_tag(
+    i: &[u8],
+    endianness: nom::Endianness,
+) -> IResult<&[u8], DataElementTag> {
     switch!(
         i,
         map!(peek!(u32!(endianness)), |b| b & 0xFFFF0000),
@@ -271,53 +415,52 @@ fn parse_data_element_tag(i: &[u8], endianness: nom::Endianness) -> IResult<&[u8
             data_type: u32!(endianness) >>
             byte_size: u32!(endianness) >>
             (DataElementTag {
-                data_type: DataType::from_u32(data_type).ok_or(nom::Err::Failure(nom::Context::Code(
-                    i,
-                    nom::ErrorKind::Custom(46)
-                )))?,
+                data_type: DataType::from_u32(data_type).ok_or(failure(i, ParseErrorKind::UnknownDataType))?,
                 data_byte_size: byte_size,
-                padding_byte_size: ceil_to_multiple(byte_size, 8) - byte_size,
+                padding_byte_size: padding_size(byte_size),
             })
         ) |
         _ => do_parse!(
             // Small Data Element Format
             data_type: map!(peek!(u32!(endianness)), |b| b & 0x0000FFFF) >>
             byte_size: map!(u32!(endianness), |b| (b & 0xFFFF0000) >> 16) >>
+            // The data has to fit into the remaining four bytes of the tag
+            apply!(assert, byte_size <= 4) >>
             (DataElementTag {
-                data_type: DataType::from_u32(data_type).ok_or(nom::Err::Failure(nom::Context::Code(
-                    i,
-                    nom::ErrorKind::Custom(46)
-                )))?,
-                // TODO: assert that byte_size is <= 4
-                data_byte_size: byte_size as u32,
-                padding_byte_size: 4 - byte_size as u32,
+                data_type: DataType::from_u32(data_type).ok_or(failure(i, ParseErrorKind::UnknownDataType))?,
+                data_byte_size: byte_size,
+                padding_byte_size: 4 - byte_size,
             })
         )
     )
 }
 
-fn parse_array_name_subelement(i: &[u8], endianness: nom::Endianness) -> IResult<&[u8], String> {
+// Decodes names, reporting invalid UTF-8 at the position of the name
+fn parse_utf8<'a>(i: &'a [u8], name: &'a [u8]) -> IResult<&'a [u8], String> {
+    match std::str::from_utf8(name) {
+        Ok(name) => Ok((i, name.to_owned())),
+        Err(_err) => Err(failure(name, ParseErrorKind::InvalidUtf8)),
+    }
+}
+
+pub fn parse_array_name_subelement(
+    i: &[u8],
+    endianness: nom::Endianness,
+) -> IResult<&[u8], String> {
     do_parse!(
         i,
         data_element_tag: apply!(parse_data_element_tag, endianness)
-            >> apply!(
-                assert,
-                data_element_tag.data_type == DataType::Int8 && data_element_tag.data_byte_size > 0
-            )
-            >> name: map_res!(take!(data_element_tag.data_byte_size), |b| {
-                std::str::from_utf8(b)
-                    .map(|s| s.to_owned())
-                    .map_err(|_err| {
-                        nom::Err::Failure(nom::Context::Code(i, nom::ErrorKind::Custom(43)))
-                    })
-            })
+            // The elements of cell arrays have empty names
+            >> apply!(assert, data_element_tag.data_type == DataType::Int8)
+            >> name: take!(data_element_tag.data_byte_size)
+            >> name: apply!(parse_utf8, name)
             // Padding bytes
             >> take!(data_element_tag.padding_byte_size)
             >> (name)
     )
 }
 
-fn parse_dimensions_array_subelement(
+pub fn parse_dimensions_array_subelement(
     i: &[u8],
     endianness: nom::Endianness,
 ) -> IResult<&[u8], Dimensions> {
@@ -341,7 +484,7 @@ fn parse_dimensions_array_subelement(
     )
 }
 
-fn parse_array_flags_subelement(
+pub fn parse_array_flags_subelement(
     i: &[u8],
     endianness: nom::Endianness,
 ) -> IResult<&[u8], ArrayFlags> {
@@ -359,89 +502,226 @@ fn parse_array_flags_subelement(
                 complex: (flags_and_class & 0x0800) != 0,
                 global: (flags_and_class & 0x0400) != 0,
                 logical: (flags_and_class & 0x0200) != 0,
-                class: ArrayType::from_u8((flags_and_class & 0xFF) as u8).ok_or(
-                    nom::Err::Failure(nom::Context::Code(i, nom::ErrorKind::Custom(44),))
-                )?,
+                class: ArrayType::from_u8((flags_and_class & 0xFF) as u8)
+                    .ok_or(failure(i, ParseErrorKind::UnknownClass))?,
                 nzmax: nzmax as usize,
             })
     )
 }
 
-fn parse_matrix_data_element(i: &[u8], endianness: nom::Endianness) -> IResult<&[u8], DataElement> {
+pub fn parse_matrix_data_element(
+    i: &[u8],
+    endianness: nom::Endianness,
+) -> IResult<&[u8], DataElement> {
+    // Empty arrays inside of cell and structure arrays are sometimes stored as
+    // matrix elements without any content
+    if i.is_empty() {
+        return Ok((i, empty_matrix_data_element()));
+    }
     do_parse!(
         i,
         flags: apply!(parse_array_flags_subelement, endianness)
             >> data_element:
                 switch!(value!(flags.class),
-                     ArrayType::Cell => apply!(parse_unsupported_data_element, endianness)
-                    | ArrayType::Struct => apply!(parse_unsupported_data_element, endianness)
-                    | ArrayType::Object => apply!(parse_unsupported_data_element, endianness)
-                    | ArrayType::Char => apply!(parse_unsupported_data_element, endianness)
+                     ArrayType::Cell => apply!(parse_cell_matrix_subelements, endianness, flags)
+                    | ArrayType::Struct => apply!(parse_structure_matrix_subelements, endianness, flags)
+                    | ArrayType::Object => apply!(parse_object_matrix_subelements, endianness, flags)
+                    | ArrayType::Char => apply!(parse_character_matrix_subelements, endianness, flags)
                     | ArrayType::Sparse => apply!(parse_sparse_matrix_subelements, endianness, flags)
+                    | ArrayType::Function | ArrayType::Opaque => apply!(parse_unsupported_matrix_subelements, endianness, flags, i)
                     | _ => apply!(parse_numeric_matrix_subelements, endianness, flags)
                 )
             >> (data_element)
     )
 }
 
-fn numeric_data_types_are_compatible(array_type: DataType, subelement_type: DataType) -> bool {
+fn empty_matrix_flags() -> ArrayFlags {
+    ArrayFlags {
+        complex: false,
+        global: false,
+        logical: false,
+        class: ArrayType::Double,
+        nzmax: 0,
+    }
+}
+
+pub fn empty_matrix_data_element() -> DataElement {
+    DataElement::NumericMatrix(
+        empty_matrix_flags(),
+        vec![0, 0],
+        String::new(),
+        NumericData::Double(Vec::new()),
+        None,
+    )
+}
+
+// Parses just the tag, array flags, dimensions and name of a matrix data
+// element, which is enough to tell what kind of variable it holds without
+// having to read its data
+pub fn parse_matrix_header(
+    i: &[u8],
+    endianness: nom::Endianness,
+) -> IResult<&[u8], (ArrayFlags, Dimensions, String)> {
+    let (i, data_element_tag) = parse_data_element_tag(i, endianness)?;
+    assert(i, data_element_tag.data_type == DataType::Matrix)?;
+    if data_element_tag.data_byte_size == 0 {
+        return Ok((i, (empty_matrix_flags(), vec![0, 0], String::new())));
+    }
+    let (i, flags) = parse_array_flags_subelement(i, endianness)?;
+    let (i, dimensions) = parse_stored_dimensions(i, endianness, flags)?;
+    let (i, name) = parse_array_name_subelement(i, endianness)?;
+    Ok((i, (flags, dimensions, name)))
+}
+
+// Opaque objects are stored without dimensions, right away followed by
+// their name
+fn parse_stored_dimensions(
+    i: &[u8],
+    endianness: nom::Endianness,
+    flags: ArrayFlags,
+) -> IResult<&[u8], Dimensions> {
+    if flags.class == ArrayType::Opaque {
+        Ok((i, Vec::new()))
+    } else {
+        parse_dimensions_array_subelement(i, endianness)
+    }
+}
+
+// Function handles and opaque objects are not decoded. Apart from their
+// dimensions and name they are kept as the `content` of their matrix element.
+fn parse_unsupported_matrix_subelements<'a>(
+    i: &'a [u8],
+    endianness: nom::Endianness,
+    flags: ArrayFlags,
+    content: &'a [u8],
+) -> IResult<&'a [u8], DataElement> {
+    let (i, dimensions) = parse_stored_dimensions(i, endianness, flags)?;
+    let (i, name) = parse_array_name_subelement(i, endianness)?;
+    Ok((
+        &i[i.len()..],
+        DataElement::UnsupportedMatrix(flags, dimensions, name, content.to_vec(), endianness),
+    ))
+}
+
+// The contents of an opaque matrix data element: its name, the type system
+// of the object (e.g. "MCOS"), its class name and a data element describing
+// the object, usually as a reference into the subsystem data
+pub fn parse_opaque_matrix(
+    i: &[u8],
+    endianness: nom::Endianness,
+) -> IResult<&[u8], (String, String, String, DataElement)> {
+    do_parse!(
+        i,
+        flags: apply!(parse_array_flags_subelement, endianness)
+            >> apply!(assert, flags.class == ArrayType::Opaque)
+            >> name: apply!(parse_array_name_subelement, endianness)
+            >> type_system: apply!(parse_array_name_subelement, endianness)
+            >> class_name: apply!(parse_array_name_subelement, endianness)
+            >> data_element: apply!(parse_next_data_element, endianness)
+            >> ((name, type_system, class_name, data_element))
+    )
+}
+
+// The numbers of a numeric matrix data element, left as the bytes they are
+// stored as
+pub struct RawNumericMatrix<'a> {
+    pub flags: ArrayFlags,
+    pub dimensions: Dimensions,
+    pub name: String,
+    pub real: (DataType, &'a [u8]),
+    pub imag: Option<(DataType, &'a [u8])>,
+}
+
+fn parse_raw_numeric_subelement(
+    i: &[u8],
+    endianness: nom::Endianness,
+) -> IResult<&[u8], (DataType, &[u8])> {
+    do_parse!(
+        i,
+        data_element_tag: apply!(parse_data_element_tag, endianness)
+            >> data: take!(data_element_tag.data_byte_size)
+            // Padding bytes
+            >> take!(data_element_tag.padding_byte_size)
+            >> ((data_element_tag.data_type, data))
+    )
+}
+
+// Parses an uncompressed numeric matrix data element, including its tag,
+// without copying its numbers. Returns `None` for other kinds of variables.
+pub fn parse_raw_numeric_matrix(
+    i: &[u8],
+    endianness: nom::Endianness,
+) -> IResult<&[u8], Option<RawNumericMatrix<'_>>> {
+    let (i, data_element_tag) = parse_data_element_tag(i, endianness)?;
+    if data_element_tag.data_type != DataType::Matrix || data_element_tag.data_byte_size == 0 {
+        return Ok((i, None));
+    }
+    let (i, flags) = parse_array_flags_subelement(i, endianness)?;
+    if flags.class.numeric_data_type().is_none() {
+        return Ok((i, None));
+    }
+    do_parse!(
+        i,
+        dimensions: apply!(parse_dimensions_array_subelement, endianness)
+            >> name: apply!(parse_array_name_subelement, endianness)
+            >> real: apply!(parse_raw_numeric_subelement, endianness)
+            >> imag: cond!(flags.complex, apply!(parse_raw_numeric_subelement, endianness))
+            >> (Some(RawNumericMatrix {
+                flags,
+                dimensions,
+                name,
+                real,
+                imag,
+            }))
+    )
+}
+
+pub fn numeric_data_types_are_compatible(array_type: DataType, subelement_type: DataType) -> bool {
     match array_type {
-        DataType::Int8 => match subelement_type {
-            DataType::Int8 => true,
-            _ => false,
-        },
-        DataType::UInt8 => match subelement_type {
-            DataType::UInt8 => true,
-            _ => false,
-        },
-        DataType::Int16 => match subelement_type {
-            DataType::UInt8 | DataType::Int16 => true,
-            _ => false,
-        },
-        DataType::UInt16 => match subelement_type {
-            DataType::UInt8 | DataType::UInt16 => true,
-            _ => false,
-        },
-        DataType::Int32 => match subelement_type {
-            DataType::UInt8 | DataType::Int16 | DataType::UInt16 | DataType::Int32 => true,
-            _ => false,
-        },
-        DataType::UInt32 => match subelement_type {
-            DataType::UInt8 | DataType::Int16 | DataType::UInt16 | DataType::UInt32 => true,
-            _ => false,
-        },
-        DataType::Int64 => match subelement_type {
+        DataType::Int8 => matches!(subelement_type, DataType::Int8),
+        DataType::UInt8 => matches!(subelement_type, DataType::UInt8),
+        DataType::Int16 => matches!(subelement_type, DataType::UInt8 | DataType::Int16),
+        DataType::UInt16 => matches!(subelement_type, DataType::UInt8 | DataType::UInt16),
+        DataType::Int32 => matches!(
+            subelement_type,
+            DataType::UInt8 | DataType::Int16 | DataType::UInt16 | DataType::Int32
+        ),
+        DataType::UInt32 => matches!(
+            subelement_type,
+            DataType::UInt8 | DataType::Int16 | DataType::UInt16 | DataType::UInt32
+        ),
+        DataType::Int64 => matches!(
+            subelement_type,
             DataType::UInt8
-            | DataType::Int16
-            | DataType::UInt16
-            | DataType::Int32
-            | DataType::Int64 => true,
-            _ => false,
-        },
-        DataType::UInt64 => match subelement_type {
+                | DataType::Int16
+                | DataType::UInt16
+                | DataType::Int32
+                | DataType::Int64
+        ),
+        DataType::UInt64 => matches!(
+            subelement_type,
             DataType::UInt8
-            | DataType::Int16
-            | DataType::UInt16
-            | DataType::Int32
-            | DataType::UInt64 => true,
-            _ => false,
-        },
-        DataType::Single => match subelement_type {
+                | DataType::Int16
+                | DataType::UInt16
+                | DataType::Int32
+                | DataType::UInt64
+        ),
+        DataType::Single => matches!(
+            subelement_type,
             DataType::UInt8
-            | DataType::Int16
-            | DataType::UInt16
-            | DataType::Int32
-            | DataType::Single => true,
-            _ => false,
-        },
-        DataType::Double => match subelement_type {
+                | DataType::Int16
+                | DataType::UInt16
+                | DataType::Int32
+                | DataType::Single
+        ),
+        DataType::Double => matches!(
+            subelement_type,
             DataType::UInt8
-            | DataType::Int16
-            | DataType::UInt16
-            | DataType::Int32
-            | DataType::Double => true,
-            _ => false,
-        },
+                | DataType::Int16
+                | DataType::UInt16
+                | DataType::Int32
+                | DataType::Double
+        ),
         _ => false,
     }
 }
@@ -451,47 +731,11 @@ fn parse_numeric_subelement(i: &[u8], endianness: nom::Endianness) -> IResult<&[
         i,
         data_element_tag: apply!(parse_data_element_tag, endianness)
             >> numeric_data:
-                switch!(value!(data_element_tag.data_type),
-                    DataType::Int8 => map!(switch!(value!(endianness),
-                        nom::Endianness::Big => count!(be_i8, data_element_tag.data_byte_size as usize) |
-                        nom::Endianness::Little => count!(le_i8, data_element_tag.data_byte_size as usize)
-                    ), |data| NumericData::Int8(data)) |
-                    DataType::UInt8 => map!(switch!(value!(endianness),
-                        nom::Endianness::Big => count!(be_u8, data_element_tag.data_byte_size as usize) |
-                        nom::Endianness::Little => count!(le_u8, data_element_tag.data_byte_size as usize)
-                    ), |data| NumericData::UInt8(data)) |
-                    DataType::Int16 => map!(switch!(value!(endianness),
-                        nom::Endianness::Big => count!(be_i16, data_element_tag.data_byte_size as usize / 2) |
-                        nom::Endianness::Little => count!(le_i16, data_element_tag.data_byte_size as usize / 2)
-                    ), |data| NumericData::Int16(data)) |
-                    DataType::UInt16 => map!(switch!(value!(endianness),
-                        nom::Endianness::Big => count!(be_u16, data_element_tag.data_byte_size as usize / 2) |
-                        nom::Endianness::Little => count!(le_u16, data_element_tag.data_byte_size as usize / 2)
-                    ), |data| NumericData::UInt16(data)) |
-                    DataType::Int32 => map!(switch!(value!(endianness),
-                        nom::Endianness::Big => count!(be_i32, data_element_tag.data_byte_size as usize / 4) |
-                        nom::Endianness::Little => count!(le_i32, data_element_tag.data_byte_size as usize / 4)
-                    ), |data| NumericData::Int32(data)) |
-                    DataType::UInt32 => map!(switch!(value!(endianness),
-                        nom::Endianness::Big => count!(be_u32, data_element_tag.data_byte_size as usize / 4) |
-                        nom::Endianness::Little => count!(le_u32, data_element_tag.data_byte_size as usize / 4)
-                    ), |data| NumericData::UInt32(data)) |
-                    DataType::Int64 => map!(switch!(value!(endianness),
-                        nom::Endianness::Big => count!(be_i64, data_element_tag.data_byte_size as usize / 8) |
-                        nom::Endianness::Little => count!(le_i64, data_element_tag.data_byte_size as usize / 8)
-                    ), |data| NumericData::Int64(data)) |
-                    DataType::UInt64 => map!(switch!(value!(endianness),
-                        nom::Endianness::Big => count!(be_u64, data_element_tag.data_byte_size as usize / 8) |
-                        nom::Endianness::Little => count!(le_u64, data_element_tag.data_byte_size as usize / 8)
-                    ), |data| NumericData::UInt64(data)) |
-                    DataType::Single => map!(switch!(value!(endianness),
-                        nom::Endianness::Big => count!(be_f32, data_element_tag.data_byte_size as usize / 4) |
-                        nom::Endianness::Little => count!(le_f32, data_element_tag.data_byte_size as usize / 4)
-                    ), |data| NumericData::Single(data)) |
-                    DataType::Double => map!(switch!(value!(endianness),
-                        nom::Endianness::Big => count!(be_f64, data_element_tag.data_byte_size as usize / 8) |
-                        nom::Endianness::Little => count!(le_f64, data_element_tag.data_byte_size as usize / 8)
-                    ), |data| NumericData::Double(data))
+                apply!(
+                    parse_numeric_data,
+                    endianness,
+                    data_element_tag.data_type,
+                    data_element_tag.data_byte_size as usize
                 )
             // Padding bytes
             >> take!(data_element_tag.padding_byte_size)
@@ -499,28 +743,98 @@ fn parse_numeric_subelement(i: &[u8], endianness: nom::Endianness) -> IResult<&[
     )
 }
 
+// Parses `byte_size` bytes worth of numbers of the given type
+pub fn parse_numeric_data(
+    i: &[u8],
+    endianness: nom::Endianness,
+    data_type: DataType,
+    byte_size: usize,
+) -> IResult<&[u8], NumericData> {
+    switch!(i, value!(data_type),
+        DataType::Int8 => map!(switch!(value!(endianness),
+            nom::Endianness::Big => count!(be_i8, byte_size) |
+            nom::Endianness::Little => count!(le_i8, byte_size)
+        ), NumericData::Int8) |
+        DataType::UInt8 => map!(switch!(value!(endianness),
+            nom::Endianness::Big => count!(be_u8, byte_size) |
+            nom::Endianness::Little => count!(le_u8, byte_size)
+        ), NumericData::UInt8) |
+        DataType::Int16 => map!(switch!(value!(endianness),
+            nom::Endianness::Big => count!(be_i16, byte_size / 2) |
+            nom::Endianness::Little => count!(le_i16, byte_size / 2)
+        ), NumericData::Int16) |
+        DataType::UInt16 => map!(switch!(value!(endianness),
+            nom::Endianness::Big => count!(be_u16, byte_size / 2) |
+            nom::Endianness::Little => count!(le_u16, byte_size / 2)
+        ), NumericData::UInt16) |
+        DataType::Int32 => map!(switch!(value!(endianness),
+            nom::Endianness::Big => count!(be_i32, byte_size / 4) |
+            nom::Endianness::Little => count!(le_i32, byte_size / 4)
+        ), NumericData::Int32) |
+        DataType::UInt32 => map!(switch!(value!(endianness),
+            nom::Endianness::Big => count!(be_u32, byte_size / 4) |
+            nom::Endianness::Little => count!(le_u32, byte_size / 4)
+        ), NumericData::UInt32) |
+        DataType::Int64 => map!(switch!(value!(endianness),
+            nom::Endianness::Big => count!(be_i64, byte_size / 8) |
+            nom::Endianness::Little => count!(le_i64, byte_size / 8)
+        ), NumericData::Int64) |
+        DataType::UInt64 => map!(switch!(value!(endianness),
+            nom::Endianness::Big => count!(be_u64, byte_size / 8) |
+            nom::Endianness::Little => count!(le_u64, byte_size / 8)
+        ), NumericData::UInt64) |
+        DataType::Single => map!(switch!(value!(endianness),
+            nom::Endianness::Big => count!(be_f32, byte_size / 4) |
+            nom::Endianness::Little => count!(le_f32, byte_size / 4)
+        ), NumericData::Single) |
+        DataType::Double => map!(switch!(value!(endianness),
+            nom::Endianness::Big => count!(be_f64, byte_size / 8) |
+            nom::Endianness::Little => count!(le_f64, byte_size / 8)
+        ), NumericData::Double)
+    )
+}
+
 fn parse_compressed_data_element(
     i: &[u8],
     endianness: nom::Endianness,
 ) -> IResult<&[u8], DataElement> {
-    let mut buf = Vec::new();
-    Decoder::new(i)
-        .map_err(|err| {
-            eprintln!("{:?}", err);
-            nom::Err::Failure(nom::Context::Code(i, nom::ErrorKind::Custom(41)))
-        })?
-        .read_to_end(&mut buf)
-        .map_err(|err| {
-            eprintln!("{:?}", err);
-            nom::Err::Failure(nom::Context::Code(i, nom::ErrorKind::Custom(42)))
+    // The element is decoded while it is being inflated, so the inflated data
+    // never has to be held in memory as a whole
+    let mut decoder = Decoder::new(i).map_err(|_err| failure(i, ParseErrorKind::Decompression))?;
+    let data_element =
+        stream::read_inflated_data_element(&mut decoder, endianness).map_err(|err| match err {
+            Error::ParseError(err) => failure(i, err.kind()),
+            Error::IOError(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                failure(i, ParseErrorKind::Truncated)
+            }
+            _ => failure(i, ParseErrorKind::Decompression),
         })?;
-    let (_remaining, data_element) = parse_next_data_element(buf.as_slice(), endianness)
-        .map_err(|err| replace_err_slice(err, i))?;
     Ok((&[], data_element))
 }
 
-pub type RowIndex = Vec<usize>;
-pub type ColumnShift = Vec<usize>;
+// Checked against the size of the array and converted to `usize` when the
+// variable is built
+pub type RowIndex = Vec<i32>;
+pub type ColumnShift = Vec<i32>;
+pub type CharacterData = Vec<u16>;
+pub type FieldNames = Vec<String>;
+
+// Parses the real or imaginary part of an array and checks that it holds
+// `len` numbers of a type that fits the class of the array, if that is known.
+// Mismatches are reported at the position of the part.
+fn parse_array_part(
+    i: &[u8],
+    endianness: nom::Endianness,
+    len: usize,
+    array_data_type: Option<DataType>,
+) -> IResult<&[u8], NumericData> {
+    let (remaining, part) = parse_numeric_subelement(i, endianness)?;
+    let compatible = array_data_type.is_none_or(|array_data_type| {
+        numeric_data_types_are_compatible(array_data_type, part.data_type())
+    });
+    assert_size(i, part.len() == len && compatible)?;
+    Ok((remaining, part))
+}
 
 fn parse_numeric_matrix_subelements(
     i: &[u8],
@@ -532,21 +846,11 @@ fn parse_numeric_matrix_subelements(
         dimensions: apply!(parse_dimensions_array_subelement, endianness)
             >> name: apply!(parse_array_name_subelement, endianness)
 
-            >> real_part: apply!(parse_numeric_subelement, endianness)
-            // Check that size and type of the real part are correct
-            >> n_required_elements: value!(dimensions.iter().product::<i32>())
-            >> array_data_type: value!(flags.class.numeric_data_type().unwrap())
-            >> apply!(assert, real_part.len() == n_required_elements as usize && numeric_data_types_are_compatible(array_data_type, real_part.data_type()))
-
-            >> imag_part: cond!(flags.complex, apply!(parse_numeric_subelement, endianness))
-            // Check that size and type of imaginary part are correct if present
-            >> apply!(assert,
-                if let Some(imag_part) = &imag_part {
-                    imag_part.len() == n_required_elements as usize && numeric_data_types_are_compatible(array_data_type, imag_part.data_type())
-                } else {
-                    true
-                }
-            )
+            // Check that size and type of the real and imaginary parts are correct
+            >> n_required_elements: apply!(count_elements, &dimensions, 1)
+            >> array_data_type: value!(flags.class.numeric_data_type())
+            >> real_part: apply!(parse_array_part, endianness, n_required_elements, array_data_type)
+            >> imag_part: cond!(flags.complex, apply!(parse_array_part, endianness, n_required_elements, array_data_type))
 
             >> (DataElement::NumericMatrix(
                 flags, dimensions, name, real_part, imag_part
@@ -564,104 +868,232 @@ fn parse_sparse_matrix_subelements(
         i,
         dimensions: apply!(parse_dimensions_array_subelement, endianness)
             >> name: apply!(parse_array_name_subelement, endianness)
+            >> apply!(count_elements, &dimensions, 1)
             >> row_index: apply!(parse_row_index_array_subelement, endianness)
             >> column_index: apply!(parse_column_index_array_subelement, endianness)
 
-            >> real_part: apply!(parse_numeric_subelement, endianness)
-            // Check that size of the real part is correct (can't check for type in sparse matrices)
-            >> apply!(assert, real_part.len() == flags.nzmax)
-
-            >> imag_part: cond!(flags.complex, apply!(parse_numeric_subelement, endianness))
-            // Check that size of the imaginary part is correct if present (can't check for type in sparse matrices)
-            >> apply!(assert,
-                if let Some(imag_part) = &imag_part {
-                    imag_part.len() == flags.nzmax as usize
-                } else {
-                    true
-                }
-            )
+            // Check that size of the real and imaginary parts is correct (can't check for type in sparse matrices)
+            >> real_part: apply!(parse_array_part, endianness, flags.nzmax, None)
+            >> imag_part: cond!(flags.complex, apply!(parse_array_part, endianness, flags.nzmax, None))
 
             >> (DataElement::SparseMatrix(
                 flags,
                 dimensions,
                 name,
-                row_index.iter().map(|&i| i as usize).collect(),
-                column_index.iter().map(|&i| i as usize).collect(),
+                row_index,
+                column_index,
                 real_part,
                 imag_part
             ))
     )
 }
 
-fn parse_row_index_array_subelement(
+fn parse_character_matrix_subelements(
     i: &[u8],
     endianness: nom::Endianness,
-) -> IResult<&[u8], RowIndex> {
+    flags: ArrayFlags,
+) -> IResult<&[u8], DataElement> {
     do_parse!(
         i,
-        data_element_tag: apply!(parse_data_element_tag, endianness)
+        dimensions: apply!(parse_dimensions_array_subelement, endianness)
+            >> name: apply!(parse_array_name_subelement, endianness)
+            >> n_required_elements: apply!(count_elements, &dimensions, 1)
+            >> data: apply!(parse_character_data, endianness, n_required_elements)
+            >> (DataElement::CharacterMatrix(flags, dimensions, name, data))
+    )
+}
+
+// Checks that the number of UTF-16 code units matches the dimensions
+fn parse_character_data(
+    i: &[u8],
+    endianness: nom::Endianness,
+    len: usize,
+) -> IResult<&[u8], CharacterData> {
+    let (remaining, data) = parse_character_subelement(i, endianness)?;
+    assert_size(i, data.len() == len)?;
+    Ok((remaining, data))
+}
+
+fn parse_cell_matrix_subelements(
+    i: &[u8],
+    endianness: nom::Endianness,
+    flags: ArrayFlags,
+) -> IResult<&[u8], DataElement> {
+    do_parse!(
+        i,
+        dimensions: apply!(parse_dimensions_array_subelement, endianness)
+            >> name: apply!(parse_array_name_subelement, endianness)
+            // Each cell is stored as a full matrix element of its own
+            >> n_required_elements: apply!(count_elements, &dimensions, 1)
+            >> cells: count!(apply!(parse_next_data_element, endianness), n_required_elements)
+            >> (DataElement::CellMatrix(flags, dimensions, name, cells))
+    )
+}
+
+fn parse_structure_matrix_subelements(
+    i: &[u8],
+    endianness: nom::Endianness,
+    flags: ArrayFlags,
+) -> IResult<&[u8], DataElement> {
+    do_parse!(
+        i,
+        dimensions: apply!(parse_dimensions_array_subelement, endianness)
+            >> name: apply!(parse_array_name_subelement, endianness)
+            >> field_names: apply!(parse_field_names_subelements, endianness)
+            // The values are stored element by element and within each
+            // element field by field
+            >> n_required_values: apply!(count_elements, &dimensions, field_names.len())
+            >> values: count!(apply!(parse_next_data_element, endianness), n_required_values)
+            >> (DataElement::StructureMatrix(flags, dimensions, name, field_names, values))
+    )
+}
+
+fn parse_object_matrix_subelements(
+    i: &[u8],
+    endianness: nom::Endianness,
+    flags: ArrayFlags,
+) -> IResult<&[u8], DataElement> {
+    // Objects are stored like structures with an additional class name
+    do_parse!(
+        i,
+        dimensions: apply!(parse_dimensions_array_subelement, endianness)
+            >> name: apply!(parse_array_name_subelement, endianness)
+            >> class_name: apply!(parse_array_name_subelement, endianness)
+            >> field_names: apply!(parse_field_names_subelements, endianness)
+            >> n_required_values: apply!(count_elements, &dimensions, field_names.len())
+            >> values: count!(apply!(parse_next_data_element, endianness), n_required_values)
+            >> (DataElement::ObjectMatrix(
+                flags,
+                dimensions,
+                name,
+                class_name,
+                field_names,
+                values
+            ))
+    )
+}
+
+pub fn parse_field_names_subelements(
+    i: &[u8],
+    endianness: nom::Endianness,
+) -> IResult<&[u8], FieldNames> {
+    do_parse!(
+        i,
+        // Field name length subelement
+        length_tag: apply!(parse_data_element_tag, endianness)
             >> apply!(
                 assert,
-                data_element_tag.data_type == DataType::Int32
-                    && data_element_tag.data_byte_size > 0
+                length_tag.data_type == DataType::Int32 && length_tag.data_byte_size == 4
             )
+            >> field_name_length: i32!(endianness)
+            >> take!(length_tag.padding_byte_size)
+            // Field names subelement
+            >> names_tag: apply!(parse_data_element_tag, endianness)
+            >> apply!(
+                assert,
+                names_tag.data_type == DataType::Int8
+                    && field_name_length > 0
+                    && names_tag.data_byte_size % field_name_length as u32 == 0
+            )
+            >> names: take!(names_tag.data_byte_size)
+            >> field_names: apply!(parse_field_names, names, field_name_length as usize)
+            >> take!(names_tag.padding_byte_size)
+            >> (field_names)
+    )
+}
+
+// Names are null terminated within their fixed size slots
+fn parse_field_names<'a>(
+    i: &'a [u8],
+    names: &'a [u8],
+    field_name_length: usize,
+) -> IResult<&'a [u8], FieldNames> {
+    let field_names = names
+        .chunks(field_name_length)
+        .map(|chunk| {
+            let end = chunk.iter().position(|&c| c == 0).unwrap_or(chunk.len());
+            parse_utf8(i, &chunk[..end]).map(|(_i, name)| name)
+        })
+        .collect::<Result<FieldNames, _>>()?;
+    Ok((i, field_names))
+}
+
+fn decode_utf32(code_points: Vec<u32>) -> Result<CharacterData, ()> {
+    let mut data = Vec::with_capacity(code_points.len());
+    let mut buf = [0; 2];
+    for code_point in code_points {
+        let c = std::char::from_u32(code_point).ok_or(())?;
+        data.extend_from_slice(c.encode_utf16(&mut buf));
+    }
+    Ok(data)
+}
+
+// Matlab characters are UTF-16 code units, so that is what all the possible
+// storage formats get converted to.
+pub fn parse_character_subelement(
+    i: &[u8],
+    endianness: nom::Endianness,
+) -> IResult<&[u8], CharacterData> {
+    do_parse!(
+        i,
+        data_element_tag: apply!(parse_data_element_tag, endianness)
+            >> data:
+                switch!(value!(data_element_tag.data_type),
+                    DataType::Utf8 => map_res!(take!(data_element_tag.data_byte_size), |b| {
+                        std::str::from_utf8(b).map(|s| s.encode_utf16().collect())
+                    }) |
+                    DataType::UInt8 => map!(take!(data_element_tag.data_byte_size), |b: &[u8]| {
+                        b.iter().map(|&c| u16::from(c)).collect()
+                    }) |
+                    DataType::Utf16 => count!(u16!(endianness), data_element_tag.data_byte_size as usize / 2) |
+                    DataType::UInt16 => count!(u16!(endianness), data_element_tag.data_byte_size as usize / 2) |
+                    DataType::Utf32 => map_res!(
+                        count!(u32!(endianness), data_element_tag.data_byte_size as usize / 4),
+                        decode_utf32
+                    )
+                )
+            // Padding bytes
+            >> take!(data_element_tag.padding_byte_size)
+            >> (data)
+    )
+}
+
+pub fn parse_row_index_array_subelement(
+    i: &[u8],
+    endianness: nom::Endianness,
+) -> IResult<&[u8], RowIndex> {
+    do_parse!(
+        i,
+        data_element_tag: apply!(parse_data_element_tag, endianness)
+            >> apply!(assert, data_element_tag.data_type == DataType::Int32)
             >> row_index:
                 count!(
                     i32!(endianness),
                     (data_element_tag.data_byte_size / 4) as usize
                 )
             >> take!(data_element_tag.padding_byte_size)
-            >> (row_index.iter().map(|&i| i as usize).collect())
+            >> (row_index)
     )
 }
 
-fn parse_column_index_array_subelement(
+pub fn parse_column_index_array_subelement(
     i: &[u8],
     endianness: nom::Endianness,
 ) -> IResult<&[u8], ColumnShift> {
     do_parse!(
         i,
         data_element_tag: apply!(parse_data_element_tag, endianness)
-            >> apply!(
-                assert,
-                data_element_tag.data_type == DataType::Int32
-                    && data_element_tag.data_byte_size > 0
-            )
+            >> apply!(assert, data_element_tag.data_type == DataType::Int32)
             >> column_index:
                 count!(
                     i32!(endianness),
                     (data_element_tag.data_byte_size / 4) as usize
                 )
             >> take!(data_element_tag.padding_byte_size)
-            >> (column_index.iter().map(|&i| i as usize).collect())
+            >> (column_index)
     )
 }
 
-fn replace_context_slice<'old, 'new, E>(
-    context: nom::Context<&'old [u8], E>,
-    new_slice: &'new [u8],
-) -> nom::Context<&'new [u8], E> {
-    match context {
-        nom::Context::Code(_, error_kind) => nom::Context::Code(new_slice, error_kind),
-        nom::Context::List(list) => nom::Context::List(
-            list.into_iter()
-                .map(|(_, error_kind)| (new_slice, error_kind))
-                .collect(),
-        ),
-    }
-}
-
-pub fn replace_err_slice<'old, 'new, E>(
-    err: nom::Err<&'old [u8], E>,
-    new_slice: &'new [u8],
-) -> nom::Err<&'new [u8], E> {
-    match err {
-        nom::Err::Error(context) => nom::Err::Error(replace_context_slice(context, new_slice)),
-        nom::Err::Failure(context) => nom::Err::Failure(replace_context_slice(context, new_slice)),
-        nom::Err::Incomplete(needed) => nom::Err::Incomplete(needed),
-    }
-}
-
 fn parse_unsupported_data_element(
     _i: &[u8],
     _endianness: nom::Endianness,
@@ -669,40 +1101,178 @@ fn parse_unsupported_data_element(
     Ok((&[], DataElement::Unsupported))
 }
 
+// The data elements at the top level of a file along with their offsets.
+// Elements that can't be parsed are left out with `OnError::Skip`, keeping
+// their errors instead.
+#[derive(Debug, Default)]
+pub struct DataElements<'a> {
+    pub parsed: Vec<(usize, DataElement)>,
+    pub errors: Vec<nom::Err<&'a [u8], u32>>,
+}
+
+impl<'a> DataElements<'a> {
+    // Adds the result of parsing an element, failing on errors unless they
+    // are to be skipped
+    pub fn add(
+        &mut self,
+        offset: usize,
+        result: Result<DataElement, nom::Err<&'a [u8], u32>>,
+        on_error: OnError,
+    ) -> Result<(), nom::Err<&'a [u8], u32>> {
+        match result {
+            // Not worth a warning, unlike other elements that aren't variables
+            Ok(DataElement::Filler) => {}
+            Ok(data_element) => self.parsed.push((offset, data_element)),
+            Err(err) if on_error == OnError::Skip => self.errors.push(err),
+            Err(err) => return Err(err),
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
-pub struct ParseResult {
+pub struct ParseResult<'a> {
     pub header: Header,
-    pub data_elements: Vec<DataElement>,
+    pub data_elements: DataElements<'a>,
 }
 
-pub fn parse_all(i: &[u8]) -> IResult<&[u8], ParseResult> {
-    do_parse!(
-        i,
-        header: parse_header
-            >> endianness: value!(if header.is_little_endian {
-                nom::Endianness::Little
-            } else {
-                nom::Endianness::Big
-            })
-            >> data_elements: many0!(complete!(apply!(parse_next_data_element, endianness)))
-            >> (ParseResult {
-                header: header,
-                data_elements: data_elements,
-            })
-    )
+pub fn parse_all(i: &[u8], on_error: OnError) -> IResult<&[u8], ParseResult<'_>> {
+    let (remaining, header) = parse_header(i)?;
+    let endianness = if header.is_little_endian {
+        nom::Endianness::Little
+    } else {
+        nom::Endianness::Big
+    };
+    let base = i.len() - remaining.len();
+    let (remaining, data_elements) = parse_data_elements(remaining, endianness, base, on_error)?;
+    Ok((
+        remaining,
+        ParseResult {
+            header,
+            data_elements,
+        },
+    ))
+}
+
+// Parses a data element at the top level of a file, which has to be complete
+fn parse_top_level_data_element(
+    i: &[u8],
+    endianness: nom::Endianness,
+) -> IResult<&[u8], DataElement> {
+    parse_next_data_element(i, endianness).map_err(|err| match err {
+        nom::Err::Incomplete(_) => failure(i, ParseErrorKind::Truncated),
+        err => err,
+    })
+}
+
+// The size of the data element at the start of `i` according to its tag,
+// including the padding. `None` if the tag is invalid.
+fn data_element_size(i: &[u8], endianness: nom::Endianness) -> Option<usize> {
+    let (_remaining, data_element_tag) = parse_data_element_tag(i, endianness).ok()?;
+    let mut byte_size = 8 + data_element_tag.data_byte_size as usize;
+    if data_element_tag.data_type != DataType::Compressed {
+        byte_size += data_element_tag.padding_byte_size as usize;
+    }
+    Some(byte_size)
+}
+
+// Data elements are parsed one after the other. Elements that can't be
+// parsed are skipped by the size in their tag, unless the tag is broken too.
+#[cfg(not(feature = "rayon"))]
+fn parse_data_elements(
+    i: &[u8],
+    endianness: nom::Endianness,
+    base: usize,
+    on_error: OnError,
+) -> IResult<&[u8], DataElements<'_>> {
+    let mut data_elements = DataElements::default();
+    let mut remaining = i;
+    while !remaining.is_empty() {
+        let offset = base + i.len() - remaining.len();
+        let (result, next) = match parse_top_level_data_element(remaining, endianness) {
+            Ok((next, data_element)) => (Ok(data_element), next),
+            Err(err) => {
+                let byte_size = data_element_size(remaining, endianness).unwrap_or(remaining.len());
+                (Err(err), &remaining[byte_size.min(remaining.len())..])
+            }
+        };
+        data_elements.add(offset, result, on_error)?;
+        remaining = next;
+    }
+    Ok((remaining, data_elements))
+}
+
+// Splits the data elements at the top level of a file without parsing them.
+// The last element may be incomplete. Also returns whatever follows the last
+// element with a valid tag.
+#[cfg(feature = "rayon")]
+fn split_data_elements(mut i: &[u8], endianness: nom::Endianness) -> (Vec<&[u8]>, &[u8]) {
+    let mut data_elements = Vec::new();
+    while let Some(byte_size) = data_element_size(i, endianness) {
+        let (data_element, remaining) = i.split_at(byte_size.min(i.len()));
+        data_elements.push(data_element);
+        i = remaining;
+    }
+    (data_elements, i)
+}
+
+// Every data element at the top level of a file can be parsed on its own, so
+// once their boundaries are known they are parsed (and inflated) in parallel
+#[cfg(feature = "rayon")]
+fn parse_data_elements(
+    i: &[u8],
+    endianness: nom::Endianness,
+    base: usize,
+    on_error: OnError,
+) -> IResult<&[u8], DataElements<'_>> {
+    use rayon::prelude::*;
+    let (split, remaining) = split_data_elements(i, endianness);
+    let results: Vec<_> = split
+        .into_par_iter()
+        .map(|data_element| {
+            let offset = base + data_element.as_ptr() as usize - i.as_ptr() as usize;
+            let result = parse_top_level_data_element(data_element, endianness)
+                .map(|(_remaining, data_element)| data_element);
+            (offset, result)
+        })
+        .collect();
+    let mut data_elements = DataElements::default();
+    for (offset, result) in results {
+        data_elements.add(offset, result, on_error)?;
+    }
+    // Whatever follows can't be a valid data element
+    if !remaining.is_empty() {
+        let offset = base + i.len() - remaining.len();
+        let result = parse_top_level_data_element(remaining, endianness)
+            .map(|(_remaining, data_element)| data_element);
+        data_elements.add(offset, result, on_error)?;
+    }
+    Ok((&i[i.len()..], data_elements))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn hdf5_detection() {
+        let mut data = vec![b' '; 520];
+        data[124..128].copy_from_slice(b"\x00\x02IM");
+        assert!(is_hdf5_mat_file(&data));
+        data[124..128].copy_from_slice(b"\x00\x00\x00\x00");
+        assert!(!is_hdf5_mat_file(&data));
+        data[512..520].copy_from_slice(b"\x89HDF\r\n\x1a\n");
+        assert!(is_hdf5_mat_file(&data));
+        assert!(!is_hdf5_mat_file(include_bytes!("../tests/double.mat")));
+    }
+
     #[test]
     fn sparse1() {
         let data = include_bytes!("../tests/sparse1.mat");
 
-        let (_, parsed_data) = parse_all(data).unwrap();
-        let parsed_matrix_data = parsed_data.data_elements[0].clone();
-        if let DataElement::SparseMatrix(flags, dim, name, irows, icols, real_vals, imag_vals) =
+        let (_, parsed_data) = parse_all(data, OnError::Fail).unwrap();
+        let parsed_matrix_data = parsed_data.data_elements.parsed[0].1.clone();
+        if let DataElement::SparseMatrix(_flags, dim, _name, irows, icols, real_vals, imag_vals) =
             parsed_matrix_data
         {
             assert_eq!(dim, vec![8, 8]);
@@ -722,9 +1292,9 @@ mod test {
     fn sparse2() {
         let data = include_bytes!("../tests/sparse2.mat");
 
-        let (_, parsed_data) = parse_all(data).unwrap();
-        let parsed_matrix_data = parsed_data.data_elements[0].clone();
-        if let DataElement::SparseMatrix(flags, dim, name, irows, icols, real_vals, imag_vals) =
+        let (_, parsed_data) = parse_all(data, OnError::Fail).unwrap();
+        let parsed_matrix_data = parsed_data.data_elements.parsed[0].1.clone();
+        if let DataElement::SparseMatrix(_flags, dim, _name, irows, icols, real_vals, imag_vals) =
             parsed_matrix_data
         {
             assert_eq!(dim, vec![8, 8]);
@@ -744,4 +1314,47 @@ mod test {
             panic!("Error extracting DataElement::SparseMatrix");
         }
     }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel() {
+        use crate::{Compression, MatFile, MatFileBuilder, WriteOptions};
+
+        let files: [&[u8]; 3] = [
+            include_bytes!("../tests/struct.mat"),
+            include_bytes!("../tests/two_arrays.mat"),
+            include_bytes!("../tests/sparse1.mat"),
+        ];
+        // The variables of all files, compressed into one file
+        let mut builder = MatFileBuilder::new();
+        for data in files.iter() {
+            for variable in MatFile::parse(*data).unwrap().variables() {
+                builder = builder.add_variable(variable.clone());
+            }
+        }
+        let mut compressed = Vec::new();
+        let options = WriteOptions::new().compression(Compression::Default);
+        builder
+            .build()
+            .unwrap()
+            .write_with_options(&mut compressed, &options)
+            .unwrap();
+        let files = [files[0], files[1], files[2], &compressed];
+        for data in files.iter() {
+            let (_remaining, parse_result) = parse_all(data, OnError::Fail).unwrap();
+            assert!(!parse_result.data_elements.parsed.is_empty());
+            let (_remaining, sequential) = nom::many0!(
+                &data[128..],
+                complete!(apply!(parse_next_data_element, nom::Endianness::Little))
+            )
+            .unwrap();
+            let parallel: Vec<_> = parse_result
+                .data_elements
+                .parsed
+                .into_iter()
+                .map(|(_offset, data_element)| data_element)
+                .collect();
+            assert_eq!(parallel, sequential);
+        }
+    }
 }
@@ -0,0 +1,434 @@
+use crate::parse::{
+    assert, failure, parse_numeric_data, ArrayFlags, ArrayType, DataElement, DataElements,
+    DataType, NumericData,
+};
+use crate::{OnError, ParseErrorKind};
+use nom::{apply, be_i32, cond, do_parse, i32, le_i32, map_res, peek, take, IResult};
+
+// Level 4 MAT-files are described in the MAT-file format documentation of
+// Matlab versions up to R2015a (and in the "MAT-File Format" section of the
+// External Interfaces Guide of even older versions).
+
+// Level 4 MAT-files don't have a header. Instead they start right away with
+// the type field of the first matrix, which is small enough to always
+// contain a zero byte. The first four bytes of a Level 5 MAT-file are text.
+pub fn is_level4_mat_file(i: &[u8]) -> bool {
+    i.len() >= 4 && i[..4].contains(&0)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MatrixType {
+    Full,
+    Text,
+    Sparse,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MatrixHeader {
+    pub endianness: nom::Endianness,
+    pub precision: DataType,
+    pub matrix_type: MatrixType,
+    pub rows: usize,
+    pub columns: usize,
+    pub complex: bool,
+    pub name_length: usize,
+}
+
+// The size of the fixed part of the matrix header
+pub const MATRIX_HEADER_SIZE: usize = 20;
+
+impl MatrixHeader {
+    // The number of bytes of the real or the imaginary part, `None` if it
+    // doesn't fit into a `usize`
+    fn part_size(&self) -> Option<usize> {
+        self.rows
+            .checked_mul(self.columns)?
+            .checked_mul(self.precision.byte_size().unwrap())
+    }
+
+    // The number of bytes of the real and imaginary parts
+    pub fn data_size(&self) -> Option<usize> {
+        let part_size = self.part_size()?;
+        if self.complex {
+            part_size.checked_mul(2)
+        } else {
+            Some(part_size)
+        }
+    }
+
+    // The class of the matrix, with full matrices keeping their precision
+    pub fn class(&self) -> ArrayType {
+        match self.matrix_type {
+            MatrixType::Text => ArrayType::Char,
+            MatrixType::Sparse => ArrayType::Sparse,
+            MatrixType::Full => match self.precision {
+                DataType::Single => ArrayType::Single,
+                DataType::Int32 => ArrayType::Int32,
+                DataType::Int16 => ArrayType::Int16,
+                DataType::UInt16 => ArrayType::UInt16,
+                DataType::UInt8 => ArrayType::UInt8,
+                _ => ArrayType::Double,
+            },
+        }
+    }
+}
+
+// The type field is a decimal number MOPT where M is the byte order, O is
+// always zero, P is the precision of the data and T is the matrix type.
+fn decode_type(mopt: i32) -> Option<(DataType, MatrixType)> {
+    if mopt < 0 || (mopt / 100) % 10 != 0 {
+        return None;
+    }
+    let precision = match (mopt / 10) % 10 {
+        0 => DataType::Double,
+        1 => DataType::Single,
+        2 => DataType::Int32,
+        3 => DataType::Int16,
+        4 => DataType::UInt16,
+        5 => DataType::UInt8,
+        _ => return None,
+    };
+    let matrix_type = match mopt % 10 {
+        0 => MatrixType::Full,
+        1 => MatrixType::Text,
+        2 => MatrixType::Sparse,
+        _ => return None,
+    };
+    Some((precision, matrix_type))
+}
+
+// Only IEEE little endian (M = 0) and big endian (M = 1) files are
+// supported, VAX and Cray formats are not.
+fn parse_endianness(i: &[u8]) -> IResult<&[u8], nom::Endianness> {
+    let (_, little) = peek!(i, le_i32)?;
+    let (_, big) = peek!(i, be_i32)?;
+    if little >= 0 && little / 1000 == 0 {
+        Ok((i, nom::Endianness::Little))
+    } else if big >= 0 && big / 1000 == 1 {
+        Ok((i, nom::Endianness::Big))
+    } else {
+        Err(failure(i, ParseErrorKind::UnsupportedNumberFormat))
+    }
+}
+
+pub fn parse_matrix_header(i: &[u8]) -> IResult<&[u8], MatrixHeader> {
+    do_parse!(
+        i,
+        endianness: parse_endianness
+            >> data_type: map_res!(i32!(endianness), |mopt| decode_type(mopt % 1000).ok_or(()))
+            >> rows: i32!(endianness)
+            >> columns: i32!(endianness)
+            >> imagf: i32!(endianness)
+            >> name_length: i32!(endianness)
+            >> apply!(assert, rows >= 0 && columns >= 0 && name_length > 0)
+            >> (MatrixHeader {
+                endianness,
+                precision: data_type.0,
+                matrix_type: data_type.1,
+                rows: rows as usize,
+                columns: columns as usize,
+                complex: imagf != 0,
+                name_length: name_length as usize,
+            })
+    )
+}
+
+pub fn parse_name(i: &[u8], name_length: usize) -> IResult<&[u8], String> {
+    map_res!(i, take!(name_length), |b: &[u8]| {
+        // The name is null terminated
+        let end = b.iter().position(|&c| c == 0).unwrap_or(b.len());
+        std::str::from_utf8(&b[..end]).map(|s| s.to_owned())
+    })
+}
+
+fn part_size<'a>(i: &'a [u8], header: &MatrixHeader) -> IResult<&'a [u8], usize> {
+    match header.part_size() {
+        Some(byte_size) => Ok((i, byte_size)),
+        None => Err(failure(i, ParseErrorKind::Malformed)),
+    }
+}
+
+pub fn parse_matrix(i: &[u8]) -> IResult<&[u8], DataElement> {
+    let (remaining, (header, name, real_part, imag_part)) = do_parse!(
+        i,
+        header: parse_matrix_header
+            >> name: apply!(parse_name, header.name_length)
+            >> byte_size: apply!(part_size, &header)
+            >> real_part: apply!(parse_numeric_data, header.endianness, header.precision, byte_size)
+            >> imag_part:
+                cond!(
+                    header.complex,
+                    apply!(parse_numeric_data, header.endianness, header.precision, byte_size)
+                )
+            >> ((header, name, real_part, imag_part))
+    )?;
+    let data_element = match header.matrix_type {
+        MatrixType::Full => full_matrix(&header, name, real_part, imag_part),
+        MatrixType::Text => text_matrix(&header, name, real_part),
+        MatrixType::Sparse => sparse_matrix(&header, name, real_part, imag_part)
+            .ok_or(failure(i, ParseErrorKind::Malformed))?,
+    };
+    Ok((remaining, data_element))
+}
+
+fn flags(class: ArrayType, complex: bool, nzmax: usize) -> ArrayFlags {
+    ArrayFlags {
+        complex,
+        global: false,
+        logical: false,
+        class,
+        nzmax,
+    }
+}
+
+fn dimensions(rows: usize, columns: usize) -> Vec<i32> {
+    vec![rows as i32, columns as i32]
+}
+
+pub fn to_f64(data: NumericData) -> Vec<f64> {
+    match data {
+        NumericData::Int8(data) => data.into_iter().map(f64::from).collect(),
+        NumericData::UInt8(data) => data.into_iter().map(f64::from).collect(),
+        NumericData::Int16(data) => data.into_iter().map(f64::from).collect(),
+        NumericData::UInt16(data) => data.into_iter().map(f64::from).collect(),
+        NumericData::Int32(data) => data.into_iter().map(f64::from).collect(),
+        NumericData::UInt32(data) => data.into_iter().map(f64::from).collect(),
+        NumericData::Int64(data) => data.into_iter().map(|x| x as f64).collect(),
+        NumericData::UInt64(data) => data.into_iter().map(|x| x as f64).collect(),
+        NumericData::Single(data) => data.into_iter().map(f64::from).collect(),
+        NumericData::Double(data) => data,
+    }
+}
+
+// Full matrices keep the precision they were stored with
+fn full_matrix(
+    header: &MatrixHeader,
+    name: String,
+    real_part: NumericData,
+    imag_part: Option<NumericData>,
+) -> DataElement {
+    DataElement::NumericMatrix(
+        flags(header.class(), header.complex, 0),
+        dimensions(header.rows, header.columns),
+        name,
+        real_part,
+        imag_part,
+    )
+}
+
+// Text matrices store one character code per entry
+fn text_matrix(header: &MatrixHeader, name: String, real_part: NumericData) -> DataElement {
+    DataElement::CharacterMatrix(
+        flags(ArrayType::Char, false, 0),
+        dimensions(header.rows, header.columns),
+        name,
+        to_f64(real_part).into_iter().map(|c| c as u16).collect(),
+    )
+}
+
+// Converts a row or column number of a sparse matrix, which has to be a
+// whole number that fits into the `i32` dimensions of an array
+fn sparse_index(value: f64) -> Option<usize> {
+    if value >= 0.0 && value <= f64::from(i32::MAX) && value.fract() == 0.0 {
+        Some(value as usize)
+    } else {
+        None
+    }
+}
+
+// Sparse matrices are stored as a full matrix with one row per nonzero
+// entry, the columns holding the (one-based) row index, column index, real
+// and optionally imaginary part of the entry. An additional last row holds
+// the size of the sparse matrix.
+fn sparse_matrix(
+    header: &MatrixHeader,
+    name: String,
+    real_part: NumericData,
+    imag_part: Option<NumericData>,
+) -> Option<DataElement> {
+    let n_entries = header.rows;
+    if n_entries == 0 || header.columns < 3 || header.columns > 4 {
+        return None;
+    }
+    let values = to_f64(real_part);
+    let column = |c: usize| &values[c * n_entries..(c + 1) * n_entries];
+    let (row_indices, column_indices, real) = (column(0), column(1), column(2));
+    let imag = if header.columns == 4 {
+        Some(column(3).to_vec())
+    } else {
+        imag_part.map(|imag| to_f64(imag)[2 * n_entries..3 * n_entries].to_vec())
+    };
+    let nnz = n_entries - 1;
+    let rows = sparse_index(row_indices[nnz])?;
+    let columns = sparse_index(column_indices[nnz])?;
+
+    // Entries are usually sorted by column already, but don't rely on it.
+    // Like in Matlab, the values of entries at the same position are summed
+    // up.
+    let mut order: Vec<usize> = (0..nnz).collect();
+    order.sort_by(|&a, &b| {
+        (column_indices[a], row_indices[a])
+            .partial_cmp(&(column_indices[b], row_indices[b]))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    let mut row_index = Vec::with_capacity(nnz);
+    let mut column_shift = vec![0; columns + 1];
+    let mut real_sums: Vec<f64> = Vec::with_capacity(nnz);
+    let mut imag_sums: Option<Vec<f64>> = imag.as_ref().map(|_| Vec::with_capacity(nnz));
+    let mut previous = None;
+    for &k in &order {
+        let (row, column) = (
+            sparse_index(row_indices[k])?,
+            sparse_index(column_indices[k])?,
+        );
+        if row < 1 || row > rows || column < 1 || column > columns {
+            return None;
+        }
+        if previous == Some((row, column)) {
+            *real_sums.last_mut()? += real[k];
+            if let (Some(sums), Some(imag)) = (&mut imag_sums, &imag) {
+                *sums.last_mut()? += imag[k];
+            }
+            continue;
+        }
+        previous = Some((row, column));
+        row_index.push(row as i32 - 1);
+        column_shift[column] += 1;
+        real_sums.push(real[k]);
+        if let (Some(sums), Some(imag)) = (&mut imag_sums, &imag) {
+            sums.push(imag[k]);
+        }
+    }
+    for c in 0..columns {
+        column_shift[c + 1] += column_shift[c];
+    }
+
+    Some(DataElement::SparseMatrix(
+        flags(ArrayType::Sparse, imag_sums.is_some(), row_index.len()),
+        dimensions(rows, columns),
+        name,
+        row_index,
+        column_shift,
+        NumericData::Double(real_sums),
+        imag_sums.map(NumericData::Double),
+    ))
+}
+
+// Matrices that can't be parsed are skipped by the size in their header,
+// unless the header is broken too
+pub fn parse_all(i: &[u8], on_error: OnError) -> IResult<&[u8], DataElements<'_>> {
+    let mut data_elements = DataElements::default();
+    let mut remaining = i;
+    while !remaining.is_empty() {
+        let offset = i.len() - remaining.len();
+        let (result, next) = match parse_matrix(remaining) {
+            Ok((next, data_element)) => (Ok(data_element), next),
+            Err(err) => {
+                let err = match err {
+                    nom::Err::Incomplete(_) => failure(remaining, ParseErrorKind::Truncated),
+                    err => err,
+                };
+                let byte_size = parse_matrix_header(remaining)
+                    .ok()
+                    .and_then(|(_rest, header)| {
+                        (MATRIX_HEADER_SIZE + header.name_length).checked_add(header.data_size()?)
+                    })
+                    .unwrap_or(remaining.len());
+                (Err(err), &remaining[byte_size.min(remaining.len())..])
+            }
+        };
+        data_elements.add(offset, result, on_error)?;
+        remaining = next;
+    }
+    Ok((remaining, data_elements))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mopt() {
+        assert_eq!(decode_type(0), Some((DataType::Double, MatrixType::Full)));
+        assert_eq!(decode_type(51), Some((DataType::UInt8, MatrixType::Text)));
+        assert_eq!(
+            decode_type(12),
+            Some((DataType::Single, MatrixType::Sparse))
+        );
+        assert_eq!(decode_type(100), None);
+        assert_eq!(decode_type(60), None);
+        assert_eq!(decode_type(3), None);
+    }
+
+    #[test]
+    fn sparse() {
+        let (_, data_elements) =
+            parse_all(include_bytes!("../tests/v4.mat"), OnError::Fail).unwrap();
+        if let DataElement::SparseMatrix(_flags, dim, name, irows, icols, real_vals, imag_vals) =
+            data_elements.parsed[3].1.clone()
+        {
+            assert_eq!(name, "S");
+            assert_eq!(dim, vec![3, 3]);
+            assert_eq!(irows, vec![0, 2, 1]);
+            assert_eq!(icols, vec![0, 1, 2, 3]);
+            assert_eq!(real_vals, NumericData::Double(vec![1.0, 2.0, 3.0]));
+            assert_eq!(imag_vals, None);
+        } else {
+            panic!("Error extracting DataElement::SparseMatrix");
+        }
+    }
+
+    // A little endian double matrix with the given type, size and values
+    fn matrix(mopt: i32, rows: i32, columns: i32, values: &[f64]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for &field in [mopt, rows, columns, 0, 2].iter() {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        data.extend_from_slice(b"x\0");
+        for value in values {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn malformed_sizes() {
+        let is_malformed = |data: &[u8]| match parse_matrix(data) {
+            Err(err) => {
+                crate::parse::to_parse_error(err, data, 0).kind() == ParseErrorKind::Malformed
+            }
+            Ok(_) => false,
+        };
+        // The size of the data overflows
+        assert!(is_malformed(&matrix(0, i32::MAX, i32::MAX, &[])));
+        // Sparse matrices with a size that isn't a valid array size
+        let sparse =
+            |rows: f64, columns: f64| matrix(2, 2, 3, &[1.0, rows, 1.0, columns, 5.0, 0.0]);
+        assert!(!is_malformed(&sparse(2.0, 2.0)));
+        assert!(is_malformed(&sparse(1e10, 2.0)));
+        assert!(is_malformed(&sparse(2.0, 1e10)));
+        assert!(is_malformed(&sparse(2.0, -1.0)));
+        assert!(is_malformed(&sparse(2.0, f64::NAN)));
+        assert!(is_malformed(&sparse(2.5, 2.0)));
+    }
+
+    #[test]
+    fn sparse_duplicates() {
+        // The entry at (1, 1) is listed twice
+        let data = matrix(
+            2,
+            4,
+            3,
+            &[1.0, 2.0, 1.0, 2.0, 1.0, 2.0, 1.0, 2.0, 1.0, 2.0, 3.0, 0.0],
+        );
+        match parse_matrix(&data) {
+            Ok((_, DataElement::SparseMatrix(flags, _, _, irows, icols, real_vals, None))) => {
+                assert_eq!(flags.nzmax, 2);
+                assert_eq!(irows, vec![0, 1]);
+                assert_eq!(icols, vec![0, 1, 2]);
+                assert_eq!(real_vals, NumericData::Double(vec![4.0, 2.0]));
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
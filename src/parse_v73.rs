@@ -0,0 +1,574 @@
+use crate::hdf5::{self, Datatype, Object};
+use crate::parse::{self, ArrayType, DataType};
+use crate::{
+    Array, CellArray, CharArray, Error, Header, NumericData, OnError, ParseError, ParseErrorKind,
+    ParseWarning, SparseArray, StructArray, Variable,
+};
+use std::convert::TryFrom;
+
+// MAT-file version 7.3 files are HDF5 files with a user block of 512 bytes
+// holding a Level 5 style header. Each variable is a dataset or group in the
+// root group, with its class stored in the "MATLAB_class" attribute:
+// * Numeric, logical and character arrays are datasets of their type (uint8
+//   for logical and uint16 for character arrays), with complex numbers being
+//   compounds of a "real" and an "imag" member.
+// * Empty arrays are datasets holding their size, marked by a "MATLAB_empty"
+//   attribute.
+// * Sparse arrays are groups with the row indices, column pointers and values
+//   in the datasets "ir", "jc" and "data", and the number of rows in their
+//   "MATLAB_sparse" attribute.
+// * Cell arrays are datasets of object references to the cells, which are
+//   stored in the "#refs#" group.
+// * Structures are groups with their field names in the "MATLAB_fields"
+//   attribute and a member per field. For structure arrays, each field is a
+//   dataset of object references to the values of the elements.
+// HDF5 lists dimensions with the slowest varying first, so the size of an
+// array is the reverse of the dimensions of its dataset.
+
+// References can form cycles in damaged files
+const MAX_DEPTH: usize = 64;
+
+fn malformed(offset: usize) -> Error {
+    Error::ParseError(ParseError::new(ParseErrorKind::Malformed).or_at(offset as u64))
+}
+
+fn size_mismatch(offset: usize) -> Error {
+    Error::ParseError(ParseError::new(ParseErrorKind::SizeMismatch).or_at(offset as u64))
+}
+
+// The text of a string attribute
+fn string_attribute(file: &hdf5::File, object: &Object, name: &str) -> Option<String> {
+    let attribute = object.attribute(name)?;
+    let bytes = match attribute.datatype {
+        Datatype::String { .. } => attribute.data,
+        Datatype::VarString => file.heap_object(attribute.data, attribute.offset).ok()?.1,
+        _ => return None,
+    };
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8(bytes[..end].to_vec()).ok()
+}
+
+// The value of an integer attribute with a single element
+fn integer_attribute(object: &Object, name: &str) -> Option<u64> {
+    let attribute = object.attribute(name)?;
+    match attribute.datatype {
+        Datatype::Integer { .. } => integers(&attribute.datatype, attribute.data)?
+            .first()
+            .copied(),
+        _ => None,
+    }
+}
+
+// The bits of elements of `size` bytes, which is at most eight
+fn words(bytes: &[u8], size: usize, big_endian: bool) -> impl Iterator<Item = u64> + '_ {
+    bytes.chunks_exact(size).map(move |bytes| {
+        let fold = |value: u64, &byte: &u8| value << 8 | u64::from(byte);
+        if big_endian {
+            bytes.iter().fold(0, fold)
+        } else {
+            bytes.iter().rev().fold(0, fold)
+        }
+    })
+}
+
+// Decodes unsigned integers of at most 64 bits
+fn integers(datatype: &Datatype, bytes: &[u8]) -> Option<Vec<u64>> {
+    match *datatype {
+        Datatype::Integer {
+            size, big_endian, ..
+        } if (1..=8).contains(&size) => Some(words(bytes, size, big_endian).collect()),
+        _ => None,
+    }
+}
+
+// The size of an array stored in a dataset of the given dimensions
+fn size_of(dims: &[u64], offset: usize) -> Result<Vec<usize>, Error> {
+    let mut size = dims
+        .iter()
+        .rev()
+        .map(|&d| usize::try_from(d).map_err(|_err| malformed(offset)))
+        .collect::<Result<Vec<_>, _>>()?;
+    while size.len() < 2 {
+        size.push(1);
+    }
+    Ok(size)
+}
+
+// The types of the array and the data of a numeric class
+fn numeric_class(class: &str) -> Option<(ArrayType, DataType)> {
+    Some(match class {
+        "double" => (ArrayType::Double, DataType::Double),
+        "single" => (ArrayType::Single, DataType::Single),
+        "int8" => (ArrayType::Int8, DataType::Int8),
+        "uint8" => (ArrayType::UInt8, DataType::UInt8),
+        "int16" => (ArrayType::Int16, DataType::Int16),
+        "uint16" => (ArrayType::UInt16, DataType::UInt16),
+        "int32" => (ArrayType::Int32, DataType::Int32),
+        "uint32" => (ArrayType::UInt32, DataType::UInt32),
+        "int64" => (ArrayType::Int64, DataType::Int64),
+        "uint64" => (ArrayType::UInt64, DataType::UInt64),
+        _ => return None,
+    })
+}
+
+// The type and byte order of numbers stored as the given datatype
+fn data_type(datatype: &Datatype) -> Option<(DataType, bool)> {
+    match *datatype {
+        Datatype::Integer {
+            size,
+            signed,
+            big_endian,
+        } => {
+            let data_type = match (size, signed) {
+                (1, true) => DataType::Int8,
+                (1, false) => DataType::UInt8,
+                (2, true) => DataType::Int16,
+                (2, false) => DataType::UInt16,
+                (4, true) => DataType::Int32,
+                (4, false) => DataType::UInt32,
+                (8, true) => DataType::Int64,
+                (8, false) => DataType::UInt64,
+                _ => return None,
+            };
+            Some((data_type, big_endian))
+        }
+        Datatype::Float {
+            size: 4,
+            big_endian,
+        } => Some((DataType::Single, big_endian)),
+        Datatype::Float {
+            size: 8,
+            big_endian,
+        } => Some((DataType::Double, big_endian)),
+        _ => None,
+    }
+}
+
+// Decodes numbers of the given type. Integers are cut down to their size,
+// which gives back negative numbers from the bits of their two's complement.
+fn decode(bytes: &[u8], data_type: DataType, big_endian: bool) -> Option<parse::NumericData> {
+    let values = |size| words(bytes, size, big_endian);
+    Some(match data_type {
+        DataType::Int8 => parse::NumericData::Int8(values(1).map(|v| v as i8).collect()),
+        DataType::UInt8 => parse::NumericData::UInt8(values(1).map(|v| v as u8).collect()),
+        DataType::Int16 => parse::NumericData::Int16(values(2).map(|v| v as i16).collect()),
+        DataType::UInt16 => parse::NumericData::UInt16(values(2).map(|v| v as u16).collect()),
+        DataType::Int32 => parse::NumericData::Int32(values(4).map(|v| v as i32).collect()),
+        DataType::UInt32 => parse::NumericData::UInt32(values(4).map(|v| v as u32).collect()),
+        DataType::Int64 => parse::NumericData::Int64(values(8).map(|v| v as i64).collect()),
+        DataType::UInt64 => parse::NumericData::UInt64(values(8).collect()),
+        DataType::Single => {
+            parse::NumericData::Single(values(4).map(|v| f32::from_bits(v as u32)).collect())
+        }
+        DataType::Double => parse::NumericData::Double(values(8).map(f64::from_bits).collect()),
+        _ => return None,
+    })
+}
+
+fn parse_numbers(
+    bytes: &[u8],
+    datatype: &Datatype,
+    expected: DataType,
+    offset: usize,
+) -> Result<parse::NumericData, Error> {
+    match data_type(datatype) {
+        Some((data_type, big_endian)) if data_type == expected => {
+            decode(bytes, data_type, big_endian).ok_or_else(|| malformed(offset))
+        }
+        _ => Err(size_mismatch(offset)),
+    }
+}
+
+// Reads the numbers of a dataset, which may be complex
+fn numeric_data(
+    file: &hdf5::File,
+    object: &Object,
+    array_type: ArrayType,
+    expected: DataType,
+) -> Result<NumericData, Error> {
+    let offset = object.offset;
+    let bytes = file.read(object)?;
+    let (real, imag) = match &object.datatype {
+        Some(Datatype::Compound { size, members }) => {
+            let member = |name: &str| {
+                members
+                    .iter()
+                    .find(|member| member.name == name)
+                    .ok_or_else(|| size_mismatch(offset))
+            };
+            // Gathers the bytes of a member of all elements
+            let part = |member: &hdf5::Member| {
+                let member_size = member.datatype.size(file);
+                if *size == 0 || member.offset + member_size > *size {
+                    return Err(malformed(offset));
+                }
+                let bytes: Vec<u8> = bytes
+                    .chunks_exact(*size)
+                    .flat_map(|element| &element[member.offset..member.offset + member_size])
+                    .copied()
+                    .collect();
+                parse_numbers(&bytes, &member.datatype, expected, offset)
+            };
+            (part(member("real")?)?, Some(part(member("imag")?)?))
+        }
+        Some(datatype) => (parse_numbers(&bytes, datatype, expected, offset)?, None),
+        None => return Err(malformed(offset)),
+    };
+    NumericData::try_from(array_type, real, imag)
+}
+
+// The dimensions of an empty array, which are stored as its data
+fn empty_size(file: &hdf5::File, object: &Object) -> Result<Vec<usize>, Error> {
+    let bytes = file.read(object)?;
+    let datatype = object
+        .datatype
+        .as_ref()
+        .ok_or_else(|| malformed(object.offset))?;
+    let dims = integers(datatype, &bytes).ok_or_else(|| size_mismatch(object.offset))?;
+    let mut size = dims
+        .into_iter()
+        .map(|d| usize::try_from(d).map_err(|_err| malformed(object.offset)))
+        .collect::<Result<Vec<_>, _>>()?;
+    while size.len() < 2 {
+        size.push(0);
+    }
+    if !size.contains(&0) {
+        return Err(size_mismatch(object.offset));
+    }
+    Ok(size)
+}
+
+// Reads a dataset of indices
+fn indices(file: &hdf5::File, object: &Object) -> Result<Vec<usize>, Error> {
+    let bytes = file.read(object)?;
+    let datatype = object
+        .datatype
+        .as_ref()
+        .ok_or_else(|| malformed(object.offset))?;
+    integers(datatype, &bytes)
+        .ok_or_else(|| size_mismatch(object.offset))?
+        .into_iter()
+        .map(|index| usize::try_from(index).map_err(|_err| malformed(object.offset)))
+        .collect()
+}
+
+// The member of a group with the given name, if there is one
+fn member<'a>(
+    file: &hdf5::File<'a>,
+    members: &[(String, u64)],
+    name: &str,
+    at: usize,
+) -> Result<Option<Object<'a>>, Error> {
+    members
+        .iter()
+        .find(|(member_name, _address)| member_name == name)
+        .map(|&(_, address)| file.object(address, at))
+        .transpose()
+}
+
+// Loads the variable stored as the given object. Returns `None` for
+// variables of classes that are not supported.
+fn load(
+    file: &hdf5::File,
+    name: String,
+    object: &Object,
+    depth: usize,
+) -> Result<Option<Variable>, Error> {
+    let offset = object.offset;
+    if depth > MAX_DEPTH {
+        return Err(malformed(offset));
+    }
+    let class = match string_attribute(file, object, "MATLAB_class") {
+        Some(class) => class,
+        None => return Ok(None),
+    };
+    let global = integer_attribute(object, "MATLAB_global").is_some_and(|global| global != 0);
+    let empty = integer_attribute(object, "MATLAB_empty").is_some_and(|empty| empty != 0);
+    let logical = class == "logical";
+    let numeric_class = if logical {
+        Some((ArrayType::UInt8, DataType::UInt8))
+    } else {
+        numeric_class(&class)
+    };
+
+    if let Some(n_rows) = integer_attribute(object, "MATLAB_sparse") {
+        if !object.is_group() || !(logical || class == "double") {
+            return Err(malformed(offset));
+        }
+        let n_rows = usize::try_from(n_rows).map_err(|_err| malformed(offset))?;
+        let members = file.members(object)?;
+        let column_pointer = match member(file, &members, "jc", offset)? {
+            Some(jc) => indices(file, &jc)?,
+            None => return Err(malformed(offset)),
+        };
+        let n_columns = column_pointer
+            .len()
+            .checked_sub(1)
+            .ok_or_else(|| size_mismatch(offset))?;
+        // Sparse arrays without entries may leave out the row indices and data
+        let row_index = match member(file, &members, "ir", offset)? {
+            Some(ir) => indices(file, &ir)?,
+            None => Vec::new(),
+        };
+        let (real, imag) = match member(file, &members, "data", offset)? {
+            Some(data) if logical => {
+                let data = file.read(&data)?;
+                (data.into_iter().map(f64::from).collect(), None)
+            }
+            Some(data) => match numeric_data(file, &data, ArrayType::Double, DataType::Double)? {
+                NumericData::Double { real, imag } => (real, imag),
+                _ => return Err(malformed(offset)),
+            },
+            None => (Vec::new(), None),
+        };
+        if column_pointer[0] != 0
+            || column_pointer.windows(2).any(|w| w[0] > w[1])
+            || column_pointer[n_columns] != row_index.len()
+            || row_index.iter().any(|&row| row >= n_rows)
+            || real.len() != row_index.len()
+        {
+            return Err(size_mismatch(offset));
+        }
+        return Ok(Some(Variable::Sparse(SparseArray {
+            name,
+            size: vec![n_rows, n_columns],
+            nzmax: row_index.len(),
+            row_index,
+            column_pointer,
+            data: NumericData::Double { real, imag },
+            logical,
+        })));
+    }
+
+    if class == "struct" {
+        return load_struct(file, name, object, empty, depth);
+    }
+    if object.is_group() {
+        return Ok(None);
+    }
+    let (size, dims) = if empty {
+        (empty_size(file, object)?, None)
+    } else {
+        let dims = object.dims.as_deref().ok_or_else(|| malformed(offset))?;
+        (size_of(dims, offset)?, Some(dims))
+    };
+
+    if let Some((array_type, expected)) = numeric_class {
+        let data = if dims.is_some() {
+            numeric_data(file, object, array_type, expected)?
+        } else {
+            let real = decode(&[], expected, false).ok_or_else(|| malformed(offset))?;
+            NumericData::try_from(array_type, real, None)?
+        };
+        return Ok(Some(Variable::Numeric(Array {
+            name,
+            size,
+            storage_type: data.numeric_type(),
+            data,
+            logical,
+            global,
+        })));
+    }
+
+    match class.as_str() {
+        "char" => {
+            let data = if dims.is_some() {
+                let bytes = file.read(object)?;
+                let datatype = object.datatype.as_ref().ok_or_else(|| malformed(offset))?;
+                match datatype {
+                    Datatype::Integer { size: 1, .. } | Datatype::Integer { size: 2, .. } => {
+                        integers(datatype, &bytes)
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(|c| c as u16)
+                            .collect()
+                    }
+                    _ => return Err(size_mismatch(offset)),
+                }
+            } else {
+                Vec::new()
+            };
+            Ok(Some(Variable::Char(CharArray { name, size, data })))
+        }
+        "cell" => {
+            let mut data = Vec::new();
+            if dims.is_some() {
+                for address in file.references(object)? {
+                    let cell = file.object(address, offset)?;
+                    match load(file, String::new(), &cell, depth + 1)? {
+                        Some(cell) => data.push(cell),
+                        None => return Ok(None),
+                    }
+                }
+            }
+            Ok(Some(Variable::Cell(CellArray { name, size, data })))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn load_struct(
+    file: &hdf5::File,
+    name: String,
+    object: &Object,
+    empty: bool,
+    depth: usize,
+) -> Result<Option<Variable>, Error> {
+    let offset = object.offset;
+    let mut field_names = Vec::new();
+    if let Some(attribute) = object.attribute("MATLAB_fields") {
+        let element_size = attribute.datatype.size(file);
+        match &attribute.datatype {
+            Datatype::Sequence(_) if element_size > 0 => (),
+            _ => return Err(malformed(attribute.offset)),
+        }
+        for element in attribute.data.chunks_exact(element_size) {
+            let (length, bytes) = file.heap_object(element, attribute.offset)?;
+            let bytes = bytes
+                .get(..length)
+                .ok_or_else(|| malformed(attribute.offset))?;
+            let field_name = String::from_utf8(bytes.to_vec()).map_err(|_err| {
+                Error::ParseError(
+                    ParseError::new(ParseErrorKind::InvalidUtf8).or_at(attribute.offset as u64),
+                )
+            })?;
+            field_names.push(field_name);
+        }
+    }
+    if empty {
+        let size = empty_size(file, object)?;
+        return Ok(Some(Variable::Struct(StructArray {
+            name,
+            size,
+            field_names,
+            data: Vec::new(),
+        })));
+    }
+    if !object.is_group() {
+        return Err(malformed(offset));
+    }
+    let members = file.members(object)?;
+    let mut fields = Vec::with_capacity(field_names.len());
+    for field_name in &field_names {
+        match member(file, &members, field_name, offset)? {
+            Some(field) => fields.push(field),
+            None => return Err(malformed(offset)),
+        }
+    }
+
+    // The fields of structure arrays are datasets of references without a
+    // class of their own
+    let is_array = fields.first().is_some_and(|field| {
+        matches!(field.datatype, Some(Datatype::Reference { .. }))
+            && field.attribute("MATLAB_class").is_none()
+    });
+    if !is_array {
+        let mut data = Vec::with_capacity(fields.len());
+        for field in &fields {
+            match load(file, String::new(), field, depth + 1)? {
+                Some(value) => data.push(value),
+                None => return Ok(None),
+            }
+        }
+        return Ok(Some(Variable::Struct(StructArray {
+            name,
+            size: vec![1, 1],
+            field_names,
+            data,
+        })));
+    }
+
+    let dims = fields[0].dims.as_deref().ok_or_else(|| malformed(offset))?;
+    let size = size_of(dims, offset)?;
+    let references = fields
+        .iter()
+        .map(|field| {
+            if field.dims.as_deref() == Some(dims) {
+                file.references(field)
+            } else {
+                Err(size_mismatch(field.offset))
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let n_elements = references[0].len();
+    let mut data = Vec::with_capacity(n_elements * fields.len());
+    for element in 0..n_elements {
+        for field in &references {
+            let value = file.object(field[element], offset)?;
+            match load(file, String::new(), &value, depth + 1)? {
+                Some(value) => data.push(value),
+                None => return Ok(None),
+            }
+        }
+    }
+    Ok(Some(Variable::Struct(StructArray {
+        name,
+        size,
+        field_names,
+        data,
+    })))
+}
+
+// The MAT-file header in the user block. It has the same layout as the
+// header of Level 5 MAT-files, except for the version.
+fn parse_header(data: &[u8]) -> Result<Header, Error> {
+    let invalid_header =
+        || Error::ParseError(ParseError::new(ParseErrorKind::InvalidHeader).or_at(0));
+    let header = data.get(..128).ok_or_else(invalid_header)?;
+    let is_little_endian = match &header[126..128] {
+        b"IM" => true,
+        b"MI" => false,
+        _ => return Err(invalid_header()),
+    };
+    let version = [header[124], header[125]];
+    let version = if is_little_endian {
+        u16::from_le_bytes(version)
+    } else {
+        u16::from_be_bytes(version)
+    };
+    Ok(Header::from(parse::Header {
+        text: String::from_utf8_lossy(&header[..116]).into_owned(),
+        subsystem_offset: None,
+        version,
+        is_little_endian,
+    }))
+}
+
+// Loads the variables of a MAT-file version 7.3 file, in the order of their
+// names
+pub fn parse_all(
+    data: &[u8],
+    on_error: OnError,
+) -> Result<(Header, Vec<Variable>, Vec<ParseWarning>), Error> {
+    let header = parse_header(data)?;
+    let file = hdf5::File::open(data)?;
+    let root = file.object(file.root(), 0)?;
+    let mut variables = Vec::new();
+    let mut warnings = Vec::new();
+    // Cells and objects are stored in groups starting with '#'
+    for (name, address) in file.members(&root)? {
+        if name.starts_with('#') {
+            continue;
+        }
+        let loaded = file.object(address, root.offset).and_then(|object| {
+            let variable = load(&file, name.clone(), &object, 0)?;
+            Ok((object.offset, variable))
+        });
+        match loaded {
+            Ok((_offset, Some(variable))) => variables.push(variable),
+            Ok((offset, None)) => warnings.push(ParseWarning::Unsupported {
+                offset: offset as u64,
+                variable_name: Some(name),
+            }),
+            Err(Error::ParseError(err)) => {
+                let err = err.in_variable(Some(name));
+                match on_error {
+                    OnError::Skip => warnings.push(ParseWarning::Skipped(err)),
+                    OnError::Fail => return Err(Error::ParseError(err)),
+                }
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok((header, variables, warnings))
+}
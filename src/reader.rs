@@ -0,0 +1,330 @@
+use crate::mcos;
+use crate::parse::{self, ArrayFlags, ArrayType, DataType, Dimensions};
+use crate::parse_v4::{self, MatrixType};
+use crate::{Class, DirectoryEntry, Error, ParseError, ParseErrorKind, Variable, VariableInfo};
+use libflate::zlib::Decoder;
+use std::io::{Read, Seek, SeekFrom};
+
+// How the variables of a file are stored
+#[derive(Clone, Copy, Debug)]
+pub enum Format {
+    Level5(nom::Endianness),
+    Level4,
+}
+
+impl Format {
+    // The format of a Level 5 MAT-file with the given header
+    pub fn of(header: &parse::Header) -> Self {
+        if header.is_little_endian {
+            Format::Level5(nom::Endianness::Little)
+        } else {
+            Format::Level5(nom::Endianness::Big)
+        }
+    }
+}
+
+// Converts a parser error for `input`, which was read from byte `base` of
+// the file
+fn parse_error(err: nom::Err<&[u8], u32>, input: &[u8], base: u64) -> Error {
+    Error::ParseError(parse::to_parse_error(err, input, base))
+}
+
+// Positions errors that have no position yet at the given offset
+fn located(err: Error, offset: u64) -> Error {
+    match err {
+        Error::ParseError(err) => Error::ParseError(err.or_at(offset)),
+        err => err,
+    }
+}
+
+// Reads the beginning of a matrix element up to the array name, in growing
+// chunks until parsing succeeds. This avoids reading (or inflating) more of
+// the element than necessary. Returns `None` if there is nothing to read,
+// like in fillers. Errors are left without a position.
+fn read_matrix_header<R: Read>(
+    mut reader: R,
+    endianness: nom::Endianness,
+) -> Result<Option<(ArrayFlags, Dimensions, String)>, Error> {
+    let mut buf = Vec::new();
+    let mut chunk_size = 256;
+    loop {
+        let n_read = (&mut reader)
+            .take(chunk_size)
+            .read_to_end(&mut buf)
+            .map_err(Error::IOError)?;
+        match parse::parse_matrix_header(&buf, endianness) {
+            Ok((_remaining, value)) => return Ok(Some(value)),
+            Err(nom::Err::Incomplete(_)) if n_read > 0 => chunk_size *= 2,
+            Err(nom::Err::Incomplete(_)) if buf.is_empty() => return Ok(None),
+            Err(err) => return Err(parse_error(err, &[], 0)),
+        }
+    }
+}
+
+// Reads exactly `buf.len()` bytes, returning `false` if the end of the file
+// was reached before
+fn read_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool, Error> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(Error::IOError(err)),
+    }
+}
+
+fn seek<R: Seek>(reader: &mut R, offset: u64) -> Result<(), Error> {
+    reader
+        .seek(SeekFrom::Start(offset))
+        .map(|_| ())
+        .map_err(Error::IOError)
+}
+
+// Determines the format of the file and lists its variables
+pub fn scan<R: Read + Seek>(
+    reader: &mut R,
+) -> Result<(Format, Option<parse::Header>, Vec<DirectoryEntry>), Error> {
+    seek(reader, 0)?;
+    let mut start = Vec::new();
+    (&mut *reader)
+        .take(520)
+        .read_to_end(&mut start)
+        .map_err(Error::IOError)?;
+    if parse::is_hdf5_mat_file(&start) {
+        return Err(Error::Hdf5NotSupported);
+    }
+    if parse_v4::is_level4_mat_file(&start) {
+        return Ok((
+            Format::Level4,
+            None,
+            scan_directory(reader, Format::Level4)?,
+        ));
+    }
+    let (_remaining, header) =
+        parse::parse_header(&start).map_err(|err| parse_error(err, &start, 0))?;
+    let format = Format::of(&header);
+    let mut directory = scan_directory(reader, format)?;
+    // The subsystem data is stored like a variable, but isn't one
+    directory.retain(|entry| Some(entry.offset) != header.subsystem_offset);
+    Ok((format, Some(header), directory))
+}
+
+fn scan_directory<R: Read + Seek>(
+    reader: &mut R,
+    format: Format,
+) -> Result<Vec<DirectoryEntry>, Error> {
+    let mut directory = Vec::new();
+    let mut offset = first_offset(format);
+    while let Some((entry, next_offset)) = scan_entry(reader, format, offset)? {
+        directory.extend(entry);
+        offset = next_offset;
+    }
+    Ok(directory)
+}
+
+// Where the variables of a file start
+fn first_offset(format: Format) -> u64 {
+    match format {
+        Format::Level5(_) => 128,
+        Format::Level4 => 0,
+    }
+}
+
+// Reads the directory entry of the variable at `offset`, if there is a
+// variable, along with the offset of what follows it. Returns `None` at the
+// end of the file.
+pub fn scan_entry<R: Read + Seek>(
+    reader: &mut R,
+    format: Format,
+    offset: u64,
+) -> Result<Option<(Option<DirectoryEntry>, u64)>, Error> {
+    match format {
+        Format::Level5(endianness) => scan_level5_entry(reader, endianness, offset),
+        Format::Level4 => Ok(scan_level4_entry(reader, offset)?
+            .map(|(entry, next_offset)| (Some(entry), next_offset))),
+    }
+}
+
+// The name of the variable stored at the given position of a file, as far as
+// it can be determined
+pub fn variable_name_at<R: Read + Seek>(
+    reader: &mut R,
+    format: Format,
+    position: u64,
+) -> Option<String> {
+    let mut offset = first_offset(format);
+    while let Ok(Some((entry, next_offset))) = scan_entry(reader, format, offset) {
+        if position < next_offset {
+            return entry.map(|entry| entry.info.name);
+        }
+        offset = next_offset;
+    }
+    None
+}
+
+fn scan_level5_entry<R: Read + Seek>(
+    reader: &mut R,
+    endianness: nom::Endianness,
+    offset: u64,
+) -> Result<Option<(Option<DirectoryEntry>, u64)>, Error> {
+    seek(reader, offset)?;
+    let mut tag = [0; 8];
+    if !read_or_eof(reader, &mut tag)? {
+        return Ok(None);
+    }
+    let (_remaining, data_element_tag) = parse::parse_data_element_tag(&tag, endianness)
+        .map_err(|err| parse_error(err, &tag, offset))?;
+    let byte_size = u64::from(data_element_tag.data_byte_size);
+    let payload = (&mut *reader).take(byte_size);
+    let matrix_header = match data_element_tag.data_type {
+        DataType::Matrix => Some(read_matrix_header((&tag[..]).chain(payload), endianness)),
+        DataType::Compressed => Some(
+            Decoder::new(payload)
+                .map_err(Error::IOError)
+                .and_then(|decoder| read_matrix_header(decoder, endianness)),
+        ),
+        // Not a variable
+        _ => None,
+    };
+    let matrix_header = matrix_header
+        .transpose()
+        .map_err(|err| located(err, offset))?
+        .flatten();
+    let compressed = data_element_tag.data_type == DataType::Compressed;
+    let entry = matrix_header.map(|(flags, dimensions, name)| DirectoryEntry {
+        info: VariableInfo {
+            name,
+            class: Class::from_flags(&flags),
+            size: dimensions.into_iter().map(|d| d as usize).collect(),
+            complex: flags.complex,
+            global: flags.global,
+            sparse: flags.class == ArrayType::Sparse,
+            nzmax: flags.nzmax,
+        },
+        offset,
+        byte_size: 8 + byte_size,
+        compressed,
+    });
+    // Compressed elements are not padded
+    let mut next_offset = offset + 8 + byte_size;
+    if !compressed {
+        next_offset += u64::from(data_element_tag.padding_byte_size);
+    }
+    Ok(Some((entry, next_offset)))
+}
+
+// Reads a single number of a Level 4 matrix as a double
+fn read_level4_number<R: Read + Seek>(
+    reader: &mut R,
+    header: &parse_v4::MatrixHeader,
+    offset: u64,
+) -> Result<f64, Error> {
+    let byte_size = header.precision.byte_size().unwrap();
+    let mut buf = vec![0; byte_size];
+    seek(reader, offset)?;
+    reader.read_exact(&mut buf).map_err(Error::IOError)?;
+    let (_remaining, data) =
+        parse::parse_numeric_data(&buf, header.endianness, header.precision, byte_size)
+            .map_err(|err| parse_error(err, &buf, offset))?;
+    Ok(parse_v4::to_f64(data)[0])
+}
+
+fn scan_level4_entry<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+) -> Result<Option<(DirectoryEntry, u64)>, Error> {
+    seek(reader, offset)?;
+    let mut fixed = [0; parse_v4::MATRIX_HEADER_SIZE];
+    if !read_or_eof(reader, &mut fixed)? {
+        return Ok(None);
+    }
+    let (_remaining, header) =
+        parse_v4::parse_matrix_header(&fixed).map_err(|err| parse_error(err, &fixed, offset))?;
+    let name_offset = offset + parse_v4::MATRIX_HEADER_SIZE as u64;
+    let mut name = Vec::new();
+    (&mut *reader)
+        .take(header.name_length as u64)
+        .read_to_end(&mut name)
+        .map_err(Error::IOError)?;
+    let (_remaining, name) = parse_v4::parse_name(&name, header.name_length)
+        .map_err(|err| parse_error(err, &name, name_offset))?;
+    let data_offset = name_offset + header.name_length as u64;
+
+    // The size of a sparse matrix is stored in the last row of its first two
+    // columns
+    let sparse = header.matrix_type == MatrixType::Sparse;
+    let size = if sparse && header.rows > 0 {
+        let byte_size = header.precision.byte_size().unwrap() as u64;
+        let last_row = data_offset + (header.rows as u64 - 1) * byte_size;
+        let rows = read_level4_number(reader, &header, last_row)?;
+        let columns =
+            read_level4_number(reader, &header, last_row + header.rows as u64 * byte_size)?;
+        vec![rows as usize, columns as usize]
+    } else {
+        vec![header.rows, header.columns]
+    };
+    let class = Class::from_array_type(header.class(), false);
+    let data_size = header.data_size().ok_or_else(|| {
+        Error::ParseError(ParseError::new(ParseErrorKind::Malformed).or_at(offset))
+    })?;
+    let byte_size = data_offset - offset + data_size as u64;
+    let entry = DirectoryEntry {
+        info: VariableInfo {
+            name,
+            class,
+            size,
+            // Complex sparse matrices have a fourth column instead
+            complex: header.complex || (sparse && header.columns == 4),
+            global: false,
+            sparse,
+            nzmax: if sparse {
+                header.rows.saturating_sub(1)
+            } else {
+                0
+            },
+        },
+        offset,
+        byte_size,
+        compressed: false,
+    };
+    Ok(Some((entry, offset + byte_size)))
+}
+
+// Loads the variable of a directory entry
+pub fn read_variable<R: Read + Seek>(
+    reader: &mut R,
+    format: Format,
+    entry: &DirectoryEntry,
+) -> Result<Option<Variable>, Error> {
+    // The size comes from the file, so the buffer only grows with what can
+    // actually be read. Files that end early are reported by the parser.
+    let mut buf = Vec::new();
+    seek(reader, entry.offset)?;
+    reader
+        .take(entry.byte_size)
+        .read_to_end(&mut buf)
+        .map_err(Error::IOError)?;
+    let (_remaining, data_element) = match format {
+        Format::Level5(endianness) => parse::parse_next_data_element(&buf, endianness),
+        Format::Level4 => parse_v4::parse_matrix(&buf),
+    }
+    .map_err(|err| {
+        let err = parse::to_parse_error(err, &buf, entry.offset);
+        Error::ParseError(err.in_variable(Some(entry.name().to_owned())))
+    })?;
+    Variable::try_from(data_element)
+}
+
+// Loads the subsystem data stored at the given offset
+pub fn read_subsystem<R: Read + Seek>(
+    reader: &mut R,
+    format: Format,
+    offset: u64,
+) -> Result<mcos::Subsystem, Error> {
+    let variable = match scan_entry(reader, format, offset)? {
+        Some((Some(entry), _next_offset)) => read_variable(reader, format, &entry)?,
+        _ => None,
+    };
+    let variable = variable.ok_or_else(|| {
+        Error::ParseError(ParseError::new(ParseErrorKind::Malformed).or_at(offset))
+    })?;
+    mcos::parse_subsystem(&variable).map_err(|err| located(err, offset))
+}
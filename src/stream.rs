@@ -0,0 +1,346 @@
+use crate::parse::{self, ArrayType, DataElement, DataElementTag, DataType, NumericData};
+use crate::{Error, ParseError, ParseErrorKind};
+use libflate::zlib::Decoder;
+use std::io::Read;
+
+// Compressed data elements are decoded straight from the zlib stream rather
+// than being inflated in full first. Subelements are read one at a time and
+// handed to the parsers for uncompressed data, except for the numbers of
+// arrays, which are read in chunks right into their final vectors.
+
+// The maximum number of bytes of numbers read at once
+const CHUNK_SIZE: usize = 1 << 16;
+
+// Positions within inflated data are meaningless for the file, so errors
+// are left without a position here
+fn parse_error(err: nom::Err<&[u8], u32>) -> Error {
+    Error::ParseError(parse::to_parse_error(err, &[], 0))
+}
+
+fn invalid(kind: ParseErrorKind) -> Error {
+    Error::ParseError(ParseError::new(kind))
+}
+
+// Checks that the data of an array matches its size and class
+fn check_size(condition: bool) -> Result<(), Error> {
+    if condition {
+        Ok(())
+    } else {
+        Err(invalid(ParseErrorKind::SizeMismatch))
+    }
+}
+
+fn read_tag(
+    reader: &mut dyn Read,
+    endianness: nom::Endianness,
+) -> Result<([u8; 8], DataElementTag), Error> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes).map_err(Error::IOError)?;
+    let (_remaining, tag) =
+        parse::parse_data_element_tag(&bytes, endianness).map_err(parse_error)?;
+    Ok((bytes, tag))
+}
+
+// Reads and discards up to `byte_size` bytes
+fn skip(reader: &mut dyn Read, byte_size: u64) -> Result<(), Error> {
+    std::io::copy(&mut reader.take(byte_size), &mut std::io::sink())
+        .map(|_| ())
+        .map_err(Error::IOError)
+}
+
+// Reads a whole subelement including its tag and padding
+fn read_subelement(
+    reader: &mut dyn Read,
+    endianness: nom::Endianness,
+    buf: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let (tag_bytes, tag) = read_tag(reader, endianness)?;
+    buf.extend_from_slice(&tag_bytes);
+    if !tag.is_small() {
+        let byte_size = u64::from(tag.data_byte_size) + u64::from(tag.padding_byte_size);
+        reader
+            .take(byte_size)
+            .read_to_end(buf)
+            .map_err(Error::IOError)?;
+    }
+    Ok(())
+}
+
+// Reads the next subelement and parses it with one of the parsers for
+// uncompressed subelements
+fn read_parsed<T>(
+    reader: &mut dyn Read,
+    endianness: nom::Endianness,
+    parser: fn(&[u8], nom::Endianness) -> nom::IResult<&[u8], T>,
+) -> Result<T, Error> {
+    let mut buf = Vec::new();
+    read_subelement(reader, endianness, &mut buf)?;
+    let (_remaining, value) = parser(&buf, endianness).map_err(parse_error)?;
+    Ok(value)
+}
+
+// Field names take up two subelements, their length and the names themselves
+fn read_field_names(
+    reader: &mut dyn Read,
+    endianness: nom::Endianness,
+) -> Result<parse::FieldNames, Error> {
+    let mut buf = Vec::new();
+    read_subelement(reader, endianness, &mut buf)?;
+    read_subelement(reader, endianness, &mut buf)?;
+    let (_remaining, field_names) =
+        parse::parse_field_names_subelements(&buf, endianness).map_err(parse_error)?;
+    Ok(field_names)
+}
+
+fn extend(data: &mut NumericData, chunk: NumericData) {
+    macro_rules! extend_variants {
+        ( $( $variant:ident ),* ) => {
+            match (data, chunk) {
+                $(
+                    (NumericData::$variant(data), NumericData::$variant(chunk)) => {
+                        data.extend(chunk)
+                    }
+                )*
+                // Chunks are always parsed as the same type
+                _ => unreachable!(),
+            }
+        };
+    }
+    extend_variants!(Int8, UInt8, Int16, UInt16, Int32, UInt32, Int64, UInt64, Single, Double)
+}
+
+// Reads the numbers of a subelement in chunks, so that only the resulting
+// vector of numbers has to be held in memory
+fn read_numeric_subelement(
+    reader: &mut dyn Read,
+    endianness: nom::Endianness,
+) -> Result<NumericData, Error> {
+    let (tag_bytes, tag) = read_tag(reader, endianness)?;
+    let byte_size = tag.data_byte_size as usize;
+    if tag.is_small() {
+        let (_remaining, data) =
+            parse::parse_numeric_data(&tag_bytes[4..], endianness, tag.data_type, byte_size)
+                .map_err(parse_error)?;
+        return Ok(data);
+    }
+    let element_size = tag.data_type.byte_size().unwrap_or(1);
+    // The size comes from the file, so the vector only grows beyond the
+    // first chunk as the numbers are actually read
+    let capacity = (byte_size / element_size).min(CHUNK_SIZE / element_size);
+    let mut data = NumericData::with_capacity(tag.data_type, capacity)
+        .ok_or_else(|| invalid(ParseErrorKind::Malformed))?;
+    let chunk_size = CHUNK_SIZE / element_size * element_size;
+    let mut buf = vec![0; chunk_size.min(byte_size)];
+    let mut remaining = byte_size;
+    while remaining > 0 {
+        let n = chunk_size.min(remaining);
+        reader.read_exact(&mut buf[..n]).map_err(Error::IOError)?;
+        let (_remaining, chunk) =
+            parse::parse_numeric_data(&buf[..n], endianness, tag.data_type, n)
+                .map_err(parse_error)?;
+        extend(&mut data, chunk);
+        remaining -= n;
+    }
+    skip(reader, u64::from(tag.padding_byte_size))?;
+    Ok(data)
+}
+
+fn read_data_elements(
+    reader: &mut dyn Read,
+    endianness: nom::Endianness,
+    count: usize,
+) -> Result<Vec<DataElement>, Error> {
+    (0..count)
+        .map(|_| read_data_element(reader, endianness))
+        .collect()
+}
+
+// Reads the subelements of a matrix data element
+fn read_matrix(reader: &mut dyn Read, endianness: nom::Endianness) -> Result<DataElement, Error> {
+    let mut flags_subelement = Vec::new();
+    read_subelement(reader, endianness, &mut flags_subelement)?;
+    let (_remaining, flags) =
+        parse::parse_array_flags_subelement(&flags_subelement, endianness).map_err(parse_error)?;
+    if let ArrayType::Function | ArrayType::Opaque = flags.class {
+        // Kept as they are, so the whole element is needed anyway
+        let mut content = flags_subelement;
+        reader.read_to_end(&mut content).map_err(Error::IOError)?;
+        let (_remaining, data_element) =
+            parse::parse_matrix_data_element(&content, endianness).map_err(parse_error)?;
+        return Ok(data_element);
+    }
+    let dimensions = read_parsed(reader, endianness, parse::parse_dimensions_array_subelement)?;
+    let name = read_parsed(reader, endianness, parse::parse_array_name_subelement)?;
+    let n_required_elements =
+        parse::element_count(&dimensions).ok_or_else(|| invalid(ParseErrorKind::Malformed))?;
+    // The number of values of a structure or object array
+    let n_required_values = |field_names: &parse::FieldNames| {
+        n_required_elements
+            .checked_mul(field_names.len())
+            .ok_or_else(|| invalid(ParseErrorKind::Malformed))
+    };
+    let data_element = match flags.class {
+        ArrayType::Cell => {
+            let cells = read_data_elements(reader, endianness, n_required_elements)?;
+            DataElement::CellMatrix(flags, dimensions, name, cells)
+        }
+        ArrayType::Struct => {
+            let field_names = read_field_names(reader, endianness)?;
+            let values = read_data_elements(reader, endianness, n_required_values(&field_names)?)?;
+            DataElement::StructureMatrix(flags, dimensions, name, field_names, values)
+        }
+        ArrayType::Object => {
+            let class_name = read_parsed(reader, endianness, parse::parse_array_name_subelement)?;
+            let field_names = read_field_names(reader, endianness)?;
+            let values = read_data_elements(reader, endianness, n_required_values(&field_names)?)?;
+            DataElement::ObjectMatrix(flags, dimensions, name, class_name, field_names, values)
+        }
+        ArrayType::Char => {
+            let data = read_parsed(reader, endianness, parse::parse_character_subelement)?;
+            check_size(data.len() == n_required_elements)?;
+            DataElement::CharacterMatrix(flags, dimensions, name, data)
+        }
+        ArrayType::Sparse => {
+            let row_index =
+                read_parsed(reader, endianness, parse::parse_row_index_array_subelement)?;
+            let column_index = read_parsed(
+                reader,
+                endianness,
+                parse::parse_column_index_array_subelement,
+            )?;
+            let real_part = read_numeric_subelement(reader, endianness)?;
+            check_size(real_part.len() == flags.nzmax)?;
+            let imag_part = if flags.complex {
+                let imag_part = read_numeric_subelement(reader, endianness)?;
+                check_size(imag_part.len() == flags.nzmax)?;
+                Some(imag_part)
+            } else {
+                None
+            };
+            DataElement::SparseMatrix(
+                flags,
+                dimensions,
+                name,
+                row_index,
+                column_index,
+                real_part,
+                imag_part,
+            )
+        }
+        _ => {
+            let array_data_type = flags.class.numeric_data_type().unwrap();
+            let is_valid = |part: &NumericData| {
+                part.len() == n_required_elements
+                    && parse::numeric_data_types_are_compatible(array_data_type, part.data_type())
+            };
+            let real_part = read_numeric_subelement(reader, endianness)?;
+            check_size(is_valid(&real_part))?;
+            let imag_part = if flags.complex {
+                let imag_part = read_numeric_subelement(reader, endianness)?;
+                check_size(is_valid(&imag_part))?;
+                Some(imag_part)
+            } else {
+                None
+            };
+            DataElement::NumericMatrix(flags, dimensions, name, real_part, imag_part)
+        }
+    };
+    Ok(data_element)
+}
+
+// Reads a data element like `parse::parse_next_data_element`
+pub fn read_data_element(
+    reader: &mut dyn Read,
+    endianness: nom::Endianness,
+) -> Result<DataElement, Error> {
+    let (_tag_bytes, tag) = read_tag(reader, endianness)?;
+    let mut data = reader.take(u64::from(tag.data_byte_size));
+    let data_element = match tag.data_type {
+        // Empty arrays inside of cell and structure arrays are sometimes
+        // stored as matrix elements without any content
+        DataType::Matrix if tag.data_byte_size == 0 => parse::empty_matrix_data_element(),
+        DataType::Matrix => read_matrix(&mut data, endianness)?,
+        DataType::Compressed => {
+            let mut decoder = Decoder::new(&mut data).map_err(Error::IOError)?;
+            read_inflated_data_element(&mut decoder, endianness)?
+        }
+        _ => DataElement::Unsupported,
+    };
+    // Skip whatever is left of the element and its padding. Compressed
+    // elements are not padded.
+    skip(&mut data, u64::MAX)?;
+    if tag.data_type != DataType::Compressed {
+        skip(reader, u64::from(tag.padding_byte_size))?;
+    }
+    Ok(data_element)
+}
+
+// Reads the data element a compressed element inflates to. Fillers inflate
+// to nothing at all.
+pub fn read_inflated_data_element(
+    decoder: &mut dyn Read,
+    endianness: nom::Endianness,
+) -> Result<DataElement, Error> {
+    let mut first = [0; 1];
+    if decoder.read(&mut first).map_err(Error::IOError)? == 0 {
+        return Ok(DataElement::Filler);
+    }
+    read_data_element(&mut (&first[..]).chain(decoder), endianness)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Compression, MatFile, MatFileBuilder, OnError, WriteOptions};
+
+    #[test]
+    fn same_as_parser() {
+        let files: [&[u8]; 5] = [
+            include_bytes!("../tests/cell.mat"),
+            include_bytes!("../tests/object.mat"),
+            include_bytes!("../tests/sparse2.mat"),
+            include_bytes!("../tests/character.mat"),
+            include_bytes!("../tests/double_as_int16.mat"),
+        ];
+        for data in files.iter() {
+            let (_remaining, parsed) = parse::parse_all(data, OnError::Fail).unwrap();
+            let mut reader = &data[128..];
+            for (_offset, data_element) in parsed.data_elements.parsed {
+                let read = read_data_element(&mut reader, nom::Endianness::Little).unwrap();
+                assert_eq!(read, data_element);
+            }
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[test]
+    fn chunks() {
+        // Large enough to be read in several chunks
+        let real: Vec<f64> = (0..20000).map(f64::from).collect();
+        let imag: Vec<f64> = real.iter().map(|x| -x).collect();
+        let mat_file = MatFileBuilder::new()
+            .add_array("x", vec![100, 200], (real, imag).into())
+            .build()
+            .unwrap();
+        let mut data = Vec::new();
+        let options = WriteOptions::new().compression(Compression::Default);
+        mat_file.write_with_options(&mut data, &options).unwrap();
+        let loaded = MatFile::parse(data.as_slice()).unwrap();
+        assert_eq!(loaded.variables(), mat_file.variables());
+    }
+
+    #[test]
+    fn oversized_tag() {
+        // A subelement of doubles claiming almost 4 GB, of which only 16
+        // bytes are there
+        let mut data = vec![9, 0, 0, 0, 0xf8, 0xff, 0xff, 0xff];
+        data.extend_from_slice(&[0; 16]);
+        let result = read_numeric_subelement(&mut data.as_slice(), nom::Endianness::Little);
+        match result {
+            Err(Error::IOError(err)) => {
+                assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof)
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
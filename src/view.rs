@@ -0,0 +1,98 @@
+use crate::parse;
+use crate::{ArrayView, NumericDataView, NumericType};
+use std::borrow::Cow;
+
+// Numbers for which every bit pattern is a valid value, so that they can be
+// borrowed straight from the bytes of a file. Must not be implemented for
+// any other types.
+trait Plain: Copy {}
+
+macro_rules! plain_impls {
+    ( $( $num:ty ),* ) => {
+        $(
+            impl Plain for $num {}
+        )*
+    };
+}
+
+plain_impls!(i8, u8, i16, u16, i32, u32, i64, u64, f32, f64);
+
+// Reinterprets the bytes as `len` numbers if they are suitably aligned
+fn borrow<T: Plain>(bytes: &[u8], len: usize) -> Option<&[T]> {
+    let ptr = bytes.as_ptr() as *const T;
+    if Some(bytes.len()) != len.checked_mul(std::mem::size_of::<T>()) || !ptr.is_aligned() {
+        return None;
+    }
+    // Safe because the bytes are properly aligned, hold exactly `len`
+    // numbers and live as long as the returned slice
+    Some(unsafe { std::slice::from_raw_parts(ptr, len) })
+}
+
+fn host_endianness() -> nom::Endianness {
+    if cfg!(target_endian = "little") {
+        nom::Endianness::Little
+    } else {
+        nom::Endianness::Big
+    }
+}
+
+// Creates a view of an uncompressed numeric matrix data element that borrows
+// its numbers from `bytes`. Returns `None` if that is not possible because the
+// numbers are stored in a different type or byte order than they are
+// accessed in, or are misaligned, in which case they have to be copied.
+pub fn borrowed_array_view(bytes: &[u8], endianness: nom::Endianness) -> Option<ArrayView<'_>> {
+    if endianness != host_endianness() {
+        return None;
+    }
+    let (_remaining, raw) = parse::parse_raw_numeric_matrix(bytes, endianness).ok()?;
+    let raw = raw?;
+    let data_type = raw.flags.class.numeric_data_type()?;
+    if raw.real.0 != data_type || raw.imag.is_some_and(|imag| imag.0 != data_type) {
+        return None;
+    }
+    let numeric_type = NumericType::try_from(data_type).ok()?;
+    if raw.dimensions.len() < 2
+        || raw.dimensions.iter().any(|&d| d < 0)
+        || (raw.flags.logical && numeric_type != NumericType::UInt8)
+    {
+        return None;
+    }
+    let size: Vec<usize> = raw.dimensions.iter().map(|&d| d as usize).collect();
+    let len = size.iter().try_fold(1usize, |len, &d| len.checked_mul(d))?;
+
+    macro_rules! borrow_parts {
+        ( $( $variant:ident => $num:ty ),* ) => {
+            match numeric_type {
+                $(
+                    NumericType::$variant => NumericDataView::$variant {
+                        real: Cow::Borrowed(borrow::<$num>(raw.real.1, len)?),
+                        imag: match raw.imag {
+                            Some(imag) => Some(Cow::Borrowed(borrow::<$num>(imag.1, len)?)),
+                            None => None,
+                        },
+                    },
+                )*
+            }
+        };
+    }
+    let data = borrow_parts!(
+        Int8 => i8,
+        UInt8 => u8,
+        Int16 => i16,
+        UInt16 => u16,
+        Int32 => i32,
+        UInt32 => u32,
+        Int64 => i64,
+        UInt64 => u64,
+        Single => f32,
+        Double => f64
+    );
+    Some(ArrayView {
+        name: raw.name,
+        size,
+        data,
+        logical: raw.flags.logical,
+        global: raw.flags.global,
+        storage_type: numeric_type,
+    })
+}
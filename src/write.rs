@@ -0,0 +1,546 @@
+use crate::date::format_timestamp;
+use crate::parse::{ArrayType, DataType};
+use crate::{
+    invalid_array, Array, CellArray, CharArray, Compression, Error, MatFile, NumericData,
+    NumericType, ObjectArray, SparseArray, StructArray, Variable, WriteOptions,
+};
+use libflate::zlib::{EncodeOptions, Encoder as ZlibEncoder};
+use num_traits::AsPrimitive;
+use std::io::Write;
+
+// Numbers that can be written as the data of a data element
+pub trait Number: Copy {
+    fn write(self, buf: &mut Vec<u8>, endianness: nom::Endianness);
+}
+
+macro_rules! number_impls {
+    ( $( $num:ty ),* ) => {
+        $(
+            impl Number for $num {
+                fn write(self, buf: &mut Vec<u8>, endianness: nom::Endianness) {
+                    match endianness {
+                        nom::Endianness::Little => buf.extend_from_slice(&self.to_le_bytes()),
+                        nom::Endianness::Big => buf.extend_from_slice(&self.to_be_bytes()),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+number_impls!(i8, u8, i16, u16, i32, u32, i64, u64, f32, f64);
+
+pub fn to_bytes<T: Number>(data: &[T], endianness: nom::Endianness) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(std::mem::size_of_val(data));
+    for &x in data {
+        x.write(&mut buf, endianness);
+    }
+    buf
+}
+
+// Like `to_bytes`, but converts the numbers to another type first
+fn to_bytes_as<T, S>(data: &[T], endianness: nom::Endianness) -> Vec<u8>
+where
+    T: AsPrimitive<S>,
+    S: Number + 'static,
+{
+    let mut buf = Vec::with_capacity(data.len() * std::mem::size_of::<S>());
+    for &x in data {
+        x.as_().write(&mut buf, endianness);
+    }
+    buf
+}
+
+fn ceil_to_multiple(x: usize, multiple: usize) -> usize {
+    x.div_ceil(multiple) * multiple
+}
+
+// Whether data of the given size is stored in the small data element format
+fn is_small(byte_size: usize) -> bool {
+    byte_size > 0 && byte_size <= 4
+}
+
+// The size of the tag, data and padding of a data element
+pub fn data_element_size(byte_size: usize) -> usize {
+    if is_small(byte_size) {
+        8
+    } else {
+        8 + ceil_to_multiple(byte_size, 8)
+    }
+}
+
+// The number of padding bytes following the data of a data element
+pub fn padding_size(byte_size: usize) -> usize {
+    if is_small(byte_size) {
+        4 - byte_size
+    } else {
+        ceil_to_multiple(byte_size, 8) - byte_size
+    }
+}
+
+// Collects the subelements of a matrix data element
+struct Encoder {
+    buf: Vec<u8>,
+    endianness: nom::Endianness,
+}
+
+impl Encoder {
+    fn new(endianness: nom::Endianness) -> Self {
+        Encoder {
+            buf: Vec::new(),
+            endianness,
+        }
+    }
+
+    fn u32(&mut self, x: u32) {
+        x.write(&mut self.buf, self.endianness);
+    }
+
+    // Writes a data element, using the small data element format if the data
+    // fits into four bytes and padding the data to an 8 byte boundary.
+    fn data_element(&mut self, data_type: DataType, data: &[u8]) {
+        self.data_element_tag(data_type, data.len());
+        self.buf.extend_from_slice(data);
+        self.buf
+            .resize(self.buf.len() + padding_size(data.len()), 0);
+    }
+
+    fn data_element_tag(&mut self, data_type: DataType, byte_size: usize) {
+        if is_small(byte_size) {
+            self.u32(((byte_size as u32) << 16) | data_type as u32);
+        } else {
+            self.u32(data_type as u32);
+            self.u32(byte_size as u32);
+        }
+    }
+
+    fn array_flags(
+        &mut self,
+        class: ArrayType,
+        complex: bool,
+        global: bool,
+        logical: bool,
+        nzmax: usize,
+    ) {
+        let mut flags = class as u32;
+        if complex {
+            flags |= 0x0800;
+        }
+        if global {
+            flags |= 0x0400;
+        }
+        if logical {
+            flags |= 0x0200;
+        }
+        let data = to_bytes(&[flags, nzmax as u32], self.endianness);
+        self.data_element(DataType::UInt32, &data);
+    }
+
+    fn dimensions(&mut self, size: &[usize]) {
+        let dims: Vec<i32> = size.iter().map(|&d| d as i32).collect();
+        let data = to_bytes(&dims, self.endianness);
+        self.data_element(DataType::Int32, &data);
+    }
+
+    fn name(&mut self, name: &str) {
+        self.data_element(DataType::Int8, name.as_bytes());
+    }
+
+    fn numeric<T: Number>(&mut self, data_type: DataType, data: &[T]) {
+        let data = to_bytes(data, self.endianness);
+        self.data_element(data_type, &data);
+    }
+
+    // Writes numbers converted to the given type, which has to be able to
+    // represent all of them exactly
+    fn numeric_as<T>(&mut self, numeric_type: NumericType, data: &[T])
+    where
+        T: AsPrimitive<i8>
+            + AsPrimitive<u8>
+            + AsPrimitive<i16>
+            + AsPrimitive<u16>
+            + AsPrimitive<i32>
+            + AsPrimitive<u32>
+            + AsPrimitive<i64>
+            + AsPrimitive<u64>
+            + AsPrimitive<f32>
+            + AsPrimitive<f64>,
+    {
+        macro_rules! convert {
+            ( $( $numeric_type:ident => $num:ty ),* ) => {
+                match numeric_type {
+                    $(
+                        NumericType::$numeric_type => {
+                            let data = to_bytes_as::<T, $num>(data, self.endianness);
+                            self.data_element(DataType::$numeric_type, &data);
+                        }
+                    )*
+                }
+            };
+        }
+        convert!(
+            Int8 => i8,
+            UInt8 => u8,
+            Int16 => i16,
+            UInt16 => u16,
+            Int32 => i32,
+            UInt32 => u32,
+            Int64 => i64,
+            UInt64 => u64,
+            Single => f32,
+            Double => f64
+        );
+    }
+
+    fn field_names(&mut self, field_names: &[String]) {
+        // Every name is null terminated and padded to the same length
+        let length = ceil_to_multiple(
+            field_names.iter().map(|name| name.len()).max().unwrap_or(0) + 1,
+            8,
+        );
+        self.numeric(DataType::Int32, &[length as i32]);
+        let mut data = Vec::with_capacity(length * field_names.len());
+        for name in field_names {
+            data.extend_from_slice(name.as_bytes());
+            data.resize(data.len() + length - name.len(), 0);
+        }
+        self.data_element(DataType::Int8, &data);
+    }
+
+    // Writes a complete matrix data element for the variable, including its tag
+    fn matrix(&mut self, variable: &Variable) {
+        let mut encoder = Encoder::new(self.endianness);
+        match variable {
+            Variable::Numeric(array) => encoder.numeric_matrix(array),
+            Variable::Sparse(array) => encoder.sparse_matrix(array),
+            Variable::Char(array) => encoder.character_matrix(array),
+            Variable::Cell(array) => encoder.cell_matrix(array),
+            Variable::Struct(array) => encoder.structure_matrix(array, None),
+            Variable::Object(array) => encoder.object_matrix(array),
+            // Rejected by `Variable::check_writable`
+            Variable::Opaque(_) => unreachable!(),
+            // Written as they were read
+            Variable::Unsupported(variable) => encoder.buf.extend_from_slice(variable.raw_bytes()),
+        }
+        self.buf
+            .extend_from_slice(&tag(DataType::Matrix, encoder.buf.len(), self.endianness));
+        self.buf.extend_from_slice(&encoder.buf);
+        // Subelements are padded already, except for the contents of
+        // unsupported variables, which could be anything
+        let padding = ceil_to_multiple(encoder.buf.len(), 8) - encoder.buf.len();
+        self.buf.resize(self.buf.len() + padding, 0);
+    }
+
+    fn numeric_matrix(&mut self, array: &Array) {
+        macro_rules! numeric_parts {
+            ( $( $variant:ident => $class:ident );* ) => {
+                match &array.data {
+                    $(
+                        NumericData::$variant { real, imag } => {
+                            self.array_flags(
+                                ArrayType::$class,
+                                imag.is_some(),
+                                array.global,
+                                array.logical,
+                                0,
+                            );
+                            self.dimensions(&array.size);
+                            self.name(&array.name);
+                            // Numbers are stored in the same type they were
+                            // loaded from, to keep the file size down
+                            self.numeric_as(array.storage_type, real);
+                            if let Some(imag) = imag {
+                                self.numeric_as(array.storage_type, imag);
+                            }
+                        }
+                    )*
+                }
+            };
+        }
+        numeric_parts!(
+            Int8 => Int8;
+            UInt8 => UInt8;
+            Int16 => Int16;
+            UInt16 => UInt16;
+            Int32 => Int32;
+            UInt32 => UInt32;
+            Int64 => Int64;
+            UInt64 => UInt64;
+            Single => Single;
+            Double => Double
+        );
+    }
+
+    fn sparse_matrix(&mut self, array: &SparseArray) {
+        let (real, imag) = match &array.data {
+            NumericData::Double { real, imag } => (real, imag),
+            // Sparse arrays are always loaded and constructed as double
+            _ => unreachable!(),
+        };
+        // Room for `nzmax` entries is written, the unused part filled with
+        // zeros
+        let nzmax = array.nzmax.max(array.nnz());
+        let padded = |values: &[f64]| {
+            let mut values = values.to_vec();
+            values.resize(nzmax, 0.0);
+            values
+        };
+        self.array_flags(
+            ArrayType::Sparse,
+            imag.is_some(),
+            false,
+            array.logical,
+            nzmax,
+        );
+        self.dimensions(&array.size);
+        self.name(&array.name);
+        let mut row_index: Vec<i32> = array.row_index.iter().map(|&i| i as i32).collect();
+        row_index.resize(nzmax, 0);
+        self.numeric(DataType::Int32, &row_index);
+        let column_pointer: Vec<i32> = array.column_pointer.iter().map(|&i| i as i32).collect();
+        self.numeric(DataType::Int32, &column_pointer);
+        self.numeric(DataType::Double, &padded(real));
+        if let Some(imag) = imag {
+            self.numeric(DataType::Double, &padded(imag));
+        }
+    }
+
+    fn character_matrix(&mut self, array: &CharArray) {
+        self.array_flags(ArrayType::Char, false, false, false, 0);
+        self.dimensions(&array.size);
+        self.name(&array.name);
+        self.numeric(DataType::UInt16, &array.data);
+    }
+
+    fn cell_matrix(&mut self, array: &CellArray) {
+        self.array_flags(ArrayType::Cell, false, false, false, 0);
+        self.dimensions(&array.size);
+        self.name(&array.name);
+        for cell in &array.data {
+            self.matrix(cell);
+        }
+    }
+
+    fn structure_matrix(&mut self, array: &StructArray, class_name: Option<&str>) {
+        let class = if class_name.is_some() {
+            ArrayType::Object
+        } else {
+            ArrayType::Struct
+        };
+        self.array_flags(class, false, false, false, 0);
+        self.dimensions(&array.size);
+        self.name(&array.name);
+        if let Some(class_name) = class_name {
+            self.name(class_name);
+        }
+        self.field_names(&array.field_names);
+        for value in &array.data {
+            self.matrix(value);
+        }
+    }
+
+    fn object_matrix(&mut self, array: &ObjectArray) {
+        self.structure_matrix(&array.properties, Some(&array.class_name));
+    }
+}
+
+// The subelements of a numeric matrix that come before its numbers
+pub fn numeric_matrix_start(
+    name: &str,
+    size: &[usize],
+    numeric_type: NumericType,
+    complex: bool,
+    endianness: nom::Endianness,
+) -> Vec<u8> {
+    let mut encoder = Encoder::new(endianness);
+    encoder.array_flags(array_type(numeric_type), complex, false, false, 0);
+    encoder.dimensions(size);
+    encoder.name(name);
+    encoder.buf
+}
+
+// The tag of the subelement holding the real or imaginary part of a numeric
+// matrix
+pub fn numeric_tag(
+    numeric_type: NumericType,
+    byte_size: usize,
+    endianness: nom::Endianness,
+) -> Vec<u8> {
+    let mut encoder = Encoder::new(endianness);
+    encoder.data_element_tag(data_type(numeric_type), byte_size);
+    encoder.buf
+}
+
+// The tag of a data element at the top level of a file
+pub fn tag(data_type: DataType, byte_size: usize, endianness: nom::Endianness) -> Vec<u8> {
+    let mut encoder = Encoder::new(endianness);
+    encoder.u32(data_type as u32);
+    encoder.u32(byte_size as u32);
+    encoder.buf
+}
+
+fn array_type(numeric_type: NumericType) -> ArrayType {
+    match numeric_type {
+        NumericType::Int8 => ArrayType::Int8,
+        NumericType::UInt8 => ArrayType::UInt8,
+        NumericType::Int16 => ArrayType::Int16,
+        NumericType::UInt16 => ArrayType::UInt16,
+        NumericType::Int32 => ArrayType::Int32,
+        NumericType::UInt32 => ArrayType::UInt32,
+        NumericType::Int64 => ArrayType::Int64,
+        NumericType::UInt64 => ArrayType::UInt64,
+        NumericType::Single => ArrayType::Single,
+        NumericType::Double => ArrayType::Double,
+    }
+}
+
+fn data_type(numeric_type: NumericType) -> DataType {
+    match numeric_type {
+        NumericType::Int8 => DataType::Int8,
+        NumericType::UInt8 => DataType::UInt8,
+        NumericType::Int16 => DataType::Int16,
+        NumericType::UInt16 => DataType::UInt16,
+        NumericType::Int32 => DataType::Int32,
+        NumericType::UInt32 => DataType::UInt32,
+        NumericType::Int64 => DataType::Int64,
+        NumericType::UInt64 => DataType::UInt64,
+        NumericType::Single => DataType::Single,
+        NumericType::Double => DataType::Double,
+    }
+}
+
+// The code Matlab uses for the platform in the header text. Platforms Matlab
+// doesn't run on get the code of 64-bit Linux.
+fn platform() -> &'static str {
+    if cfg!(all(target_os = "windows", target_arch = "x86_64")) {
+        "PCWIN64"
+    } else if cfg!(all(target_os = "macos", target_arch = "x86_64")) {
+        "MACI64"
+    } else if cfg!(all(target_os = "macos", target_arch = "aarch64")) {
+        "MACA64"
+    } else {
+        "GLNXA64"
+    }
+}
+
+pub fn header(endianness: nom::Endianness) -> Vec<u8> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let text = format!(
+        "MATLAB 5.0 MAT-file, Platform: {}, Created on: {}",
+        platform(),
+        format_timestamp(now)
+    );
+    let mut buf = text.into_bytes();
+    buf.resize(116, b' ');
+    // No subsystem data
+    buf.resize(124, 0);
+    0x0100u16.write(&mut buf, endianness);
+    match endianness {
+        nom::Endianness::Little => buf.extend_from_slice(b"IM"),
+        nom::Endianness::Big => buf.extend_from_slice(b"MI"),
+    }
+    buf
+}
+
+// A zlib encoder for compressed data elements
+pub fn compressor(compression: Compression) -> std::io::Result<ZlibEncoder<Vec<u8>>> {
+    let options = match compression {
+        Compression::Fast => EncodeOptions::new().fixed_huffman_codes(),
+        _ => EncodeOptions::new(),
+    };
+    ZlibEncoder::with_options(Vec::new(), options)
+}
+
+fn compress(data: &[u8], compression: Compression) -> std::io::Result<Vec<u8>> {
+    let mut encoder = compressor(compression)?;
+    encoder.write_all(data)?;
+    encoder.finish().into_result()
+}
+
+// Whether data of the given size can be stored in a data element, whose tag
+// holds its size as a u32
+pub fn fits_data_element(byte_size: usize) -> bool {
+    byte_size <= u32::MAX as usize
+}
+
+// Encodes a variable as a data element at the top level of a file. The
+// subelements of a matrix are smaller than the matrix itself, so only the
+// size of the matrix has to be checked.
+pub fn variable_data_element(
+    variable: &Variable,
+    endianness: nom::Endianness,
+    compression: Compression,
+) -> Result<Vec<u8>, Error> {
+    let too_large = || {
+        invalid_array(
+            variable.name(),
+            variable.size(),
+            "too large to be written to a MAT-file".to_owned(),
+        )
+    };
+    let mut encoder = Encoder::new(endianness);
+    encoder.matrix(variable);
+    if !fits_data_element(encoder.buf.len() - 8) {
+        return Err(too_large());
+    }
+    if compression == Compression::None {
+        return Ok(encoder.buf);
+    }
+    // Compressed elements hold a whole matrix element each and are not padded
+    let data = compress(&encoder.buf, compression).map_err(Error::IOError)?;
+    if !fits_data_element(data.len()) {
+        return Err(too_large());
+    }
+    let mut element = tag(DataType::Compressed, data.len(), endianness);
+    element.extend_from_slice(&data);
+    Ok(element)
+}
+
+// An element of `byte_size` bytes, including its tag, that holds no
+// variable: a compressed element that inflates to nothing, followed by
+// zeros. Unlike other elements it doesn't have to be padded, so it can take
+// the place of any variable, whose elements are always large enough.
+pub fn filler(byte_size: usize, endianness: nom::Endianness) -> std::io::Result<Vec<u8>> {
+    let mut element = tag(DataType::Compressed, byte_size - 8, endianness);
+    element.extend_from_slice(&compress(&[], Compression::Default)?);
+    assert!(element.len() <= byte_size);
+    element.resize(byte_size, 0);
+    Ok(element)
+}
+
+pub fn write_all<W: std::io::Write>(
+    mut writer: W,
+    mat_file: &MatFile,
+    options: &WriteOptions,
+) -> Result<(), Error> {
+    let endianness = options.byte_order.endianness();
+    writer
+        .write_all(&header(endianness))
+        .map_err(Error::IOError)?;
+    for variable in &mat_file.variables {
+        let data_element = variable_data_element(variable, endianness, options.compression)?;
+        writer.write_all(&data_element).map_err(Error::IOError)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn small_data_element() {
+        let mut encoder = Encoder::new(nom::Endianness::Little);
+        encoder.name("abc");
+        assert_eq!(encoder.buf, b"\x01\x00\x03\x00abc\x00");
+        let mut encoder = Encoder::new(nom::Endianness::Little);
+        encoder.name("abcde");
+        assert_eq!(
+            encoder.buf,
+            b"\x01\x00\x00\x00\x05\x00\x00\x00abcde\x00\x00\x00"
+        );
+    }
+}
@@ -0,0 +1,358 @@
+#!/usr/bin/env python3
+"""Writes tests/v73.mat, a MAT-file version 7.3 file for the tests.
+
+Matlab isn't available to write the file, so it is put together following
+the HDF5 file format specification, in the layout Matlab uses: a 512 byte user
+block holding the MAT-file header, a version 0 superblock, version 1 object
+headers and groups stored as symbol tables. The variables are those of
+
+    a = [1 2 3; 4 5 6];
+    big = reshape(1:1000, 40, 25);  % chunked, shuffled and deflated
+    z = single([1+2i, 3-4i]);
+    i16 = int16([-1 2 -3]);
+    s = 'hello';
+    b = logical([1 0 1]);
+    e = zeros(0, 3);
+    sp = sparse([1 0; 0 2; 3 0]);
+    c = {1, 'x'; int8(2), {}};
+    st = struct('x', 1, 'name', 'ab');
+    sa = struct('v', {1, 2});
+    global g; g = 7;
+    fh = @sin;  % not supported, stored as a placeholder
+
+Run it from the root of the repository with `python3 tests/v73.py`.
+"""
+
+import struct
+import zlib
+
+UNDEFINED = 0xFFFFFFFFFFFFFFFF
+USER_BLOCK_SIZE = 512
+
+
+def pad8(data):
+    return data + b"\0" * (-len(data) % 8)
+
+
+class File:
+    def __init__(self):
+        # Addresses are relative to the superblock, which takes up the first
+        # 96 bytes
+        self.data = bytearray(96)
+
+    def alloc(self, data):
+        address = len(self.data)
+        self.data += pad8(bytes(data))
+        return address
+
+
+# Datatype messages
+
+def float_type(size):
+    if size == 8:
+        props = struct.pack("<HHBBBBI", 0, 64, 52, 11, 0, 52, 1023)
+        sign = 63
+    else:
+        props = struct.pack("<HHBBBBI", 0, 32, 23, 8, 0, 23, 127)
+        sign = 31
+    return bytes([0x11, 0x20, sign, 0]) + struct.pack("<I", size) + props
+
+
+def int_type(size, signed):
+    return (bytes([0x10, 0x08 if signed else 0, 0, 0]) + struct.pack("<I", size)
+            + struct.pack("<HH", 0, 8 * size))
+
+
+def string_type(size):
+    return bytes([0x13, 0, 0, 0]) + struct.pack("<I", size)
+
+
+def reference_type():
+    return bytes([0x17, 0, 0, 0]) + struct.pack("<I", 8)
+
+
+def complex_type(size):
+    members = b""
+    for k, name in enumerate([b"real", b"imag"]):
+        members += pad8(name + b"\0") + struct.pack("<IB3xII16x", k * size, 0, 0, 0)
+        members += float_type(size)
+    return bytes([0x16, 2, 0, 0]) + struct.pack("<I", 2 * size) + members
+
+
+def vlen_string_sequence_type():
+    return bytes([0x19, 0, 0, 0]) + struct.pack("<I", 16) + string_type(1)
+
+
+# Other messages
+
+def dataspace(dims):
+    if dims is None:
+        return bytes([1, 0, 0, 0, 0, 0, 0, 0])
+    return (bytes([1, len(dims), 1, 0, 0, 0, 0, 0])
+            + b"".join(struct.pack("<Q", d) for d in dims) * 2)
+
+
+def attribute(name, datatype, dims, data):
+    name = name.encode() + b"\0"
+    space = dataspace(dims)
+    return (struct.pack("<BBHHH", 1, 0, len(name), len(datatype), len(space))
+            + pad8(name) + pad8(datatype) + pad8(space) + data)
+
+
+def class_attribute(name):
+    return attribute("MATLAB_class", string_type(len(name)), None, name.encode())
+
+
+def uint8_attribute(name, value):
+    return attribute(name, int_type(1, False), None, bytes([value]))
+
+
+def uint64_attribute(name, value):
+    return attribute(name, int_type(8, False), None, struct.pack("<Q", value))
+
+
+def contiguous(address, size):
+    return struct.pack("<BBQQ", 3, 1, address, size)
+
+
+def compact(data):
+    return struct.pack("<BBH", 3, 0, len(data)) + data
+
+
+# Fill value, telling that the default fill value is used
+FILL_VALUE = (0x0005, bytes([2, 2, 2, 0]))
+
+
+def object_header(f, messages, split=None):
+    """Writes an object header, with the messages from `split` on in a
+    continuation block."""
+    def encode(messages):
+        return b"".join(struct.pack("<HHB3x", kind, len(pad8(body)), 0) + pad8(body)
+                        for kind, body in messages)
+    n_messages = len(messages)
+    if split is not None:
+        rest = encode(messages[split:])
+        address = f.alloc(rest)
+        messages = messages[:split] + [(0x0010, struct.pack("<QQ", address, len(rest)))]
+        n_messages += 1
+    body = encode(messages)
+    return f.alloc(struct.pack("<BBHII4x", 1, 0, n_messages, 1, len(body)) + body)
+
+
+def dataset(f, dims, datatype, raw, attributes, layout=None, filters=None, split=None):
+    if layout is None:
+        layout = contiguous(f.alloc(raw), len(raw)) if raw else contiguous(UNDEFINED, 0)
+    messages = [(0x0001, dataspace(dims)), (0x0003, datatype), FILL_VALUE, (0x0008, layout)]
+    if filters is not None:
+        messages.append((0x000B, filters))
+    messages += [(0x000C, a) for a in attributes]
+    return object_header(f, messages, split)
+
+
+def matlab_dataset(f, class_name, size, datatype, raw, extra=(), **kwargs):
+    attributes = [class_attribute(class_name)] + list(extra)
+    return dataset(f, list(reversed(size)), datatype, raw, attributes, **kwargs)
+
+
+def doubles(values):
+    return b"".join(struct.pack("<d", v) for v in values)
+
+
+def empty(f, class_name, size, extra=()):
+    attributes = [class_attribute(class_name), uint8_attribute("MATLAB_empty", 1)] + list(extra)
+    raw = b"".join(struct.pack("<Q", d) for d in size)
+    return dataset(f, [len(size)], int_type(8, False), raw, attributes)
+
+
+def group(f, members, attributes=()):
+    """Writes a group stored as a symbol table, with up to 8 members in each
+    symbol table node."""
+    names = sorted(members)
+    heap = bytearray(8)
+    offsets = {}
+    for name in names:
+        offsets[name] = len(heap)
+        heap += pad8(name.encode() + b"\0")
+    heap_address = f.alloc(heap)
+    local_heap = f.alloc(b"HEAP" + bytes([0, 0, 0, 0])
+                         + struct.pack("<QQQ", len(heap), UNDEFINED, heap_address))
+    nodes = []
+    for start in range(0, len(names), 8):
+        chunk = names[start:start + 8]
+        entries = b"".join(struct.pack("<QQII16x", offsets[name], members[name], 0, 0)
+                           for name in chunk)
+        entries += b"\0" * (40 * (8 - len(chunk)))
+        nodes.append((chunk[-1], f.alloc(b"SNOD" + struct.pack("<BBH", 1, 0, len(chunk)) + entries)))
+    # Each key is the name of the last member of the node before it
+    tree = b"TREE" + struct.pack("<BBHQQ", 0, 0, len(nodes), UNDEFINED, UNDEFINED)
+    tree += struct.pack("<Q", 0)
+    for last, node in nodes:
+        tree += struct.pack("<QQ", node, offsets[last])
+    btree = f.alloc(tree)
+    messages = [(0x0011, struct.pack("<QQ", btree, local_heap))]
+    messages += [(0x000C, a) for a in attributes]
+    return object_header(f, messages), btree, local_heap
+
+
+def chunked(f, dims, chunk_dims, element_size, raw):
+    """Stores row-major data in chunks, shuffled and deflated, indexed by a
+    B-tree of two levels."""
+    rank = len(dims)
+    chunks = []
+    def origins(k):
+        if k == rank:
+            yield []
+            return
+        for origin in range(0, dims[k], chunk_dims[k]):
+            for rest in origins(k + 1):
+                yield [origin] + rest
+    for origin in origins(0):
+        chunk = bytearray()
+        def rows(k, index):
+            if k == rank - 1:
+                for i in range(chunk_dims[k]):
+                    position = [o + j for o, j in zip(origin, index + [i])]
+                    if all(p < d for p, d in zip(position, dims)):
+                        linear = 0
+                        for p, d in zip(position, dims):
+                            linear = linear * d + p
+                        chunk.extend(raw[linear * element_size:(linear + 1) * element_size])
+                    else:
+                        chunk.extend(bytes(element_size))
+                return
+            for i in range(chunk_dims[k]):
+                rows(k + 1, index + [i])
+        rows(0, [])
+        n = len(chunk) // element_size
+        shuffled = bytes(chunk[j * element_size + i] for i in range(element_size) for j in range(n))
+        compressed = zlib.compress(shuffled, 6)
+        chunks.append((origin, f.alloc(compressed), len(compressed)))
+
+    def key(size, origin):
+        return struct.pack("<II", size, 0) + b"".join(struct.pack("<Q", o) for o in origin + [0])
+
+    def node(level, entries):
+        data = b"TREE" + struct.pack("<BBHQQ", 1, level, len(entries), UNDEFINED, UNDEFINED)
+        for size, origin, child in entries:
+            data += key(size, origin) + struct.pack("<Q", child)
+        return data + key(0, [d for d in dims])
+
+    half = (len(chunks) + 1) // 2
+    leaves = []
+    for part in (chunks[:half], chunks[half:]):
+        address = f.alloc(node(0, [(size, origin, child) for origin, child, size in part]))
+        leaves.append((0, part[0][0], address))
+    btree = f.alloc(node(1, leaves))
+    layout = (struct.pack("<BBBQ", 3, 2, rank + 1, btree)
+              + b"".join(struct.pack("<I", d) for d in chunk_dims + [element_size]))
+    filters = struct.pack("<BB6x", 1, 2)
+    filters += struct.pack("<HHHH", 2, 8, 1, 1) + pad8(b"shuffle\0") + struct.pack("<I4x", element_size)
+    filters += struct.pack("<HHHH", 1, 8, 1, 1) + pad8(b"deflate\0") + struct.pack("<I4x", 6)
+    return layout, filters
+
+
+def global_heap(f, strings):
+    """Stores strings in a global heap collection, returning their heap IDs."""
+    objects = b""
+    for index, string in enumerate(strings, 1):
+        objects += struct.pack("<HH4xQ", index, 1, len(string)) + pad8(string)
+    free = struct.pack("<HH4xQ", 0, 0, 16)
+    collection = b"GCOL" + bytes([1, 0, 0, 0]) + struct.pack("<Q", 16 + len(objects) + 16)
+    address = f.alloc(collection + objects + free)
+    return [struct.pack("<IQI", len(string), address, index)
+            for index, string in enumerate(strings, 1)]
+
+
+def fields_attribute(f, names):
+    ids = global_heap(f, [name.encode() for name in names])
+    return attribute("MATLAB_fields", vlen_string_sequence_type(), [len(names)], b"".join(ids))
+
+
+def char_data(text):
+    return b"".join(struct.pack("<H", ord(c)) for c in text)
+
+
+def char(f, text, size=None):
+    return matlab_dataset(f, "char", size or [1, len(text)], int_type(2, False), char_data(text),
+                          [uint8_attribute("MATLAB_int_decode", 2)])
+
+
+def double(f, values, size):
+    return matlab_dataset(f, "double", size, float_type(8), doubles(values))
+
+
+def main():
+    f = File()
+    refs = {}
+
+    def ref(address):
+        name = "%x" % len(refs)
+        refs[name] = address
+        return struct.pack("<Q", address)
+
+    variables = {}
+    variables["a"] = matlab_dataset(f, "double", [2, 3], float_type(8),
+                                    doubles([1, 4, 2, 5, 3, 6]), split=2)
+
+    # Stored as 25 rows of 40 values in HDF5
+    values = doubles(range(1, 1001))
+    layout, filters = chunked(f, [25, 40], [10, 16], 8, values)
+    variables["big"] = matlab_dataset(f, "double", [40, 25], float_type(8), None,
+                                      layout=layout, filters=filters)
+
+    variables["z"] = matlab_dataset(f, "single", [1, 2], complex_type(4),
+                                    struct.pack("<ffff", 1, 2, 3, -4))
+    variables["i16"] = matlab_dataset(f, "int16", [1, 3], int_type(2, True),
+                                      struct.pack("<hhh", -1, 2, -3))
+    variables["s"] = char(f, "hello")
+    variables["b"] = matlab_dataset(f, "logical", [1, 3], int_type(1, False), None,
+                                    [uint8_attribute("MATLAB_int_decode", 1)],
+                                    layout=compact(bytes([1, 0, 1])))
+    variables["e"] = empty(f, "double", [0, 3])
+
+    sparse = {
+        "jc": dataset(f, [3], int_type(8, False), struct.pack("<QQQ", 0, 2, 3), []),
+        "ir": dataset(f, [3], int_type(8, False), struct.pack("<QQQ", 0, 2, 1), []),
+        "data": dataset(f, [3], float_type(8), doubles([1, 3, 2]), []),
+    }
+    variables["sp"], _, _ = group(f, sparse, [class_attribute("double"),
+                                              uint64_attribute("MATLAB_sparse", 3)])
+
+    cells = [
+        double(f, [1], [1, 1]),
+        matlab_dataset(f, "int8", [1, 1], int_type(1, True), struct.pack("<b", 2)),
+        char(f, "x"),
+        empty(f, "cell", [0, 0]),
+    ]
+    # Cells are stored in column-major order, like all data
+    variables["c"] = matlab_dataset(f, "cell", [2, 2], reference_type(),
+                                    b"".join(ref(cell) for cell in cells))
+
+    st = {"x": double(f, [1], [1, 1]), "name": char(f, "ab")}
+    variables["st"], _, _ = group(f, st, [class_attribute("struct"),
+                                          fields_attribute(f, ["x", "name"])])
+
+    sa = {"v": dataset(f, [2, 1], reference_type(),
+                       ref(double(f, [1], [1, 1])) + ref(double(f, [2], [1, 1])), [])}
+    variables["sa"], _, _ = group(f, sa, [class_attribute("struct"), fields_attribute(f, ["v"])])
+
+    variables["g"] = matlab_dataset(f, "double", [1, 1], float_type(8), doubles([7]),
+                                    [uint8_attribute("MATLAB_global", 1)])
+    variables["fh"] = matlab_dataset(f, "function_handle", [1, 1], int_type(1, False), b"\0")
+
+    variables["#refs#"], _, _ = group(f, refs)
+    root, btree, heap = group(f, variables)
+
+    # Superblock version 0 with the root group symbol table entry
+    f.data[0:96] = (b"\x89HDF\r\n\x1a\n" + bytes([0, 0, 0, 0, 0, 8, 8, 0])
+                    + struct.pack("<HHI", 4, 16, 0)
+                    + struct.pack("<QQQQ", USER_BLOCK_SIZE, UNDEFINED, len(f.data), UNDEFINED)
+                    + struct.pack("<QQII", 0, root, 1, 0) + struct.pack("<QQ", btree, heap))
+
+    text = b"MATLAB 7.3 MAT-file, Platform: GLNXA64, Created on: Sat Oct 17 12:00:00 2026 HDF5 schema 1.00 ."
+    header = text.ljust(116, b" ") + b" " * 8 + b"\x00\x02IM"
+    with open("tests/v73.mat", "wb") as out:
+        out.write(header.ljust(USER_BLOCK_SIZE, b"\0") + f.data)
+
+
+main()